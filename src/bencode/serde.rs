@@ -17,7 +17,7 @@ impl TryFrom<Bencode> for serde_json::Value {
 }
 
 impl Bencode {
-    fn convert_list(list: &Vec<Bencode>) -> serde_json::Value {
+    fn convert_list(list: &[Bencode]) -> serde_json::Value {
         let val_list: Vec<serde_json::Value> = list.iter().map(|item| {
             match item {
                 Bencode::Byte(s) => s.to_owned().into(),
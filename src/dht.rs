@@ -0,0 +1,45 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub mod krpc;
+pub mod node;
+pub mod routing;
+pub mod store;
+pub mod token;
+
+pub use node::Dht;
+
+/// handshake reserved bit announcing a DHT node, in `reserved[7]`
+pub const RESERVED_BIT: u8 = 0x01;
+
+pub fn supports_dht(reserved: &[u8; 8]) -> bool {
+    reserved[7] & RESERVED_BIT != 0
+}
+
+/// Settings of our DHT node.
+#[derive(Debug, Clone)]
+pub struct DhtConfig {
+    pub bind: SocketAddr,
+    /// `host:port` of nodes to join the network through
+    pub bootstrap: Vec<String>,
+    /// where the node id and routing table are kept between runs
+    pub state_file: Option<PathBuf>,
+    /// a node that doesn't answer in time is marked failed
+    pub query_timeout: Duration,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([0, 0, 0, 0], 6881)),
+            bootstrap: vec![
+                "router.bittorrent.com:6881".to_string(),
+                "dht.transmissionbt.com:6881".to_string(),
+                "router.utorrent.com:6881".to_string(),
+            ],
+            state_file: None,
+            query_timeout: Duration::from_secs(2),
+        }
+    }
+}
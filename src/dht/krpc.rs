@@ -0,0 +1,280 @@
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+use anyhow::{Context, Result};
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_bencode::value::Value;
+use serde_bytes::ByteBuf;
+
+use crate::dht::routing::NodeId;
+
+pub const ERROR_GENERIC: i64 = 201;
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+/// A KRPC message (BEP 5): a query, a response or an error, told apart by `y`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KrpcMessage {
+    /// transaction id, echoed in the response
+    pub t: ByteBuf,
+    /// "q", "r" or "e"
+    pub y: String,
+    /// query method
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub q: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub a: Option<QueryArgs>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub r: Option<ResponseValues>,
+    /// error code and message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(deserialize_with = "deserialize_error")]
+    pub e: Option<(i64, String)>,
+    /// client version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<ByteBuf>,
+}
+
+/// Arguments of all query methods, each method uses some of them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct QueryArgs {
+    pub id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub info_hash: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+    /// 1: use the source port of the query instead of `port`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub implied_port: Option<u8>,
+}
+
+/// Values of all responses, each method uses some of them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ResponseValues {
+    pub id: ByteBuf,
+    /// compact node infos
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<ByteBuf>,
+    /// compact peers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<ByteBuf>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+}
+
+impl KrpcMessage {
+    pub fn query(t: &[u8], method: &str, args: QueryArgs) -> Self {
+        Self {
+            t: ByteBuf::from(t),
+            y: "q".to_string(),
+            q: Some(method.to_string()),
+            a: Some(args),
+            ..Default::default()
+        }
+    }
+
+    pub fn response(t: &[u8], values: ResponseValues) -> Self {
+        Self {
+            t: ByteBuf::from(t),
+            y: "r".to_string(),
+            r: Some(values),
+            ..Default::default()
+        }
+    }
+
+    pub fn error(t: &[u8], code: i64, message: &str) -> Self {
+        Self {
+            t: ByteBuf::from(t),
+            y: "e".to_string(),
+            e: Some((code, message.to_string())),
+            ..Default::default()
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        serde_bencode::from_bytes(bytes).context("invalid krpc message")
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_bencode::to_bytes(self).expect("krpc message is always encodable")
+    }
+}
+
+// serde_bencode reads a tuple without the `e` closing its list, which then ends the
+// message early. Read the list as a whole instead.
+fn deserialize_error<'de, D>(deserializer: D) -> Result<Option<(i64, String)>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match &Vec::<Value>::deserialize(deserializer)?[..] {
+        [Value::Int(code), Value::Bytes(message), ..] => {
+            Ok(Some((*code, String::from_utf8_lossy(message).into_owned())))
+        }
+        _ => Err(D::Error::custom("error is not a code and a message")),
+    }
+}
+
+/// compact node info: 20 bytes id + 6 bytes ipv4 address each, ipv6 nodes are skipped
+pub fn encode_nodes(nodes: &[(NodeId, SocketAddr)]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(nodes.len() * 26);
+    for (id, addr) in nodes {
+        if let SocketAddr::V4(v4) = addr {
+            bytes.extend_from_slice(&id.0);
+            bytes.extend_from_slice(&encode_peer(v4));
+        }
+    }
+    bytes
+}
+
+pub fn decode_nodes(bytes: &[u8]) -> Vec<(NodeId, SocketAddr)> {
+    bytes
+        .chunks_exact(26)
+        .filter_map(|c| {
+            let id = NodeId::from_slice(&c[..20])?;
+            Some((id, SocketAddr::V4(decode_peer(&c[20..])?)))
+        })
+        .collect()
+}
+
+/// compact peer: 4 bytes ip + 2 bytes port
+pub fn encode_peer(addr: &SocketAddrV4) -> [u8; 6] {
+    let mut bytes = [0u8; 6];
+    bytes[..4].copy_from_slice(&addr.ip().octets());
+    bytes[4..].copy_from_slice(&addr.port().to_be_bytes());
+    bytes
+}
+
+pub fn decode_peer(bytes: &[u8]) -> Option<SocketAddrV4> {
+    let bytes: [u8; 6] = bytes.try_into().ok()?;
+    let ip = Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+    Some(SocketAddrV4::new(
+        ip,
+        u16::from_be_bytes([bytes[4], bytes[5]]),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    // the examples of BEP 5
+    const PING: &[u8] = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+    const PONG: &[u8] = b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re";
+    const ERROR: &[u8] = b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
+    const ANNOUNCE: &[u8] = b"d1:ad2:id20:abcdefghij012345678912:implied_porti1e9:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe";
+    const PEERS: &[u8] = b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl6:axje.u6:idhtnmee1:t2:aa1:y1:re";
+
+    fn id(bytes: &[u8]) -> ByteBuf {
+        ByteBuf::from(bytes)
+    }
+
+    #[test]
+    fn encodes_the_bep_examples() {
+        let ping = KrpcMessage::query(
+            b"aa",
+            "ping",
+            QueryArgs {
+                id: id(b"abcdefghij0123456789"),
+                ..Default::default()
+            },
+        );
+        assert_eq!(ping.to_bytes(), PING);
+        let pong = KrpcMessage::response(
+            b"aa",
+            ResponseValues {
+                id: id(b"mnopqrstuvwxyz123456"),
+                ..Default::default()
+            },
+        );
+        assert_eq!(pong.to_bytes(), PONG);
+        let error = KrpcMessage::error(b"aa", ERROR_GENERIC, "A Generic Error Ocurred");
+        assert_eq!(error.to_bytes(), ERROR);
+        let announce = KrpcMessage::query(
+            b"aa",
+            "announce_peer",
+            QueryArgs {
+                id: id(b"abcdefghij0123456789"),
+                info_hash: Some(id(b"mnopqrstuvwxyz123456")),
+                port: Some(6881),
+                token: Some(id(b"aoeusnth")),
+                implied_port: Some(1),
+                ..Default::default()
+            },
+        );
+        assert_eq!(announce.to_bytes(), ANNOUNCE);
+        let peers = KrpcMessage::response(
+            b"aa",
+            ResponseValues {
+                id: id(b"abcdefghij0123456789"),
+                values: Some(vec![id(b"axje.u"), id(b"idhtnm")]),
+                token: Some(id(b"aoeusnth")),
+                ..Default::default()
+            },
+        );
+        assert_eq!(peers.to_bytes(), PEERS);
+    }
+
+    #[test]
+    fn decodes_the_bep_examples() {
+        let ping = KrpcMessage::from_bytes(PING).unwrap();
+        assert_eq!((&ping.t[..], &ping.y[..]), (&b"aa"[..], "q"));
+        assert_eq!(ping.q.as_deref(), Some("ping"));
+        assert_eq!(&ping.a.unwrap().id[..], b"abcdefghij0123456789");
+
+        let error = KrpcMessage::from_bytes(ERROR).unwrap();
+        assert_eq!(error.y, "e");
+        assert_eq!(error.e, Some((201, "A Generic Error Ocurred".to_string())));
+
+        let announce = KrpcMessage::from_bytes(ANNOUNCE).unwrap().a.unwrap();
+        assert_eq!(
+            announce.info_hash.as_deref().map(|b| &b[..]),
+            Some(&b"mnopqrstuvwxyz123456"[..])
+        );
+        assert_eq!(
+            (announce.port, announce.implied_port),
+            (Some(6881), Some(1))
+        );
+        assert_eq!(
+            announce.token.as_deref().map(|b| &b[..]),
+            Some(&b"aoeusnth"[..])
+        );
+
+        let peers = KrpcMessage::from_bytes(PEERS).unwrap().r.unwrap();
+        let values: Vec<SocketAddrV4> = peers
+            .values
+            .unwrap()
+            .iter()
+            .map(|v| decode_peer(v).unwrap())
+            .collect();
+        assert_eq!(values[0], "97.120.106.101:11893".parse().unwrap());
+        assert_eq!(values[1], "105.100.104.116:28269".parse().unwrap());
+        assert!(peers.nodes.is_none());
+
+        assert!(KrpcMessage::from_bytes(b"d1:t2:aa").is_err());
+        assert!(KrpcMessage::from_bytes(b"i42e").is_err());
+    }
+
+    #[test]
+    fn packs_compact_nodes_and_peers() {
+        let v4: SocketAddr = "10.1.2.3:6881".parse().unwrap();
+        let v6 = SocketAddr::from((Ipv6Addr::LOCALHOST, 6881));
+        let nodes = [
+            (NodeId([1; 20]), v4),
+            (NodeId([2; 20]), v6),
+            (NodeId([3; 20]), "192.168.0.1:1".parse().unwrap()),
+        ];
+        let bytes = encode_nodes(&nodes);
+        assert_eq!(bytes.len(), 2 * 26);
+        assert_eq!(&bytes[20..26], &[10, 1, 2, 3, 0x1a, 0xe1]);
+        assert_eq!(decode_nodes(&bytes), [nodes[0], nodes[2]]);
+        // a trailing partial node is ignored
+        assert_eq!(decode_nodes(&bytes[..40]), [nodes[0]]);
+        assert!(decode_peer(&[1, 2, 3, 4, 5]).is_none());
+    }
+}
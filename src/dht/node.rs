@@ -0,0 +1,621 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+use crate::dht::krpc;
+use crate::dht::krpc::{KrpcMessage, QueryArgs, ResponseValues};
+use crate::dht::routing::{NodeId, RoutingTable, K};
+use crate::dht::store::PeerStore;
+use crate::dht::token::Tokens;
+use crate::dht::DhtConfig;
+
+/// queries in flight per lookup step
+const ALPHA: usize = 3;
+/// peers returned by one get_peers response, keeps it below the usual udp payload limit
+const MAX_VALUES: usize = 50;
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+// nodes not heard from for this long are pinged by the maintenance
+const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+const VERSION: &[u8] = b"RB01";
+
+/// A mainline DHT node (BEP 5). Answers the queries of other nodes and looks up peers of
+/// torrents. Clones share the same node, it stops once the last clone is dropped.
+#[derive(Clone)]
+pub struct Dht {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    socket: Arc<UdpSocket>,
+    config: DhtConfig,
+    table: Mutex<RoutingTable>,
+    tokens: Mutex<Tokens>,
+    store: Mutex<PeerStore>,
+    // queries waiting for their response, by transaction id
+    pending: Mutex<HashMap<u16, Pending>>,
+    next_tid: AtomicU16,
+    // nodes of the state file, tried on bootstrap
+    saved: Vec<SocketAddr>,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+struct Pending {
+    addr: SocketAddr,
+    resp: oneshot::Sender<Result<ResponseValues>>,
+}
+
+/// Result of a get_peers query.
+#[derive(Debug, Default)]
+pub struct PeersReply {
+    pub peers: Vec<SocketAddr>,
+    pub nodes: Vec<(NodeId, SocketAddr)>,
+    pub token: Option<Vec<u8>>,
+}
+
+// a node that answered during a lookup
+struct Responder {
+    addr: SocketAddr,
+    token: Option<Vec<u8>>,
+}
+
+/// What the state file holds: our id and the nodes of the routing table.
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedState {
+    id: ByteBuf,
+    nodes: ByteBuf,
+}
+
+impl Dht {
+    /// bind the socket and start answering queries, with the id and nodes of the state
+    /// file if there is one. Call `bootstrap` to join the network.
+    pub async fn start(config: DhtConfig) -> Result<Self> {
+        let saved = match &config.state_file {
+            Some(path) if path.exists() => Some(load_state(path)?),
+            _ => None,
+        };
+        let (id, saved) = match saved {
+            Some((id, nodes)) => (id, nodes.into_iter().map(|(_, addr)| addr).collect()),
+            None => (NodeId::random(), Vec::new()),
+        };
+        let socket = UdpSocket::bind(config.bind)
+            .await
+            .with_context(|| format!("bind dht on {}", config.bind))?;
+        let inner = Arc::new(Inner {
+            socket: Arc::new(socket),
+            config,
+            table: Mutex::new(RoutingTable::new(id)),
+            tokens: Mutex::new(Tokens::new()),
+            store: Mutex::new(PeerStore::new()),
+            pending: Mutex::new(HashMap::new()),
+            next_tid: AtomicU16::new(0),
+            saved,
+            tasks: Mutex::new(Vec::new()),
+        });
+        let serve = tokio::spawn(serve(inner.socket.clone(), Arc::downgrade(&inner)));
+        let maintain = tokio::spawn(maintain(Arc::downgrade(&inner)));
+        inner.tasks.lock().unwrap().extend([serve, maintain]);
+        Ok(Self { inner })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.inner.table.lock().unwrap().own_id()
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.inner.socket.local_addr()?)
+    }
+
+    /// nodes in the routing table
+    pub fn node_count(&self) -> usize {
+        self.inner.table.lock().unwrap().len()
+    }
+
+    /// add a node we learned about elsewhere, it is pinged before it enters the table
+    pub async fn add_node(&self, addr: SocketAddr) -> Result<NodeId> {
+        self.ping(addr).await
+    }
+
+    /// ask the bootstrap and saved nodes for nodes close to us, then look up our own id to
+    /// fill the routing table. Returns the number of nodes known afterwards.
+    pub async fn bootstrap(&self) -> Result<usize> {
+        self.inner.bootstrap().await
+    }
+
+    pub async fn ping(&self, addr: SocketAddr) -> Result<NodeId> {
+        let resp = self.inner.query(addr, "ping", QueryArgs::default()).await?;
+        response_id(&resp)
+    }
+
+    pub async fn find_node(
+        &self,
+        addr: SocketAddr,
+        target: NodeId,
+    ) -> Result<Vec<(NodeId, SocketAddr)>> {
+        self.inner.find_node(addr, target).await
+    }
+
+    pub async fn get_peers(&self, addr: SocketAddr, info_hash: [u8; 20]) -> Result<PeersReply> {
+        self.inner.get_peers(addr, info_hash).await
+    }
+
+    /// tell `addr` we are a peer of `info_hash` listening on `port`, `token` is from its
+    /// get_peers response
+    pub async fn announce_peer(
+        &self,
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        port: u16,
+        token: &[u8],
+    ) -> Result<()> {
+        let args = QueryArgs {
+            info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+            port: Some(port),
+            token: Some(ByteBuf::from(token)),
+            ..Default::default()
+        };
+        self.inner.query(addr, "announce_peer", args).await?;
+        Ok(())
+    }
+
+    /// peers of `info_hash` found by an iterative lookup
+    pub async fn lookup_peers(&self, info_hash: [u8; 20]) -> Vec<SocketAddr> {
+        let (peers, _) = self.inner.lookup(NodeId(info_hash), true).await;
+        peers.into_iter().collect()
+    }
+
+    /// look up the peers of `info_hash` and announce ourselves on `port` to the closest
+    /// nodes, returns the peers found
+    pub async fn announce(&self, info_hash: [u8; 20], port: u16) -> Vec<SocketAddr> {
+        let (peers, closest) = self.inner.lookup(NodeId(info_hash), true).await;
+        let announces = closest.iter().filter_map(|r| {
+            let token = r.token.as_ref()?;
+            Some(self.announce_peer(r.addr, info_hash, port, token))
+        });
+        let announced = join_all(announces)
+            .await
+            .iter()
+            .filter(|r| r.is_ok())
+            .count();
+        println!(
+            "dht: {} peers for {}, announced to {} nodes",
+            peers.len(),
+            hex::encode(info_hash),
+            announced
+        );
+        peers.into_iter().collect()
+    }
+
+    /// write our id and the routing table to `path`
+    pub fn save(&self, path: &Path) -> Result<()> {
+        self.inner.save(path)
+    }
+}
+
+impl Inner {
+    fn own_id(&self) -> NodeId {
+        self.table.lock().unwrap().own_id()
+    }
+
+    async fn bootstrap(&self) -> Result<usize> {
+        let mut addrs = self.saved.clone();
+        for host in &self.config.bootstrap {
+            match tokio::net::lookup_host(host.as_str()).await {
+                Ok(resolved) => addrs.extend(resolved.filter(SocketAddr::is_ipv4)),
+                Err(e) => println!("dht: resolve bootstrap node {}: {}", host, e),
+            }
+        }
+        let own = self.own_id();
+        let queries = addrs.iter().map(|addr| self.find_node(*addr, own));
+        let answered = join_all(queries).await.iter().filter(|r| r.is_ok()).count();
+        if answered == 0 && self.table.lock().unwrap().is_empty() {
+            bail!("no bootstrap node answered");
+        }
+        self.lookup(own, false).await;
+        Ok(self.table.lock().unwrap().len())
+    }
+
+    async fn find_node(
+        &self,
+        addr: SocketAddr,
+        target: NodeId,
+    ) -> Result<Vec<(NodeId, SocketAddr)>> {
+        let args = QueryArgs {
+            target: Some(ByteBuf::from(target.0.to_vec())),
+            ..Default::default()
+        };
+        let resp = self.query(addr, "find_node", args).await?;
+        Ok(resp
+            .nodes
+            .map(|n| krpc::decode_nodes(&n))
+            .unwrap_or_default())
+    }
+
+    async fn get_peers(&self, addr: SocketAddr, info_hash: [u8; 20]) -> Result<PeersReply> {
+        let args = QueryArgs {
+            info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+            ..Default::default()
+        };
+        let resp = self.query(addr, "get_peers", args).await?;
+        Ok(PeersReply {
+            peers: resp
+                .values
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|v| krpc::decode_peer(v))
+                .map(SocketAddr::V4)
+                .collect(),
+            nodes: resp
+                .nodes
+                .map(|n| krpc::decode_nodes(&n))
+                .unwrap_or_default(),
+            token: resp.token.map(ByteBuf::into_vec),
+        })
+    }
+
+    /// Iterative lookup of `target`: query the closest unqueried nodes, `ALPHA` at a time,
+    /// until the `K` closest known nodes all answered or failed. With `peers` get_peers is
+    /// sent instead of find_node; returns the peers found and the closest responders.
+    async fn lookup(&self, target: NodeId, peers: bool) -> (HashSet<SocketAddr>, Vec<Responder>) {
+        let mut candidates: BTreeMap<[u8; 20], (NodeId, SocketAddr)> = self
+            .table
+            .lock()
+            .unwrap()
+            .closest(&target, K)
+            .into_iter()
+            .map(|n| (n.id.distance(&target), (n.id, n.addr)))
+            .collect();
+        let mut queried = HashSet::new();
+        let mut responders: BTreeMap<[u8; 20], Responder> = BTreeMap::new();
+        let mut found = HashSet::new();
+        let own = self.own_id();
+
+        loop {
+            let next: Vec<([u8; 20], SocketAddr)> = candidates
+                .iter()
+                .take(K)
+                .filter(|(_, (_, addr))| !queried.contains(addr))
+                .take(ALPHA)
+                .map(|(d, (_, addr))| (*d, *addr))
+                .collect();
+            if next.is_empty() {
+                break;
+            }
+            let queries = next.iter().map(|(_, addr)| async move {
+                if peers {
+                    self.get_peers(*addr, target.0).await
+                } else {
+                    self.find_node(*addr, target).await.map(|nodes| PeersReply {
+                        nodes,
+                        ..Default::default()
+                    })
+                }
+            });
+            let replies = join_all(queries).await;
+            for ((distance, addr), reply) in next.into_iter().zip(replies) {
+                queried.insert(addr);
+                let Ok(reply) = reply else {
+                    candidates.remove(&distance);
+                    continue;
+                };
+                found.extend(reply.peers);
+                responders.insert(
+                    distance,
+                    Responder {
+                        addr,
+                        token: reply.token,
+                    },
+                );
+                for (id, addr) in reply.nodes {
+                    if id != own && !queried.contains(&addr) {
+                        candidates.entry(id.distance(&target)).or_insert((id, addr));
+                    }
+                }
+            }
+        }
+        (found, responders.into_values().take(K).collect())
+    }
+
+    /// send a query and wait for its response, the node is marked failed on timeout
+    async fn query(
+        &self,
+        addr: SocketAddr,
+        method: &str,
+        mut args: QueryArgs,
+    ) -> Result<ResponseValues> {
+        args.id = ByteBuf::from(self.own_id().0.to_vec());
+        let tid = self.next_tid.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(tid, Pending { addr, resp: tx });
+        let msg = KrpcMessage::query(&tid.to_be_bytes(), method, args);
+        if let Err(e) = self.socket.send_to(&msg.to_bytes(), addr).await {
+            self.pending.lock().unwrap().remove(&tid);
+            return Err(e).with_context(|| format!("send {} to {}", method, addr));
+        }
+        match tokio::time::timeout(self.config.query_timeout, rx).await {
+            Ok(Ok(resp)) => resp.with_context(|| format!("{} to {}", method, addr)),
+            Ok(Err(_)) => Err(anyhow!("{} to {} dropped", method, addr)),
+            Err(_) => {
+                self.pending.lock().unwrap().remove(&tid);
+                self.table.lock().unwrap().mark_failed(addr);
+                Err(anyhow!("{} to {} timed out", method, addr))
+            }
+        }
+    }
+
+    fn handle(&self, packet: &[u8], from: SocketAddr) -> Option<KrpcMessage> {
+        let msg = KrpcMessage::from_bytes(packet).ok()?;
+        match msg.y.as_str() {
+            "q" => Some(self.answer(msg, from)),
+            "r" | "e" => {
+                self.on_response(msg, from);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn on_response(&self, msg: KrpcMessage, from: SocketAddr) {
+        let Ok(tid) = <[u8; 2]>::try_from(msg.t.as_slice()) else {
+            return;
+        };
+        let tid = u16::from_be_bytes(tid);
+        let pending = {
+            let mut pending = self.pending.lock().unwrap();
+            // responses from other addresses are spoofed or stale, leave the query waiting
+            match pending.get(&tid) {
+                Some(p) if p.addr == from => pending.remove(&tid),
+                _ => None,
+            }
+        };
+        let Some(pending) = pending else {
+            return;
+        };
+        let resp = match (msg.r, msg.e) {
+            (Some(r), _) => match response_id(&r) {
+                Ok(id) => {
+                    self.table.lock().unwrap().insert(id, from);
+                    Ok(r)
+                }
+                Err(e) => Err(e),
+            },
+            (None, Some((code, message))) => Err(anyhow!("error {}: {}", code, message)),
+            (None, None) => Err(anyhow!("empty response")),
+        };
+        let _ = pending.resp.send(resp);
+    }
+
+    fn answer(&self, msg: KrpcMessage, from: SocketAddr) -> KrpcMessage {
+        let t = msg.t.as_slice();
+        let Some(args) = msg.a else {
+            return KrpcMessage::error(t, krpc::ERROR_PROTOCOL, "missing arguments");
+        };
+        let Some(querier) = NodeId::from_slice(&args.id) else {
+            return KrpcMessage::error(t, krpc::ERROR_PROTOCOL, "invalid id");
+        };
+        self.table.lock().unwrap().insert(querier, from);
+
+        let own = ByteBuf::from(self.own_id().0.to_vec());
+        let mut values = ResponseValues {
+            id: own,
+            ..Default::default()
+        };
+        match msg.q.as_deref() {
+            Some("ping") => {}
+            Some("find_node") => {
+                let Some(target) = args.target.as_ref().and_then(|b| NodeId::from_slice(b)) else {
+                    return KrpcMessage::error(t, krpc::ERROR_PROTOCOL, "invalid target");
+                };
+                values.nodes = Some(self.closest_nodes(&target));
+            }
+            Some("get_peers") => {
+                let Some(info_hash) = args.info_hash.as_ref().and_then(|b| NodeId::from_slice(b))
+                else {
+                    return KrpcMessage::error(t, krpc::ERROR_PROTOCOL, "invalid info_hash");
+                };
+                values.token = Some(ByteBuf::from(self.tokens.lock().unwrap().issue(from.ip())));
+                let peers: Vec<ByteBuf> = self
+                    .store
+                    .lock()
+                    .unwrap()
+                    .get(&info_hash.0, MAX_VALUES)
+                    .into_iter()
+                    .filter_map(|peer| match peer {
+                        SocketAddr::V4(v4) => Some(ByteBuf::from(krpc::encode_peer(&v4).to_vec())),
+                        SocketAddr::V6(_) => None,
+                    })
+                    .collect();
+                if peers.is_empty() {
+                    values.nodes = Some(self.closest_nodes(&info_hash));
+                } else {
+                    values.values = Some(peers);
+                }
+            }
+            Some("announce_peer") => {
+                let Some(info_hash) = args.info_hash.as_ref().and_then(|b| NodeId::from_slice(b))
+                else {
+                    return KrpcMessage::error(t, krpc::ERROR_PROTOCOL, "invalid info_hash");
+                };
+                let token = args.token.as_deref().map_or(&[][..], Vec::as_slice);
+                if !self.tokens.lock().unwrap().verify(from.ip(), token) {
+                    return KrpcMessage::error(t, krpc::ERROR_PROTOCOL, "bad token");
+                }
+                let port = match (args.implied_port, args.port) {
+                    (Some(1), _) => from.port(),
+                    (_, Some(port)) => port,
+                    _ => return KrpcMessage::error(t, krpc::ERROR_PROTOCOL, "missing port"),
+                };
+                let peer = SocketAddr::new(from.ip(), port);
+                self.store.lock().unwrap().add(info_hash.0, peer);
+            }
+            _ => return KrpcMessage::error(t, krpc::ERROR_METHOD_UNKNOWN, "method unknown"),
+        }
+        let mut resp = KrpcMessage::response(t, values);
+        resp.v = Some(ByteBuf::from(VERSION));
+        resp
+    }
+
+    fn closest_nodes(&self, target: &NodeId) -> ByteBuf {
+        let nodes: Vec<(NodeId, SocketAddr)> = self
+            .table
+            .lock()
+            .unwrap()
+            .closest(target, K)
+            .into_iter()
+            .map(|n| (n.id, n.addr))
+            .collect();
+        ByteBuf::from(krpc::encode_nodes(&nodes))
+    }
+
+    /// ping the nodes we haven't heard from for a while, bootstrap again when the table
+    /// ran low
+    async fn maintain(&self) {
+        self.store.lock().unwrap().expire();
+        let questionable: Vec<SocketAddr> = self
+            .table
+            .lock()
+            .unwrap()
+            .nodes()
+            .filter(|n| n.last_seen.elapsed() >= QUESTIONABLE_AFTER)
+            .map(|n| n.addr)
+            .collect();
+        join_all(
+            questionable
+                .into_iter()
+                .map(|addr| self.query(addr, "ping", QueryArgs::default())),
+        )
+        .await;
+        if self.table.lock().unwrap().len() < K {
+            if let Err(e) = self.bootstrap().await {
+                println!("dht: bootstrap failed: {:#}", e);
+            }
+        }
+        if let Some(path) = &self.config.state_file {
+            if let Err(e) = self.save(path) {
+                println!("dht: {:#}", e);
+            }
+        }
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        let (id, nodes) = {
+            let table = self.table.lock().unwrap();
+            let nodes: Vec<(NodeId, SocketAddr)> = table.nodes().map(|n| (n.id, n.addr)).collect();
+            (table.own_id(), nodes)
+        };
+        let state = SavedState {
+            id: ByteBuf::from(id.0.to_vec()),
+            nodes: ByteBuf::from(krpc::encode_nodes(&nodes)),
+        };
+        let bytes = serde_bencode::to_bytes(&state)?;
+        std::fs::write(path, bytes).with_context(|| format!("write dht state {}", path.display()))
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        for task in self.tasks.get_mut().unwrap().drain(..) {
+            task.abort();
+        }
+    }
+}
+
+fn response_id(resp: &ResponseValues) -> Result<NodeId> {
+    NodeId::from_slice(&resp.id).context("response with invalid node id")
+}
+
+fn load_state(path: &Path) -> Result<(NodeId, Vec<(NodeId, SocketAddr)>)> {
+    let bytes =
+        std::fs::read(path).with_context(|| format!("read dht state {}", path.display()))?;
+    let state: SavedState = serde_bencode::from_bytes(&bytes)
+        .with_context(|| format!("invalid dht state {}", path.display()))?;
+    let id = NodeId::from_slice(&state.id).context("dht state with invalid node id")?;
+    Ok((id, krpc::decode_nodes(&state.nodes)))
+}
+
+// answers queries and routes responses, until the node is dropped
+async fn serve(socket: Arc<UdpSocket>, inner: Weak<Inner>) {
+    let mut buf = vec![0u8; 2048];
+    loop {
+        let (n, from) = match socket.recv_from(&mut buf).await {
+            Ok(res) => res,
+            // e.g. icmp port unreachable of an earlier query
+            Err(_) => continue,
+        };
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+        if let Some(resp) = inner.handle(&buf[..n], from) {
+            if let Err(e) = socket.send_to(&resp.to_bytes(), from).await {
+                println!("dht: send to {} {}", from, e);
+            }
+        }
+    }
+}
+
+// periodic maintenance, until the node is dropped
+async fn maintain(inner: Weak<Inner>) {
+    let mut tick = tokio::time::interval(MAINTENANCE_INTERVAL);
+    tick.tick().await;
+    loop {
+        tick.tick().await;
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+        inner.maintain().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn start_node(bootstrap: Option<SocketAddr>) -> Dht {
+        Dht::start(DhtConfig {
+            bind: SocketAddr::from(([127, 0, 0, 1], 0)),
+            bootstrap: bootstrap.iter().map(|a| a.to_string()).collect(),
+            state_file: None,
+            query_timeout: Duration::from_millis(500),
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn nodes_find_announced_peers() {
+        let router = start_node(None).await;
+        let router_addr = router.local_addr().unwrap();
+        let mut nodes = Vec::new();
+        for _ in 0..4 {
+            let node = start_node(Some(router_addr)).await;
+            assert!(node.bootstrap().await.unwrap() >= 1);
+            nodes.push(node);
+        }
+        // late joiners learn about the earlier ones through the router
+        assert!(nodes[3].node_count() >= 2);
+        assert!(router.node_count() >= nodes.len());
+
+        let info_hash = [0x42; 20];
+        assert!(nodes[0].lookup_peers(info_hash).await.is_empty());
+        nodes[0].announce(info_hash, 51413).await;
+        let peer = SocketAddr::from(([127, 0, 0, 1], 51413));
+        for node in &nodes[1..] {
+            assert_eq!(node.lookup_peers(info_hash).await, vec![peer]);
+        }
+        // another torrent stays unknown
+        assert!(nodes[2].lookup_peers([0x24; 20]).await.is_empty());
+    }
+}
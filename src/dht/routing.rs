@@ -0,0 +1,248 @@
+use std::fmt::{Debug, Display, Formatter};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::torrent::random;
+
+/// nodes per bucket, also the number of closest nodes a lookup converges on
+pub const K: usize = 8;
+// a node is replaced by a new one after this many unanswered queries
+const MAX_FAILURES: u32 = 2;
+// nodes heard from within this time are good and never replaced
+const GOOD_FOR: Duration = Duration::from_secs(15 * 60);
+
+/// 160 bit id of a DHT node, also used for info hashes as lookup targets.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub [u8; 20]);
+
+impl NodeId {
+    pub fn random() -> Self {
+        let mut id = [0u8; 20];
+        random::fill(&mut id);
+        NodeId(id)
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Option<Self> {
+        Some(NodeId(bytes.try_into().ok()?))
+    }
+
+    /// XOR metric, compare the results to order nodes by closeness
+    pub fn distance(&self, other: &NodeId) -> [u8; 20] {
+        let mut d = [0u8; 20];
+        for (i, b) in d.iter_mut().enumerate() {
+            *b = self.0[i] ^ other.0[i];
+        }
+        d
+    }
+
+    /// number of leading bits shared with `other`, 160 for the same id
+    pub fn common_prefix(&self, other: &NodeId) -> usize {
+        let d = self.distance(other);
+        d.iter()
+            .position(|b| *b != 0)
+            .map_or(160, |i| i * 8 + d[i].leading_zeros() as usize)
+    }
+}
+
+impl Display for NodeId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl Debug for NodeId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NodeEntry {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+    pub last_seen: Instant,
+    pub failures: u32,
+}
+
+impl NodeEntry {
+    fn is_bad(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+
+    fn is_good(&self) -> bool {
+        self.failures == 0 && self.last_seen.elapsed() < GOOD_FOR
+    }
+}
+
+/// Kademlia routing table: bucket `i` holds up to `K` nodes sharing exactly `i` leading
+/// bits with our own id, so we know many nodes close to us and a few far away.
+#[derive(Debug)]
+pub struct RoutingTable {
+    own: NodeId,
+    buckets: Vec<Vec<NodeEntry>>,
+}
+
+impl RoutingTable {
+    pub fn new(own: NodeId) -> Self {
+        Self {
+            own,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    pub fn own_id(&self) -> NodeId {
+        self.own
+    }
+
+    /// a node talked to us, false if its bucket is full of good nodes
+    pub fn insert(&mut self, id: NodeId, addr: SocketAddr) -> bool {
+        if id == self.own {
+            return false;
+        }
+        let bucket = &mut self.buckets[self.own.common_prefix(&id)];
+        if let Some(node) = bucket.iter_mut().find(|n| n.id == id) {
+            node.addr = addr;
+            node.last_seen = Instant::now();
+            node.failures = 0;
+            return true;
+        }
+        let entry = NodeEntry {
+            id,
+            addr,
+            last_seen: Instant::now(),
+            failures: 0,
+        };
+        if bucket.len() < K {
+            bucket.push(entry);
+            return true;
+        }
+        // replace the worst node unless all of them are good
+        let worst = bucket
+            .iter()
+            .enumerate()
+            .filter(|(_, n)| !n.is_good())
+            .max_by_key(|(_, n)| (n.is_bad(), n.last_seen.elapsed()))
+            .map(|(i, _)| i);
+        match worst {
+            Some(i) => {
+                bucket[i] = entry;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// a query to `addr` went unanswered
+    pub fn mark_failed(&mut self, addr: SocketAddr) {
+        for bucket in &mut self.buckets {
+            if let Some(node) = bucket.iter_mut().find(|n| n.addr == addr) {
+                node.failures += 1;
+            }
+        }
+    }
+
+    /// up to `n` nodes closest to `target`, closest first; bad nodes are skipped
+    pub fn closest(&self, target: &NodeId, n: usize) -> Vec<NodeEntry> {
+        let mut nodes: Vec<NodeEntry> = self.nodes().filter(|e| !e.is_bad()).cloned().collect();
+        nodes.sort_by_key(|e| e.id.distance(target));
+        nodes.truncate(n);
+        nodes
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &NodeEntry> {
+        self.buckets.iter().flatten()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWN: NodeId = NodeId([0; 20]);
+
+    // a node sharing exactly `prefix` leading bits with `OWN`, told apart by `n`
+    fn node(prefix: usize, n: u8) -> NodeId {
+        let mut id = [0u8; 20];
+        id[prefix / 8] = 0x80 >> (prefix % 8);
+        id[19] |= n;
+        NodeId(id)
+    }
+
+    fn addr(n: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], n))
+    }
+
+    #[test]
+    fn measures_shared_prefixes() {
+        assert_eq!(OWN.common_prefix(&OWN), 160);
+        for prefix in [0, 1, 7, 8, 13, 159] {
+            assert_eq!(OWN.common_prefix(&node(prefix, 0)), prefix);
+        }
+        assert_eq!(node(3, 0).distance(&node(3, 5))[19], 5);
+    }
+
+    #[test]
+    fn splits_nodes_into_buckets_by_shared_prefix() {
+        let mut table = RoutingTable::new(OWN);
+        assert!(!table.insert(OWN, addr(1)));
+        // the far half of the id space holds K nodes
+        for n in 0..K as u8 {
+            assert!(table.insert(node(0, n), addr(n.into())));
+        }
+        assert!(!table.insert(node(0, 100), addr(100)));
+        // nodes closer to us go to buckets of their own, each holding K again
+        for prefix in 1..4 {
+            for n in 0..K as u8 {
+                assert!(table.insert(node(prefix, n), addr(n.into())));
+            }
+            assert!(!table.insert(node(prefix, 100), addr(100)));
+        }
+        assert_eq!(table.len(), 4 * K);
+        assert_eq!(table.buckets[2].len(), K);
+        assert!(table.buckets[2]
+            .iter()
+            .all(|n| OWN.common_prefix(&n.id) == 2));
+        // a node already known is refreshed, not added again
+        assert!(table.insert(node(0, 0), addr(999)));
+        assert_eq!(table.len(), 4 * K);
+        assert_eq!(table.buckets[0][0].addr, addr(999));
+    }
+
+    #[test]
+    fn replaces_failed_nodes_in_a_full_bucket() {
+        let mut table = RoutingTable::new(OWN);
+        for n in 0..K as u8 {
+            table.insert(node(0, n), addr(n.into()));
+        }
+        table.mark_failed(addr(3));
+        table.mark_failed(addr(3));
+        assert!(table
+            .closest(&node(0, 3), K)
+            .iter()
+            .all(|n| n.id != node(0, 3)));
+
+        assert!(table.insert(node(0, 100), addr(100)));
+        assert!(table.nodes().all(|n| n.id != node(0, 3)));
+        assert_eq!(table.len(), K);
+    }
+
+    #[test]
+    fn finds_the_closest_nodes() {
+        let mut table = RoutingTable::new(OWN);
+        for prefix in 0..6 {
+            table.insert(node(prefix, 1), addr(prefix as u16));
+        }
+        let target = node(4, 0);
+        let closest: Vec<NodeId> = table.closest(&target, 3).iter().map(|n| n.id).collect();
+        assert_eq!(closest, [node(4, 1), node(5, 1), node(3, 1)]);
+        assert_eq!(table.closest(&target, 100).len(), 6);
+    }
+}
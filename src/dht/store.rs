@@ -0,0 +1,52 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// announced peers expire after this time without a new announce
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+const MAX_PEERS_PER_HASH: usize = 200;
+const MAX_HASHES: usize = 2000;
+
+/// Peers announced to our node, by info hash.
+#[derive(Debug, Default)]
+pub struct PeerStore {
+    swarms: HashMap<[u8; 20], HashMap<SocketAddr, Instant>>,
+}
+
+impl PeerStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, info_hash: [u8; 20], peer: SocketAddr) {
+        if self.swarms.len() >= MAX_HASHES && !self.swarms.contains_key(&info_hash) {
+            self.expire();
+            if self.swarms.len() >= MAX_HASHES {
+                return;
+            }
+        }
+        let swarm = self.swarms.entry(info_hash).or_default();
+        if swarm.len() < MAX_PEERS_PER_HASH || swarm.contains_key(&peer) {
+            swarm.insert(peer, Instant::now());
+        }
+    }
+
+    /// up to `max` live peers of `info_hash`
+    pub fn get(&self, info_hash: &[u8; 20], max: usize) -> Vec<SocketAddr> {
+        self.swarms
+            .get(info_hash)
+            .into_iter()
+            .flatten()
+            .filter(|(_, at)| at.elapsed() < PEER_TTL)
+            .map(|(peer, _)| *peer)
+            .take(max)
+            .collect()
+    }
+
+    pub fn expire(&mut self) {
+        for swarm in self.swarms.values_mut() {
+            swarm.retain(|_, at| at.elapsed() < PEER_TTL);
+        }
+        self.swarms.retain(|_, swarm| !swarm.is_empty());
+    }
+}
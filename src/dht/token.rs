@@ -0,0 +1,108 @@
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use sha1::{Digest, Sha1};
+
+use crate::torrent::random;
+
+/// the secret changes this often, tokens of the previous secret stay valid too
+const ROTATE: Duration = Duration::from_secs(5 * 60);
+
+/// Tokens handed out with `get_peers` responses and required by `announce_peer`, so a
+/// node can only announce from the address that asked for peers.
+#[derive(Debug)]
+pub struct Tokens {
+    current: [u8; 16],
+    previous: [u8; 16],
+    rotated: Instant,
+}
+
+impl Default for Tokens {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tokens {
+    pub fn new() -> Self {
+        let mut current = [0u8; 16];
+        random::fill(&mut current);
+        Self {
+            current,
+            previous: current,
+            rotated: Instant::now(),
+        }
+    }
+
+    pub fn issue(&mut self, ip: IpAddr) -> Vec<u8> {
+        self.rotate();
+        token(&self.current, ip)
+    }
+
+    pub fn verify(&mut self, ip: IpAddr, expected: &[u8]) -> bool {
+        self.rotate();
+        [self.current, self.previous]
+            .iter()
+            .any(|secret| token(secret, ip) == expected)
+    }
+
+    fn rotate(&mut self) {
+        if self.rotated.elapsed() >= ROTATE {
+            self.previous = self.current;
+            random::fill(&mut self.current);
+            self.rotated = Instant::now();
+        }
+    }
+}
+
+fn token(secret: &[u8], ip: IpAddr) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    match ip {
+        IpAddr::V4(ip) => hasher.update(ip.octets()),
+        IpAddr::V6(ip) => hasher.update(ip.octets()),
+    }
+    hasher.update(secret);
+    hasher.finalize()[..8].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // as if the secret was last changed `ago`
+    fn age(tokens: &mut Tokens, ago: Duration) {
+        tokens.rotated = Instant::now().checked_sub(ago).unwrap();
+    }
+
+    #[test]
+    fn binds_tokens_to_the_address() {
+        let mut tokens = Tokens::new();
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        let token = tokens.issue(ip);
+        assert_eq!(token.len(), 8);
+        assert!(tokens.verify(ip, &token));
+        assert!(!tokens.verify(IpAddr::from([10, 0, 0, 2]), &token));
+        assert!(!tokens.verify(ip, &token[..7]));
+        assert!(!Tokens::new().verify(ip, &token));
+    }
+
+    #[test]
+    fn accepts_tokens_of_the_previous_secret_only() {
+        let mut tokens = Tokens::new();
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        let old = tokens.issue(ip);
+
+        age(&mut tokens, ROTATE);
+        let new = tokens.issue(ip);
+        assert_ne!(new, old);
+        assert!(tokens.verify(ip, &old));
+        assert!(tokens.verify(ip, &new));
+
+        age(&mut tokens, ROTATE);
+        assert!(!tokens.verify(ip, &old));
+        assert!(tokens.verify(ip, &new));
+        // not rotated again before the time is up
+        age(&mut tokens, ROTATE - Duration::from_secs(1));
+        assert!(tokens.verify(ip, &new));
+    }
+}
@@ -0,0 +1,5 @@
+pub mod bencode;
+pub mod dht;
+pub mod torrent;
+pub mod tracker;
+pub mod utp;
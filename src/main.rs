@@ -1,16 +1,20 @@
-use std::net::SocketAddrV4;
+use std::net::SocketAddr;
+use std::time::Duration;
 use std::{env, string};
 
 use anyhow::Context;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-
-use torrent::client::Client;
-use torrent::handeshake::Handshake;
-use torrent::torrent::Keys::{Multiple, Single};
-use torrent::torrent::Torrent;
-
-mod bencode;
-mod torrent;
+use bittorrent_starter_rust::bencode;
+use bittorrent_starter_rust::dht::{Dht, DhtConfig};
+use bittorrent_starter_rust::torrent::client::Client;
+use bittorrent_starter_rust::torrent::listener::ListenerConfig;
+use bittorrent_starter_rust::torrent::mse::EncryptionPolicy;
+use bittorrent_starter_rust::torrent::peer::TransportPreference;
+use bittorrent_starter_rust::torrent::proxy::ProxyConfig;
+use bittorrent_starter_rust::torrent::session::Session;
+use bittorrent_starter_rust::torrent::torrent::Torrent;
+use bittorrent_starter_rust::tracker;
+use bittorrent_starter_rust::tracker::TrackerConfig;
+use bittorrent_starter_rust::utp::UtpConfig;
 
 // Available if you need it!
 
@@ -29,37 +33,92 @@ async fn main() -> anyhow::Result<()> {
         }
         "peers" => {
             let torrent = Torrent::from_file(&args[2]);
-            Client::new(torrent)
+            client(&args, torrent)?
                 .get_peers()
-                .await
-                .unwrap()
+                .await?
                 .iter()
                 .for_each(|peer| println!("{}:{}", peer.0, peer.1));
         }
         "handshake" => {
-            let peer = &args[3];
-            // 165.232.33.77:51467
-            // 178.62.85.20:51489
-            // 178.62.82.89:51448
-
-            // println!("handshake with {}", peer);
+            // ./your_bittorrent.sh handshake sample.torrent 165.232.33.77:51467
+            let peer: SocketAddr = args[3]
+                .parse()
+                .with_context(|| format!("invalid peer address {}", args[3]))?;
             let torrent = Torrent::from_file(&args[2]);
-            let handshake = Client::new(torrent).handshake().await.unwrap();
-            assert_eq!(handshake.length, 19);
-            assert_eq!(&handshake.bittorrent, b"BitTorrent protocol");
-            println!("Peer ID: {:}", hex::encode(&handshake.peer_id));
+            let mut client = client(&args, torrent)?;
+            if let Some(secs) = option(&args, "--timeout") {
+                client = client.with_connect_timeout(Duration::from_secs(secs.parse()?));
+            }
+            let handshake = client.handshake_with(peer).await?;
+            println!("Peer ID: {:}", hex::encode(handshake.peer_id));
         }
         "download_piece" => {
             // args.iter().into_iter().for_each(|a| println!("{}", a));
             let torrent = Torrent::from_file(&args[4]);
             let piece: usize = args[5].parse()?;
-            Client::new(torrent).download_pieces(piece).await?
+            client(&args, torrent)?
+                .download_pieces(&args[3], piece)
+                .await?
         }
         "download" => {
             // ./your_bittorrent.sh download -o /tmp/test.txt sample.torrent
             println!("=============={:?}",args);
             let torrent = Torrent::from_file(&args[4]);
-            Client::new(torrent).download(&args[3]).await?
+            client(&args, torrent)?.download(&args[3]).await?
+        }
+        "seed" => {
+            // ./your_bittorrent.sh seed sample.torrent /tmp/test.txt --port 6881
+            let torrent = Torrent::from_file(&args[2]);
+            client(&args, torrent)?
+                .with_listener(ListenerConfig::default())
+                .seed(&args[3])
+                .await?
+        }
+        "dht" => {
+            // ./your_bittorrent.sh dht --dht-port 6881 --dht-bootstrap host:port,host:port
+            let config = dht_config(&args)?;
+            let state_file = config.state_file.clone();
+            let dht = Dht::start(config).await?;
+            println!("dht node {} listening on {}", dht.id(), dht.local_addr()?);
+            match dht.bootstrap().await {
+                Ok(n) => println!("bootstrapped with {} nodes", n),
+                // the first node of a network has no one to bootstrap from
+                Err(e) => println!("bootstrap failed: {:#}", e),
+            }
+            loop {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                println!("{} nodes known", dht.node_count());
+                if let Some(path) = &state_file {
+                    dht.save(path)?;
+                }
+            }
+        }
+        "tracker" => {
+            // ./your_bittorrent.sh tracker --http 0.0.0.0:6969 --udp 0.0.0.0:6969 --whitelist hashes.txt
+            // --trusted 10.0.0.0/8 lets those clients announce other addresses, like loopback
+            let mut config = TrackerConfig::default();
+            if let Some(addr) = option(&args, "--http") {
+                config.http_addr = (addr != "off").then(|| addr.parse()).transpose()?;
+            }
+            if let Some(addr) = option(&args, "--udp") {
+                config.udp_addr = (addr != "off").then(|| addr.parse()).transpose()?;
+            }
+            if let Some(secs) = option(&args, "--interval") {
+                config.interval = Duration::from_secs(secs.parse()?);
+            }
+            if let Some(secs) = option(&args, "--peer-ttl") {
+                config.peer_ttl = Duration::from_secs(secs.parse()?);
+            }
+            if let Some(list) = option(&args, "--trusted") {
+                config.trusted_networks = list
+                    .split(',')
+                    .map(|n| TrackerConfig::parse_network(n.trim()))
+                    .collect::<anyhow::Result<_>>()?;
+            }
+            if let Some(path) = option(&args, "--whitelist") {
+                config.whitelist = Some(TrackerConfig::load_whitelist(path)?);
+            }
+            tracker::run(config).await?
         }
         _ => {
             println!("unknown command: {}", args[1]);
@@ -69,22 +128,90 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-fn get_file_length(torrent: &Torrent) -> usize {
-    match torrent.info.keys {
-        Single { length } => length,
-        Multiple { .. } => 0,
+/// client for `torrent` honoring the global options:
+/// `--proxy <url>`, `--proxy-peers`, `--no-direct-fallback`, `--max-peers <n>`,
+/// `--sequential`, `--file-priorities <p,p,..>`, `--port <n>`, `--listen` and
+/// `--upload-slots <n>`, `--encryption <plaintext|prefer|require>`, `--no-lsd`, `--no-web-seeds`,
+/// `--transport <tcp|prefer-tcp|prefer-utp|utp>`, `--utp-loss <0..1>`, `--dht` and the
+/// options of `dht_config`
+fn client(args: &[String], torrent: Torrent) -> anyhow::Result<Client> {
+    let mut session = Session::new();
+    if let Some(port) = option(args, "--port") {
+        session = session.with_port(port.parse()?);
+    }
+    let mut client =
+        Client::with_session(torrent, session)
+            .with_sequential(flag(args, "--sequential"))
+            .with_lsd(!flag(args, "--no-lsd"))
+            .with_web_seeds(!flag(args, "--no-web-seeds"));
+    if flag(args, "--listen") {
+        client = client.with_listener(ListenerConfig::default());
+    }
+    if let Some(list) = option(args, "--file-priorities") {
+        let priorities = list
+            .split(',')
+            .map(|p| p.trim().parse())
+            .collect::<Result<Vec<u8>, _>>()?;
+        client = client.with_file_priorities(priorities);
+    }
+    if let Some(n) = option(args, "--upload-slots") {
+        client = client.with_upload_slots(n.parse()?, 1);
+    }
+    if let Some(n) = option(args, "--max-peers") {
+        client = client.with_max_peers(n.parse()?);
+    }
+    if let Some(policy) = option(args, "--encryption") {
+        client = client.with_encryption(EncryptionPolicy::parse(policy)?);
+    }
+    if let Some(transport) = option(args, "--transport") {
+        client = client.with_transport(TransportPreference::parse(transport)?);
+    }
+    if let Some(loss) = option(args, "--utp-loss") {
+        client = client.with_utp(UtpConfig {
+            simulated_loss: loss.parse()?,
+            ..UtpConfig::default()
+        });
+    }
+    if flag(args, "--dht") {
+        client = client.with_dht(dht_config(args)?);
+    }
+    match option(args, "--proxy") {
+        Some(url) => {
+            let proxy = ProxyConfig::parse(url)?
+                .with_proxy_peers(flag(args, "--proxy-peers"))
+                .with_direct_fallback(!flag(args, "--no-direct-fallback"));
+            client.with_proxy(proxy)
+        }
+        None => Ok(client),
     }
 }
 
-fn url_encode(bytes: &[u8]) -> String {
-    let _result = String::new();
-    bytes
-        .iter()
-        .map(|&b| match b {
-            b'0'..=b'9' | b'A'..=b'Z' | b'a'..=b'z' | b'-' | b'.' | b'_' | b'~' => {
-                String::from(b as char)
-            }
-            _ => format!("%{:02X}", b),
-        })
-        .collect()
+/// dht node honoring `--dht-port <n>`, `--dht-bootstrap <host:port,..>` and
+/// `--dht-state <file>`
+fn dht_config(args: &[String]) -> anyhow::Result<DhtConfig> {
+    let mut config = DhtConfig::default();
+    if let Some(port) = option(args, "--dht-port") {
+        config.bind.set_port(port.parse()?);
+    }
+    if let Some(list) = option(args, "--dht-bootstrap") {
+        config.bootstrap = list
+            .split(',')
+            .map(|n| n.trim().to_string())
+            .filter(|n| !n.is_empty())
+            .collect();
+    }
+    config.state_file = option(args, "--dht-state").map(Into::into);
+    Ok(config)
+}
+
+fn flag(args: &[String], name: &str) -> bool {
+    args.iter().any(|a| a == name)
+}
+
+/// value following `name` on the command line, e.g. `--port 6881`
+fn option<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|a| a == name)
+        .and_then(|i| args.get(i + 1))
+        .map(String::as_str)
 }
@@ -1,5 +1,27 @@
+#[allow(clippy::module_inception)]
 pub mod torrent;
 pub mod handeshake;
 pub mod exchange;
-mod serde;
-pub(crate) mod client;
\ No newline at end of file
+pub mod bitfield;
+pub mod availability;
+pub mod extension;
+pub mod fast;
+pub mod pex;
+pub mod pipeline;
+pub mod picker;
+pub mod peer;
+pub mod choker;
+pub mod engine;
+pub mod listener;
+pub mod lsd;
+pub mod mse;
+pub mod storage;
+pub mod rate;
+pub mod stats;
+pub mod session;
+pub mod random;
+pub(crate) mod serde;
+pub mod client;
+pub mod proxy;
+pub mod transport;
+pub mod webseed;
@@ -0,0 +1,55 @@
+use crate::torrent::bitfield::BitField;
+
+/// How many connected peers have each piece of a torrent.
+#[derive(Debug, Clone, Default)]
+pub struct Availability {
+    counts: Vec<u32>,
+}
+
+impl Availability {
+    pub fn new(piece_count: usize) -> Self {
+        Self {
+            counts: vec![0; piece_count],
+        }
+    }
+
+    /// a peer sent its bitfield
+    pub fn add_bitfield(&mut self, bits: &BitField) {
+        for piece in bits.iter() {
+            self.add_have(piece);
+        }
+    }
+
+    /// a peer announced a new piece
+    pub fn add_have(&mut self, piece: usize) {
+        if let Some(c) = self.counts.get_mut(piece) {
+            *c += 1;
+        }
+    }
+
+    /// a peer disconnected, `bits` is everything it had
+    pub fn remove_bitfield(&mut self, bits: &BitField) {
+        for piece in bits.iter() {
+            if let Some(c) = self.counts.get_mut(piece) {
+                *c = c.saturating_sub(1);
+            }
+        }
+    }
+
+    pub fn count(&self, piece: usize) -> u32 {
+        self.counts.get(piece).copied().unwrap_or(0)
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.counts.len()
+    }
+
+    /// pieces no connected peer has
+    pub fn missing(&self) -> impl Iterator<Item = usize> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| **c == 0)
+            .map(|(i, _)| i)
+    }
+}
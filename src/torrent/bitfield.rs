@@ -0,0 +1,114 @@
+use anyhow::{ensure, Result};
+
+/// Pieces a peer has, bit 0 of the first byte is piece 0 (high bit first).
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct BitField {
+    bytes: Vec<u8>,
+    // number of pieces, the bits after it in the last byte are spare bits
+    len: usize,
+}
+
+impl BitField {
+    /// empty bitfield for `len` pieces
+    pub fn new(len: usize) -> Self {
+        BitField {
+            bytes: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    /// bitfield with every piece set, e.g. for a seeder
+    pub fn full(len: usize) -> Self {
+        let mut bits = Self::new(len);
+        (0..len).for_each(|i| bits.set(i));
+        bits
+    }
+
+    /// raw bytes from the wire, the piece count is unknown until [`BitField::validate`]
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        BitField {
+            bytes: bytes.to_vec(),
+            len: bytes.len() * 8,
+        }
+    }
+
+    /// check the received bitfield against the torrent's piece count:
+    /// the byte length must match and the spare bits must be cleared
+    pub fn validate(mut self, piece_count: usize) -> Result<Self> {
+        ensure!(
+            self.bytes.len() == piece_count.div_ceil(8),
+            "bitfield of {} bytes doesn't match {} pieces",
+            self.bytes.len(),
+            piece_count
+        );
+        self.len = piece_count;
+        ensure!(
+            (piece_count..self.bytes.len() * 8).all(|i| !self.bit(i)),
+            "bitfield has spare bits set"
+        );
+        Ok(self)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, piece: usize) -> bool {
+        piece < self.len && self.bit(piece)
+    }
+
+    /// out of range pieces are ignored
+    pub fn set(&mut self, piece: usize) {
+        if piece < self.len {
+            self.bytes[piece / 8] |= 0x80 >> (piece % 8);
+        }
+    }
+
+    pub fn clear(&mut self, piece: usize) {
+        if piece < self.len {
+            self.bytes[piece / 8] &= !(0x80 >> (piece % 8));
+        }
+    }
+
+    /// number of pieces set
+    pub fn count(&self) -> usize {
+        self.bytes.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.len
+    }
+
+    /// pieces set in both
+    pub fn intersection(&self, other: &BitField) -> BitField {
+        let bytes = self
+            .bytes
+            .iter()
+            .zip(&other.bytes)
+            .map(|(a, b)| a & b)
+            .collect();
+        BitField {
+            bytes,
+            len: self.len.min(other.len),
+        }
+    }
+
+    /// indices of the pieces set
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|i| self.bit(*i))
+    }
+
+    fn bit(&self, i: usize) -> bool {
+        self.bytes
+            .get(i / 8)
+            .is_some_and(|b| b & (0x80 >> (i % 8)) != 0)
+    }
+}
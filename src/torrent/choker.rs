@@ -0,0 +1,316 @@
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use crate::torrent::random;
+
+/// Connections younger than this count as new and are three times as likely to get the
+/// optimistic unchoke, they have nothing to reciprocate with yet.
+const NEW_PEER_AGE: Duration = Duration::from_secs(60);
+
+/// Upload slot settings of the choker.
+#[derive(Debug, Clone, Copy)]
+pub struct ChokerConfig {
+    /// peers unchoked for their rate
+    pub upload_slots: usize,
+    /// peers unchoked regardless of their rate
+    pub optimistic_slots: usize,
+    /// time between two rounds
+    pub interval: Duration,
+    /// time between two optimistic unchoke rotations
+    pub optimistic_interval: Duration,
+}
+
+impl Default for ChokerConfig {
+    fn default() -> Self {
+        Self {
+            upload_slots: 4,
+            optimistic_slots: 1,
+            interval: Duration::from_secs(10),
+            optimistic_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+/// A connection as seen by the choker.
+#[derive(Debug, Clone, Copy)]
+pub struct ChokeCandidate {
+    pub id: u64,
+    pub interested: bool,
+    /// bytes/s the peer gives us
+    pub download_rate: f64,
+    /// bytes/s we give the peer
+    pub upload_rate: f64,
+    pub connected_at: Instant,
+}
+
+impl ChokeCandidate {
+    // while downloading peers are ranked by what they give us, once seeding by what
+    // they take
+    fn rate(&self, seeding: bool) -> f64 {
+        if seeding {
+            self.upload_rate
+        } else {
+            self.download_rate
+        }
+    }
+}
+
+/// Tit-for-tat: the interested peers with the best rates get the upload slots, plus an
+/// optimistic unchoke rotating between the others so new peers get a chance to prove
+/// themselves.
+#[derive(Debug)]
+pub struct Choker {
+    config: ChokerConfig,
+    /// peers unchoked for their rate in the last round
+    regular: Vec<u64>,
+    optimistic: Vec<u64>,
+    rotated_at: Option<Instant>,
+}
+
+impl Choker {
+    pub fn new(config: ChokerConfig) -> Self {
+        Self {
+            config,
+            regular: Vec::new(),
+            optimistic: Vec::new(),
+            rotated_at: None,
+        }
+    }
+
+    /// the peers to unchoke in a full round, everybody else is choked
+    pub fn choose(
+        &mut self,
+        peers: &[ChokeCandidate],
+        seeding: bool,
+        now: Instant,
+    ) -> HashSet<u64> {
+        let interested = Self::by_rate(peers, seeding);
+        self.regular = interested
+            .iter()
+            .take(self.config.upload_slots)
+            .map(|p| p.id)
+            .collect();
+
+        let rotate = self
+            .rotated_at
+            .is_none_or(|at| now.duration_since(at) >= self.config.optimistic_interval);
+        if rotate {
+            self.optimistic.clear();
+            self.rotated_at = Some(now);
+        }
+        self.unchoke(&interested, now)
+    }
+
+    /// the peers to unchoke between rounds, when peers come and go or change their
+    /// interest: unchoked peers keep their slots while interested and only free slots
+    /// are handed out. A full round on every change would choke peers we just unchoked
+    /// and back, before either side got a block through.
+    pub fn fill(&mut self, peers: &[ChokeCandidate], seeding: bool, now: Instant) -> HashSet<u64> {
+        let interested = Self::by_rate(peers, seeding);
+        self.regular
+            .retain(|id| interested.iter().any(|p| p.id == *id));
+        for p in &interested {
+            if self.regular.len() >= self.config.upload_slots {
+                break;
+            }
+            if !self.regular.contains(&p.id) && !self.optimistic.contains(&p.id) {
+                self.regular.push(p.id);
+            }
+        }
+        self.unchoke(&interested, now)
+    }
+
+    // the interested peers, best rate first
+    fn by_rate(peers: &[ChokeCandidate], seeding: bool) -> Vec<&ChokeCandidate> {
+        let mut interested: Vec<&ChokeCandidate> = peers.iter().filter(|p| p.interested).collect();
+        interested.sort_by(|a, b| b.rate(seeding).total_cmp(&a.rate(seeding)));
+        interested
+    }
+
+    // the regular slots plus the optimistic ones, topped up from the other interested peers
+    fn unchoke(&mut self, interested: &[&ChokeCandidate], now: Instant) -> HashSet<u64> {
+        let mut unchoked: HashSet<u64> = self.regular.iter().copied().collect();
+        // keep the current optimistic peers while they are interested
+        self.optimistic
+            .retain(|id| !unchoked.contains(id) && interested.iter().any(|p| p.id == *id));
+        let mut others: Vec<&ChokeCandidate> = interested
+            .iter()
+            .copied()
+            .filter(|p| !unchoked.contains(&p.id) && !self.optimistic.contains(&p.id))
+            .collect();
+        while self.optimistic.len() < self.config.optimistic_slots && !others.is_empty() {
+            let pick = Self::weighted_pick(&others, now);
+            self.optimistic.push(others.swap_remove(pick).id);
+        }
+        unchoked.extend(&self.optimistic);
+        unchoked
+    }
+
+    fn weighted_pick(peers: &[&ChokeCandidate], now: Instant) -> usize {
+        let weight = |p: &ChokeCandidate| {
+            if now.duration_since(p.connected_at) < NEW_PEER_AGE {
+                3
+            } else {
+                1
+            }
+        };
+        let total: usize = peers.iter().map(|p| weight(p)).sum();
+        let mut n = random::below(total);
+        for (i, p) in peers.iter().enumerate() {
+            let w = weight(p);
+            if n < w {
+                return i;
+            }
+            n -= w;
+        }
+        peers.len() - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn config(upload_slots: usize, optimistic_slots: usize) -> ChokerConfig {
+        ChokerConfig {
+            upload_slots,
+            optimistic_slots,
+            ..ChokerConfig::default()
+        }
+    }
+
+    // an interested peer connected at `start`, downloading from it at `rate`
+    fn peer(id: u64, rate: f64, start: Instant) -> ChokeCandidate {
+        ChokeCandidate {
+            id,
+            interested: true,
+            download_rate: rate,
+            upload_rate: 0.0,
+            connected_at: start,
+        }
+    }
+
+    fn ids(unchoked: HashSet<u64>) -> Vec<u64> {
+        let mut ids: Vec<u64> = unchoked.into_iter().collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    #[test]
+    fn unchokes_the_fastest_peers_in_the_upload_slots() {
+        let start = Instant::now();
+        let now = start + 600 * SECOND;
+        let peers: Vec<ChokeCandidate> = [10.0, 50.0, 30.0, 0.0, 40.0, 20.0]
+            .iter()
+            .enumerate()
+            .map(|(id, rate)| peer(id as u64, *rate, start))
+            .collect();
+
+        assert_eq!(
+            ids(Choker::new(config(2, 0)).choose(&peers, false, now)),
+            [1, 4]
+        );
+        assert_eq!(
+            ids(Choker::new(config(3, 0)).choose(&peers, false, now)),
+            [1, 2, 4]
+        );
+        let unchoked = Choker::new(config(3, 1)).choose(&peers, false, now);
+        assert_eq!(unchoked.len(), 4);
+        assert!([1, 2, 4].iter().all(|id| unchoked.contains(id)));
+        assert_eq!(
+            Choker::new(config(10, 1)).choose(&peers, false, now).len(),
+            6
+        );
+    }
+
+    #[test]
+    fn unchokes_interested_peers_only() {
+        let start = Instant::now();
+        let now = start + 600 * SECOND;
+        let mut peers: Vec<ChokeCandidate> = (0..4).map(|id| peer(id, id as f64, start)).collect();
+        peers[3].interested = false;
+        peers[2].interested = false;
+
+        let mut choker = Choker::new(config(1, 1));
+        for round in 0..10 {
+            let unchoked = choker.choose(&peers, false, now + round * 30 * SECOND);
+            assert_eq!(ids(unchoked), [0, 1]);
+        }
+        peers.iter_mut().for_each(|p| p.interested = false);
+        assert!(choker.choose(&peers, false, now).is_empty());
+    }
+
+    #[test]
+    fn ranks_by_download_rate_while_leeching_and_upload_rate_while_seeding() {
+        let start = Instant::now();
+        let now = start + 600 * SECOND;
+        let mut peers = vec![peer(0, 100.0, start), peer(1, 10.0, start)];
+        peers[1].upload_rate = 500.0;
+
+        let mut choker = Choker::new(config(1, 0));
+        assert_eq!(ids(choker.choose(&peers, false, now)), [0]);
+        assert_eq!(ids(choker.choose(&peers, true, now + 10 * SECOND)), [1]);
+    }
+
+    #[test]
+    fn rotates_the_optimistic_unchoke_every_30_seconds() {
+        let start = Instant::now();
+        let now = start + 600 * SECOND;
+        let peers: Vec<ChokeCandidate> = (0..6).map(|id| peer(id, 0.0, start)).collect();
+        let mut choker = Choker::new(config(0, 1));
+
+        let mut picks = Vec::new();
+        for rotation in 0..20 {
+            let at = now + rotation * 30 * SECOND;
+            let unchoked = ids(choker.choose(&peers, false, at));
+            assert_eq!(unchoked.len(), 1);
+            // the rounds in between keep it
+            for round in 1..3 {
+                let kept = choker.choose(&peers, false, at + round * 10 * SECOND);
+                assert_eq!(ids(kept), unchoked);
+            }
+            assert_eq!(choker.rotated_at, Some(at));
+            picks.push(unchoked[0]);
+        }
+        assert!(picks.windows(2).any(|w| w[0] != w[1]), "{:?}", picks);
+    }
+
+    #[test]
+    fn favours_new_peers_for_the_optimistic_unchoke() {
+        let start = Instant::now();
+        let now = start + 600 * SECOND;
+        let new = peer(0, 0.0, now - 10 * SECOND);
+        let old: Vec<ChokeCandidate> = (1..4).map(|id| peer(id, 0.0, start)).collect();
+        let mut peers = vec![&new];
+        peers.extend(&old);
+
+        // weights 3, 1, 1, 1: the new peer is picked half of the time
+        let picked_new = (0..6000)
+            .filter(|_| Choker::weighted_pick(&peers, now) == 0)
+            .count();
+        assert!((2700..3300).contains(&picked_new), "{}", picked_new);
+    }
+
+    #[test]
+    fn fills_free_slots_between_rounds() {
+        let start = Instant::now();
+        let now = start + 600 * SECOND;
+        let mut peers: Vec<ChokeCandidate> =
+            (0..4).map(|id| peer(id, 10.0 * id as f64, start)).collect();
+        peers[3].interested = false;
+        let mut choker = Choker::new(config(2, 0));
+        assert_eq!(ids(choker.choose(&peers, false, now)), [1, 2]);
+
+        // a faster peer getting interested waits for the next round
+        peers[3].interested = true;
+        assert_eq!(ids(choker.fill(&peers, false, now + SECOND)), [1, 2]);
+        // a slot freed by a peer losing interest is filled at once
+        peers[2].interested = false;
+        assert_eq!(ids(choker.fill(&peers, false, now + 2 * SECOND)), [1, 3]);
+        peers[2].interested = true;
+        assert_eq!(ids(choker.fill(&peers, false, now + 3 * SECOND)), [1, 3]);
+        assert_eq!(ids(choker.choose(&peers, false, now + 10 * SECOND)), [2, 3]);
+    }
+}
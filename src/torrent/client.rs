@@ -1,426 +1,484 @@
-use std::fs::File;
-use std::io::Write;
-use std::net::{Ipv4Addr, SocketAddrV4};
-use std::process;
-
-use anyhow::{anyhow, Context, Result};
-use bytes::{Buf, BufMut, BytesMut};
-// has to import explicitly
-use futures_util::SinkExt;
-// has to import explicitly
-use futures_util::StreamExt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context, Result};
 use serde_bencode::from_bytes;
-use sha1::Digest;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio_util::codec::{Decoder, Encoder, Framed};
+use tokio::task::JoinHandle;
+use url::Url;
 
-use crate::torrent::exchange::{BlockReqPayload, BlockRespPayload, ExchangeMsg, MsgType};
+use crate::dht::{Dht, DhtConfig};
+use crate::torrent::engine::{Engine, EngineConfig};
+use crate::torrent::extension;
 use crate::torrent::handeshake::Handshake;
+use crate::torrent::listener::{Listener, ListenerConfig, TorrentTable};
+use crate::torrent::lsd::Lsd;
+use crate::torrent::mse::EncryptionPolicy;
+use crate::torrent::peer;
+use crate::torrent::peer::TransportPreference;
+use crate::torrent::picker::{PickMode, Priority};
+use crate::torrent::pipeline::PipelineConfig;
+use crate::torrent::proxy::{HttpClient, ProxyConfig, ProxyKind};
 use crate::torrent::serde::peers::Peer;
-use crate::torrent::torrent::{Keys, PeersResponse, Torrent};
-use crate::{get_file_length, url_encode};
+use crate::torrent::session::Session;
+use crate::torrent::storage::{FileLayout, SinglePieceFile, Storage};
+use crate::torrent::torrent::{FailureResponse, PeersResponse, Torrent};
+use crate::torrent::transport::Transport;
+use crate::utp::{UtpConfig, UtpSocket};
+
+// an announce taking longer fails, with or without a proxy
+const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(30);
 
-const BLOCK_MAX: usize = 1 << 14;
-const MAX: usize = 1 << 16;
+// dht lookups again after this long, sooner while they found no peers
+const DHT_REANNOUNCE: Duration = Duration::from_secs(15 * 60);
+const DHT_RETRY: Duration = Duration::from_secs(30);
 
 pub struct Client {
     pub torrent: Torrent,
-    pub c: reqwest::Client,
-    peer_conn: Option<TcpStream>,
+    http: HttpClient,
+    pub session: Session,
+    proxy: Option<ProxyConfig>,
+    engine: EngineConfig,
+    file_priorities: Vec<Priority>,
+    listen: Option<ListenerConfig>,
+    dht: Option<DhtConfig>,
+    lsd: bool,
+    utp: UtpConfig,
 }
 
 impl Client {
     pub fn new(torrent: Torrent) -> Self {
+        Self::with_session(torrent, Session::new())
+    }
+
+    pub fn with_session(torrent: Torrent, session: Session) -> Self {
         Self {
             torrent,
-            c: reqwest::Client::new(),
-            peer_conn: None,
+            http: HttpClient::default().with_timeout(ANNOUNCE_TIMEOUT),
+            session,
+            proxy: None,
+            engine: EngineConfig::default(),
+            file_priorities: Vec::new(),
+            listen: None,
+            dht: None,
+            lsd: true,
+            utp: UtpConfig::default(),
         }
     }
 
-    pub async fn get_peers(&self) -> Result<Vec<Peer>> {
-        let url = format!(
-            "{}?info_hash={}",
-            self.torrent.announce,
-            url_encode(self.torrent.info_hash().as_slice())
-        );
-        let builder = self.c.get(url).query(&[
-            ("peer_id", "00112233445566778899".parse().unwrap()),
-            ("port", 6881.to_string()),
-            ("uploaded", "0".parse().unwrap()),
-            ("downloaded", "0".parse().unwrap()),
-            ("left", get_file_length(&self.torrent).to_string()),
-            ("compact", "1".parse().unwrap()),
-        ]);
-        let req = builder.build();
-        let resp = self.c.execute(req.unwrap()).await?;
-
-        let bytes = resp.bytes().await?;
-        println!("Resp: {:?}", String::from_utf8_lossy(&bytes));
-        let peers_resp: PeersResponse = from_bytes::<PeersResponse>(&bytes).unwrap();
-        Ok(peers_resp.peers)
+    pub fn with_pipeline(mut self, pipeline: PipelineConfig) -> Self {
+        self.engine.pipeline = pipeline;
+        self
     }
 
-    pub async fn handshake(&mut self) -> Result<Handshake> {
-        let peers = self.get_peers().await?;
-        let peer = peers.iter().next().unwrap();
-        let peer = SocketAddrV4::new(
-            peer.0.parse::<Ipv4Addr>().context("parse peer ip").unwrap(),
-            peer.1,
-        );
-        println!("connecting to peer {:?}", peers);
-        let peer_conn = TcpStream::connect(peer)
-            .await
-            .context("connect to peer")
-            .unwrap();
-        self.peer_conn = Some(peer_conn);
-
-        let mut handshake =
-            Handshake::new(self.torrent.info_hash().into(), *b"00112233445566778899");
-        {
-            const SIZE: usize = std::mem::size_of::<Handshake>();
-            // This line casts a mutable reference to handshake to a mutable pointer to an array of bytes of the same SIZE as Handshake.
-            let handshake_bytes = &mut handshake as *mut Handshake as *mut [u8; SIZE];
-            // Safety: Handshake is a POD with repr(c)
-            // This block contains unsafe code that dereferences the pointer created in the
-            // previous line to obtain a mutable reference to an array of bytes.
-            let handshake_bytes: &mut [u8; SIZE] = unsafe { &mut *handshake_bytes };
-            println!("handshake start");
-            // Option.unwrap will move value, so instead we get a mut ref to connection
-            if let Some(conn) = self.peer_conn.as_mut() {
-                conn.write_all(handshake_bytes)
-                    .await
-                    .context("write handshake")
-                    .unwrap();
-                println!("handshake read resp");
-                conn.read_exact(handshake_bytes)
-                    .await
-                    .context("read handshake")
-                    .unwrap();
-                anyhow::ensure!(handshake.length == 19);
-                anyhow::ensure!(&handshake.bittorrent == b"BitTorrent protocol");
-                return Ok(handshake);
-            }
-        }
-        Err(anyhow!("handshake failed, non peer connection"))
-    }
-
-    pub async fn download_pieces(&mut self, piece_idx: usize) -> Result<()> {
-        let handshake = self.handshake().await?;
-
-        println!("handshake peer: {:?}", hex::encode(handshake.peer_id));
-        let conn = self.peer_conn.as_mut().unwrap();
-
-        println!("Wait for BitField msg");
-        let msg = ExchangeMsg::read_from(conn).await?;
-        anyhow::ensure!(msg.message_id.unwrap() == MsgType::BitField);
-
-        println!("Send Interested msg");
-        let msg = ExchangeMsg::new(MsgType::Interested, Vec::new());
-        let mut peer = Framed::new(conn, MessageCodec);
-        peer.send(msg).await.context("send interested message")?;
-
-        println!("Wait for Unchoke msg");
-        let msg = peer.next().await.context("invalid unchoke msg")?;
-        assert_eq!(MsgType::Unchoke, msg.unwrap().message_id.unwrap());
-
-        // 1. download piece with index piece_idx
-        let info = &self.torrent.info;
-        println!("Download piece {:?}", info.pieces.0);
-        let piece_hash = info.pieces.0[piece_idx];
-        let piece_count = info.pieces.0.len();
-        let req_piece_size = if piece_idx == piece_count - 1 {
-            // if last piece
-            let total_length = match info.keys {
-                Keys::Single { length } => length,
-                _ => 0,
-            };
-            let md = total_length % info.piece_length;
-
-            if md == 0 {
-                info.piece_length
-            } else {
-                md
-            }
-        } else {
-            info.piece_length
-        };
-        // 2. create request for each block of the piece
-        // each block is identified:
-        // index: piece index
-        // begin: offset within the piece
-        // length: length of the block
-
-        let mut piece_buf: Vec<u8> = Vec::with_capacity(req_piece_size);
-        // 2.1 calc offset for each block
-        let blocks_count = (req_piece_size + BLOCK_MAX - 1) / BLOCK_MAX;
-        for b in 0..blocks_count {
-            let block_size = if b == blocks_count - 1 {
-                let md = req_piece_size % BLOCK_MAX;
-                if md != 0 {
-                    md
-                } else {
-                    BLOCK_MAX
-                }
-            } else {
-                BLOCK_MAX
-            };
-
-            let mut block_payload =
-                BlockReqPayload::new(piece_idx as u32, (b * BLOCK_MAX) as u32, block_size as u32);
-            let block_payload = Vec::from(block_payload.as_bytes_mut());
-            let block_req = ExchangeMsg::new(MsgType::Request, block_payload);
-            println!("Download block {} / {}", b, blocks_count);
-
-            peer.send(block_req)
-                .await
-                .with_context(|| format!("send request for block {b}"))?;
-
-            let piece = peer
-                .next()
-                .await
-                .expect("peer always send a piece")
-                .with_context(|| "peer message is invalid")?;
-
-            assert_eq!(piece.message_id, Some(MsgType::Piece));
-            assert!(!piece.payload.is_empty());
-            // println!("block {}, resp payload: {:?}", b, piece.payload.clone());
-            println!("Download receive block size: {}", piece.payload.len());
-            // payload format: index begin block
-            if let Some(mut resp) = BlockRespPayload::from_bytes(&piece.payload) {
-                // accumulate block resp
-                println!("pre append buf size: {}", &resp.data.len());
-                piece_buf.append(&mut resp.data.to_vec());
-            };
-        }
-        println!("piece len: {}", &piece_buf.len());
-        // verify hash
-        let mut hasher = sha1::Sha1::new();
-        Digest::update(&mut hasher, &piece_buf);
-        let hash = hasher.finalize();
-
-        anyhow::ensure!(hash.as_slice() == piece_hash);
-
-        std::fs::write(
-            format!("piece_0_{}", process::id()),
-            String::from_utf8(piece_buf)?,
-        )
-        .unwrap();
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.engine.connect.timeout = timeout;
+        self
+    }
 
-        // send request
-        Ok(())
+    /// give up on peers that keep us choked this long
+    pub fn with_unchoke_timeout(mut self, timeout: Duration) -> Self {
+        self.engine.unchoke_timeout = timeout;
+        self
     }
 
-    pub async fn download(&mut self, output_file: &str) -> Result<()> {
-        let handshake = self.handshake().await?;
+    /// peers we upload to at a time: `regular` ones for their rates, `optimistic` ones at
+    /// random
+    pub fn with_upload_slots(mut self, regular: usize, optimistic: usize) -> Self {
+        self.engine.choker.upload_slots = regular;
+        self.engine.choker.optimistic_slots = optimistic;
+        self
+    }
 
-        let conn = self.peer_conn.as_mut().unwrap();
+    /// peers connected at the same time while downloading
+    pub fn with_max_peers(mut self, max_peers: usize) -> Self {
+        self.engine.max_peers = max_peers;
+        self
+    }
 
-        let msg = ExchangeMsg::read_from(conn).await?;
-        anyhow::ensure!(msg.message_id.unwrap() == MsgType::BitField);
+    /// download pieces in order instead of rarest first, for streaming
+    pub fn with_sequential(mut self, sequential: bool) -> Self {
+        self.engine.pick_mode = if sequential {
+            PickMode::Sequential
+        } else {
+            PickMode::RarestFirst
+        };
+        self
+    }
 
-        println!("Send Interested msg");
-        let msg = ExchangeMsg::new(MsgType::Interested, Vec::new());
-        let mut peer = Framed::new(conn, MessageCodec);
-        peer.send(msg).await.context("send interested message")?;
+    /// priorities of the files in torrent order, 0 skips a file; missing entries are normal
+    pub fn with_file_priorities(mut self, priorities: Vec<Priority>) -> Self {
+        self.file_priorities = priorities;
+        self
+    }
 
-        println!("Wait for Unchoke msg");
-        let msg = peer.next().await.context("invalid unchoke msg")?;
-        assert_eq!(MsgType::Unchoke, msg.unwrap().message_id.unwrap());
+    /// accept incoming peer connections on the session port while downloading
+    pub fn with_listener(mut self, config: ListenerConfig) -> Self {
+        self.listen = Some(config);
+        self
+    }
 
-        // piece length: number of bytes in each piece, an integer
-        // pieces: concatenated SHA-1 hashes of each piece (20 bytes each), a string
-        // length: The length of the file, in bytes.
+    /// find peers through the DHT too, except for private torrents
+    pub fn with_dht(mut self, config: DhtConfig) -> Self {
+        self.dht = Some(config);
+        self
+    }
 
-        let info = &self.torrent.info;
+    /// whether peer connections are encrypted, outgoing and incoming ones
+    pub fn with_encryption(mut self, policy: EncryptionPolicy) -> Self {
+        self.engine.connect.encryption = policy;
+        self
+    }
 
-        let piece_len = info.piece_length;
+    /// announce torrents on the LAN and find peers there (on by default), never for
+    /// private torrents
+    pub fn with_lsd(mut self, enabled: bool) -> Self {
+        self.lsd = enabled;
+        self
+    }
 
-        let piece_cnt = info.pieces.0.len();
+    /// download from the torrent's web seeds too (on by default)
+    pub fn with_web_seeds(mut self, enabled: bool) -> Self {
+        self.engine.web_seeds = enabled;
+        self
+    }
 
-        let total_len = match info.keys {
-            Keys::Single { length } => length,
-            _ => 0,
-        };
-        let mut output: File = File::create(output_file)?;
-        for (piece_idx, el) in info.pieces.0.iter().enumerate() {
-            println!("Download piece at {} , hash: {:?}", piece_idx, el);
-            let req_piece_size = if piece_idx == piece_cnt - 1 {
-                let md = total_len % piece_len;
-                if md == 0 {
-                    piece_len
-                } else {
-                    md
-                }
-            } else {
-                piece_len
-            };
-
-            let mut piece_buf = Vec::with_capacity(req_piece_size);
-
-            let block_cnt = (req_piece_size + BLOCK_MAX - 1) / BLOCK_MAX;
-
-            for b in 0..block_cnt {
-                let block_size = if b == block_cnt - 1 {
-                    let md = req_piece_size % BLOCK_MAX;
-                    if md != 0 {
-                        md
-                    } else {
-                        BLOCK_MAX
-                    }
-                } else {
-                    BLOCK_MAX
-                };
+    /// transport of outgoing peer connections, with uTP we accept uTP connections too
+    pub fn with_transport(mut self, transport: TransportPreference) -> Self {
+        self.engine.connect.transport = transport;
+        self
+    }
 
-                let mut block_payload = BlockReqPayload::new(
-                    piece_idx as u32,
-                    (b * BLOCK_MAX) as u32,
-                    block_size as u32,
-                );
+    /// connect to peers through `transport` only, e.g. an in-memory network
+    pub fn with_peer_transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.engine.connect.custom = Some(transport);
+        self
+    }
 
-                let block_payload = Vec::from(block_payload.as_bytes_mut());
-                let block_req = ExchangeMsg::new(MsgType::Request, block_payload);
-                println!("Download block {}/{}", b, block_cnt);
+    pub fn with_utp(mut self, config: UtpConfig) -> Self {
+        self.utp = config;
+        self
+    }
 
-                peer.send(block_req)
-                    .await
-                    .with_context(|| format!("send request for block {b}"))?;
+    /// send announces (and peer connections if configured) through `proxy`. A socks5
+    /// proxy only carries plain http announces, https trackers need the direct fallback.
+    pub fn with_proxy(mut self, proxy: ProxyConfig) -> Result<Self> {
+        ensure!(
+            proxy.kind != ProxyKind::Socks5
+                || proxy.allow_direct_fallback
+                || !self.torrent.announce.starts_with("https:"),
+            "https tracker {} can't be reached through a socks5 proxy without direct fallback",
+            self.torrent.announce
+        );
+        self.http = HttpClient::new(Some(&proxy), Some(ANNOUNCE_TIMEOUT))?;
+        self.engine.connect.proxy = Some(proxy.clone());
+        self.proxy = Some(proxy);
+        Ok(self)
+    }
 
-                let piece = peer
-                    .next()
+    pub async fn get_peers(&self) -> Result<Vec<Peer>> {
+        match self.announce(&self.http).await {
+            Err(e) if self.proxy.as_ref().is_some_and(|p| p.allow_direct_fallback) => {
+                println!("announce through proxy failed, retry directly: {:#}", e);
+                self.announce(&HttpClient::direct()?.with_timeout(ANNOUNCE_TIMEOUT))
                     .await
-                    .expect("peer always send a piece")
-                    .with_context(|| "peer message is invalid")?;
-
-                assert_eq!(piece.message_id, Some(MsgType::Piece));
-                assert!(!piece.payload.is_empty());
-
-                println!("Download receive block size: {}", piece.payload.len());
-                // payload format: index begin block
-                if let Some(mut resp) = BlockRespPayload::from_bytes(&piece.payload) {
-                    // accumulate block resp
-                    println!("pre append buf size: {}", &resp.data.len());
-                    piece_buf.append(&mut resp.data.to_vec());
-                };
             }
+            res => res,
+        }
+    }
 
-            println!("Download Piece len: {}", &piece_buf.len());
-            // verify hash
-            let mut hasher = sha1::Sha1::new();
-            Digest::update(&mut hasher, &piece_buf);
-            let hash = hasher.finalize();
-
-            anyhow::ensure!(hash.as_slice() == el);
-
-            output.write(&piece_buf).unwrap();
+    async fn announce(&self, http: &HttpClient) -> Result<Vec<Peer>> {
+        // info_hash and peer_id are raw bytes, the query encoder only takes strings
+        let url = format!(
+            "{}?info_hash={}&peer_id={}",
+            self.torrent.announce,
+            url_encode(self.torrent.info_hash().as_slice()),
+            url_encode(&self.session.peer_id)
+        );
+        let mut params = vec![
+            ("port", self.session.port.to_string()),
+            ("uploaded", "0".to_string()),
+            ("downloaded", "0".to_string()),
+            ("left", self.torrent.length().to_string()),
+            ("compact", "1".to_string()),
+            ("no_peer_id", "1".to_string()),
+            ("key", self.session.key_hex()),
+            ("numwant", self.session.numwant.to_string()),
+        ];
+        if let Some(ip) = self.session.ip {
+            params.push(("ip", ip.to_string()));
+        }
+        let mut url =
+            Url::parse(&url).with_context(|| format!("invalid announce url {}", url))?;
+        url.query_pairs_mut().extend_pairs(&params);
+        let (_, bytes) = http.get(&url, None).await?;
+        println!("Resp: {:?}", String::from_utf8_lossy(&bytes));
+        if let Ok(failure) = from_bytes::<FailureResponse>(&bytes) {
+            bail!("tracker refused the announce: {}", failure.failure_reason);
         }
+        let peers_resp =
+            from_bytes::<PeersResponse>(&bytes).context("invalid tracker response")?;
+        Ok(peers_resp.peers)
+    }
 
-        Ok(())
+    /// announce and handshake with the first peer returned by the tracker
+    pub async fn handshake(&mut self) -> Result<Handshake> {
+        let peers = self.get_peers().await?;
+        let peer = peers.first().context("tracker returned no peers")?;
+        let peer = peer
+            .socket_addr()
+            .with_context(|| format!("invalid peer address {}", peer))?;
+        println!("connecting to peer {:?}", peers);
+        self.handshake_with(peer).await
     }
-}
 
-pub struct MessageCodec;
+    /// connect to `peer` (ipv4 or ipv6) and exchange handshakes, without asking the tracker
+    /// the connect timeout covers the whole exchange
+    pub async fn handshake_with(&mut self, peer: SocketAddr) -> Result<Handshake> {
+        self.bind_utp().await?;
+        let info_hash = self.torrent.info_hash();
+        let mut handshake = Handshake::new(info_hash, self.session.peer_id);
+        handshake.reserved[5] |= extension::RESERVED_BIT;
+        let exchange = async {
+            let mut conn = peer::connect_encrypted(peer, &self.engine.connect, &info_hash).await?;
+            println!("handshake start");
+            peer::handshake(&mut conn, &handshake).await
+        };
+        tokio::time::timeout(self.engine.connect.timeout, exchange)
+            .await
+            .with_context(|| format!("handshake with {} timed out", peer))?
+    }
 
-impl Encoder<ExchangeMsg> for MessageCodec {
-    type Error = anyhow::Error;
+    /// tracker peers as socket addresses, none for trackerless torrents
+    async fn peer_addrs(&self) -> Result<Vec<SocketAddr>> {
+        if self.torrent.announce.is_empty() {
+            return Ok(Vec::new());
+        }
+        let peers = self.get_peers().await?;
+        println!("connecting to peers {:?}", peers);
+        Ok(peers.iter().filter_map(Peer::socket_addr).collect())
+    }
 
-    fn encode(&mut self, item: ExchangeMsg, dst: &mut BytesMut) -> Result<()> {
-        let len_bytes = u32::to_be_bytes((item.payload.len() + 1) as u32);
-        dst.reserve(4 + 1 + item.payload.len());
-        dst.extend_from_slice(&len_bytes);
-        dst.put_u8(item.message_id.unwrap() as u8);
-        dst.extend_from_slice(&item.payload);
-        Ok(())
+    /// download a single piece into `output_file`
+    pub async fn download_pieces(&mut self, output_file: &str, piece_idx: usize) -> Result<()> {
+        anyhow::ensure!(
+            piece_idx < self.torrent.piece_count(),
+            "torrent has only {} pieces",
+            self.torrent.piece_count()
+        );
+        let peers = self.peer_addrs().await?;
+        self.bind_utp().await?;
+        let store = SinglePieceFile {
+            path: PathBuf::from(output_file),
+            index: piece_idx,
+        };
+        Engine::new(
+            self.torrent.clone(),
+            self.session.clone(),
+            self.engine.clone(),
+            Box::new(store),
+        )
+        .with_wanted(&[piece_idx])
+        .run(peers)
+        .await
     }
-}
 
-impl Decoder for MessageCodec {
-    type Item = ExchangeMsg;
-    type Error = anyhow::Error;
+    /// download the whole torrent: a single file to `output`, multiple files below directory
+    /// `output`
+    pub async fn download(&mut self, output: &str) -> Result<()> {
+        let peers = self.peer_addrs().await?;
+        self.bind_utp().await?;
+        let layout = FileLayout::new(&self.torrent, Path::new(output))?;
+        let storage = Storage::open(layout.clone())?;
+        let engine = Engine::new(
+            self.torrent.clone(),
+            self.session.clone(),
+            self.engine.clone(),
+            Box::new(storage),
+        )
+        .with_file_priorities(&layout, &self.file_priorities);
+        // before any connection, so all of them advertise the dht node
+        let discovery = self.discover(&engine).await?;
+        let listener = match &self.listen {
+            Some(config) => Some(self.listen(config.clone(), &engine).await?),
+            None => None,
+        };
+        let lsd = self.local_discovery(&engine).await;
+        let result = engine.run(peers).await;
+        if let Some(listener) = listener {
+            listener.abort();
+        }
+        if let Some(lsd) = lsd {
+            lsd.abort();
+        }
+        if let Some((dht, discovery)) = discovery {
+            discovery.abort();
+            self.save_dht(&dht);
+        }
+        result
+    }
 
-    fn decode(&mut self, src: &mut BytesMut) -> anyhow::Result<Option<Self::Item>> {
-        if src.len() < 4 {
+    /// serve the verified pieces found at `input` to incoming peers until stopped
+    pub async fn seed(&mut self, input: &str) -> Result<()> {
+        let layout = FileLayout::new(&self.torrent, Path::new(input))?;
+        self.bind_utp().await?;
+        let engine = Engine::new(
+            self.torrent.clone(),
+            self.session.clone(),
+            self.engine.clone(),
+            Box::new(Storage::open_read_only(layout)?),
+        )
+        // the files are read-only, damaged pieces can't be downloaded again
+        .with_wanted(&[]);
+        let found = engine.recheck()?;
+        println!("{} of {} pieces present", found, self.torrent.piece_count());
+        let config = self.listen.clone().unwrap_or_default();
+        let _discovery = self.discover(&engine).await?;
+        let listener = self.listen(config, &engine).await?;
+        let _lsd = self.local_discovery(&engine).await;
+        if !self.torrent.announce.is_empty() {
+            // announce so the tracker hands out our address
+            self.get_peers().await?;
+        }
+        listener.await?
+    }
+
+    /// start a DHT node when configured, it keeps announcing the torrent and adding the
+    /// peers it finds to `engine`, whose peers exchange nodes with it
+    async fn discover(&self, engine: &Engine) -> Result<Option<(Dht, JoinHandle<()>)>> {
+        let config = match &self.dht {
+            Some(config) if !self.torrent.is_private() => config.clone(),
+            _ => return Ok(None),
+        };
+        if !self.direct_allowed() {
+            println!("dht: off, the proxy forbids direct traffic");
             return Ok(None);
         }
+        let dht = Dht::start(config).await?;
+        println!("dht node listening on {}", dht.local_addr()?);
+        engine.set_dht(dht.clone());
+        let source = engine.peer_source();
+        let info_hash = engine.info_hash();
+        let port = self.session.port;
+        let node = dht.clone();
+        let discovery = tokio::spawn(async move {
+            match node.bootstrap().await {
+                Ok(n) => println!("dht: bootstrapped with {} nodes", n),
+                Err(e) => println!("dht: {:#}", e),
+            }
+            loop {
+                let peers = node.announce(info_hash, port).await;
+                let delay = if peers.is_empty() {
+                    DHT_RETRY
+                } else {
+                    DHT_REANNOUNCE
+                };
+                source.add(peers);
+                tokio::time::sleep(delay).await;
+            }
+        });
+        Ok(Some((dht, discovery)))
+    }
+
+    // udp can't go through the proxy, so the dht and lsd only run where traffic may
+    // bypass it
+    fn direct_allowed(&self) -> bool {
+        self.proxy.as_ref().is_none_or(|p| p.allow_direct_fallback)
+    }
 
-        let mut len_bytes = [0u8; 4];
-        len_bytes.copy_from_slice(&src[..4]);
-
-        // len is the total bytes of messageId + Payload
-        let len: usize = u32::from_be_bytes(len_bytes) as usize;
-        println!("expect msg len: {:?}", len);
-        if len == 0 {
-            // discard heartbeat msg
-            src.advance(4);
-            // and then try again in case the buffer has more messages
-            return self.decode(src);
+    /// announce the torrent on the LAN and add the peers found there, unless disabled.
+    /// LSD is optional, when it can't start we go on without it.
+    async fn local_discovery(&self, engine: &Engine) -> Option<JoinHandle<()>> {
+        if !self.lsd || self.torrent.is_private() {
+            return None;
         }
-        // println!("Src len: {:?}", src.len());
-        if src.len() < 5 {
-            // Not enough data to read tag marker.
-            return Ok(None);
+        if !self.direct_allowed() {
+            println!("lsd: off, the proxy forbids direct traffic");
+            return None;
         }
+        let lsd = match Lsd::bind(self.session.port).await {
+            Ok(lsd) => lsd,
+            Err(e) => {
+                println!("lsd: {:#}", e);
+                return None;
+            }
+        };
+        let torrents = TorrentTable::new();
+        torrents.insert(engine.clone());
+        // without a tracker the LAN may be the only source of peers, keep waiting for them
+        let source = self.torrent.announce.is_empty().then(|| engine.peer_source());
+        Some(tokio::spawn(async move {
+            let _source = source;
+            if let Err(e) = lsd.run(torrents).await {
+                println!("lsd: {:#}", e);
+            }
+        }))
+    }
 
-        // Check that the length is not too large to avoid a denial of
-        // service attack where the server runs out of memory.
-        if len > MAX {
-            println!("Decode msg: Frame of length {:?} is too large.", len);
-            // return Err(std::io::Error::new(
-            //     std::io::ErrorKind::InvalidData,
-            //     format!("Frame of length {} is too large.", len),
-            // ));
-            return Ok(None); // TODO
+    /// bind the uTP socket unless peer connections are tcp only, on the session port
+    /// when that is free
+    async fn bind_utp(&mut self) -> Result<()> {
+        if self.engine.connect.transport == TransportPreference::TcpOnly
+            || self.engine.connect.utp.is_some()
+        {
+            return Ok(());
         }
+        // the dht node may want the same udp port
+        let port = match &self.dht {
+            Some(dht) if dht.bind.port() == self.session.port => 0,
+            _ => self.session.port,
+        };
+        let addr = SocketAddr::from(([0, 0, 0, 0], port));
+        let socket = match UtpSocket::bind(addr, self.utp).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                println!("{:#}, using another port", e);
+                UtpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0)), self.utp).await?
+            }
+        };
+        println!("utp socket on {}", socket.local_addr()?);
+        self.engine.connect.utp = Some(socket);
+        Ok(())
+    }
 
-        // if not all data arrived, we need to wait
-        if src.len() < 4 + len {
-            // The full string has not yet arrived.
-            //
-            // We reserve more space in the buffer. This is not strictly
-            // necessary, but is a good idea performance-wise.
-            src.reserve(4 + len - src.len());
-            // We inform the Framed that we need more bytes to form the next
-            // frame.
-            return Ok(None);
+    fn save_dht(&self, dht: &Dht) {
+        if let Some(path) = self.dht.as_ref().and_then(|c| c.state_file.as_ref()) {
+            if let Err(e) = dht.save(path) {
+                println!("dht: {:#}", e);
+            }
         }
+    }
 
-        let msg_type = src[4].try_into()?;
-        // src.advance(1);
-        let data = if src.len() > 5 {
-            src[5..len + 4].to_vec()
-        } else {
-            Vec::new()
+    async fn listen(
+        &self,
+        config: ListenerConfig,
+        engine: &Engine,
+    ) -> Result<JoinHandle<Result<()>>> {
+        let torrents = TorrentTable::new();
+        torrents.insert(engine.clone());
+        let addr = SocketAddr::from(([0, 0, 0, 0], self.session.port));
+        let config = ListenerConfig {
+            encryption: self.engine.connect.encryption,
+            ..config
         };
-        let actual_len = &data.len();
-        let msg = ExchangeMsg::new(msg_type, data);
-        src.advance(4 + len);
-        println!("receive msg len: {:?}", actual_len);
-        Ok(Some(msg))
+        let mut listener = Listener::bind(addr, config, torrents).await?;
+        if let Some(utp) = &self.engine.connect.utp {
+            listener = listener.with_acceptor(utp.clone());
+        }
+        println!("listening for peers on {}", listener.local_addr()?);
+        Ok(tokio::spawn(listener.serve()))
     }
 }
 
-// pub async fn read_from<R>(reader: &mut R) -> Result<Self>
-// where
-//     R: AsyncRead + Unpin,
-// {
-//     let len = reader.read_u32().await?;
-//     println!("resp len: {}", len);
-//     if len > 0 {
-//         return match reader.read_u8().await?.try_into()? {
-//             MsgType::BitField => {
-//                 let mut buf = Vec::new();
-//                 reader.read_to_end(&mut buf).await?;
-//                 println!("message payload: {:?}", buf);
-//                 Ok(ExchangeMsg {
-//                     len_prefix: len,
-//                     message_id: Some(MsgType::BitField),
-//                     payload: buf,
-//                 })
-//             }
-//             _ => Err(anyhow!("")),
-//         };
-//     }
-//
-//     Err(anyhow!(""))
-// }
+fn url_encode(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&b| match b {
+            b'0'..=b'9' | b'A'..=b'Z' | b'a'..=b'z' | b'-' | b'.' | b'_' | b'~' => {
+                String::from(b as char)
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
@@ -0,0 +1,1223 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use futures_util::{SinkExt, StreamExt};
+use sha1::Digest;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{broadcast, mpsc, watch, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+use tokio_util::codec::Framed;
+
+use crate::dht;
+use crate::dht::Dht;
+use crate::torrent::bitfield::BitField;
+use crate::torrent::choker::{ChokeCandidate, Choker, ChokerConfig};
+use crate::torrent::exchange::{BlockReqPayload, MessageCodec, PeerMessage, BLOCK_MAX};
+use crate::torrent::extension;
+use crate::torrent::extension::ExtendedHandshake;
+use crate::torrent::fast;
+use crate::torrent::mse::MseStream;
+use crate::torrent::handeshake::Handshake;
+use crate::torrent::peer;
+use crate::torrent::peer::{ConnectOptions, PeerState};
+use crate::torrent::pex;
+use crate::torrent::pex::{PexMessage, PexState};
+use crate::torrent::picker::{BlockOutcome, PickMode, PiecePicker, Priority};
+use crate::torrent::pipeline::{PipelineConfig, RequestQueue};
+use crate::torrent::rate::RateMeter;
+use crate::torrent::session::Session;
+use crate::torrent::stats::Stats;
+use crate::torrent::storage::{FileLayout, PieceStore};
+use crate::torrent::torrent::Torrent;
+use crate::torrent::transport::PeerStream;
+use crate::torrent::webseed::WebSeed;
+
+pub const CLIENT_VERSION: &str = concat!("RB ", env!("CARGO_PKG_VERSION"));
+// peers learned while running that wait for a connection, more are dropped
+const NEW_PEERS_QUEUE: usize = 512;
+// a web seed waits this long when every missing block is requested from peers already
+const WEB_SEED_IDLE: Duration = Duration::from_secs(1);
+// and this long after a failed request, giving up after `WEB_SEED_MAX_FAILURES` in a row
+const WEB_SEED_RETRY: Duration = Duration::from_secs(10);
+const WEB_SEED_MAX_FAILURES: u32 = 5;
+
+#[derive(Debug, Clone)]
+pub struct EngineConfig {
+    /// peers connected at the same time
+    pub max_peers: usize,
+    pub pipeline: PipelineConfig,
+    pub connect: ConnectOptions,
+    pub pick_mode: PickMode,
+    /// drop peers that keep us choked this long while we are interested
+    pub unchoke_timeout: Duration,
+    /// time a peer we connected to gets to answer our handshake
+    pub handshake_timeout: Duration,
+    /// drop peers that send nothing for this long
+    pub idle_timeout: Duration,
+    /// send a keep-alive this often
+    pub keep_alive: Duration,
+    pub choker: ChokerConfig,
+    /// download from the torrent's web seeds too
+    pub web_seeds: bool,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            max_peers: 30,
+            pipeline: PipelineConfig::default(),
+            connect: ConnectOptions::default(),
+            pick_mode: PickMode::default(),
+            unchoke_timeout: peer::UNCHOKE_TIMEOUT,
+            handshake_timeout: peer::HANDSHAKE_TIMEOUT,
+            idle_timeout: peer::IDLE_TIMEOUT,
+            keep_alive: peer::KEEP_ALIVE_INTERVAL,
+            choker: ChokerConfig::default(),
+            web_seeds: true,
+        }
+    }
+}
+
+/// Downloads a torrent from many peers at once, sharing one piece picker between them.
+/// Clones share the same torrent state.
+#[derive(Clone)]
+pub struct Engine {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    torrent: Torrent,
+    info_hash: [u8; 20],
+    session: Session,
+    config: EngineConfig,
+    picker: Mutex<PiecePicker>,
+    store: Box<dyn PieceStore>,
+    stats: Stats,
+    events: broadcast::Sender<PeerEvent>,
+    // connections of this torrent, outgoing and incoming
+    slots: Arc<Semaphore>,
+    // what the choker and peer exchange know about each connection, by connection id
+    peers: Mutex<HashMap<u64, PeerEntry>>,
+    // peers learned while running, e.g. through peer exchange
+    new_peers: mpsc::Sender<SocketAddr>,
+    new_peers_rx: Mutex<Option<mpsc::Receiver<SocketAddr>>>,
+    // live `PeerSource`s, `run` waits for their peers while no connection is left
+    sources: watch::Sender<usize>,
+    next_conn_id: AtomicU64,
+    // wakes the choker before its next round, e.g. when a peer's interest changes
+    rechoke: Arc<Notify>,
+    choker_started: AtomicBool,
+    // our DHT node, peers learn its port and we add theirs
+    dht: Mutex<Option<Dht>>,
+    // flips to true once every wanted piece is verified
+    done: watch::Sender<bool>,
+}
+
+impl Engine {
+    pub fn new(
+        torrent: Torrent,
+        session: Session,
+        config: EngineConfig,
+        store: Box<dyn PieceStore>,
+    ) -> Self {
+        let mut picker = PiecePicker::new(&torrent);
+        picker.set_mode(config.pick_mode);
+        let (done, _) = watch::channel(false);
+        let (events, _) = broadcast::channel(256);
+        let (new_peers, new_peers_rx) = mpsc::channel(NEW_PEERS_QUEUE);
+        Self {
+            shared: Arc::new(Shared {
+                info_hash: torrent.info_hash(),
+                torrent,
+                session,
+                picker: Mutex::new(picker),
+                store,
+                stats: Stats::new(),
+                events,
+                slots: Arc::new(Semaphore::new(config.max_peers.max(1))),
+                peers: Mutex::new(HashMap::new()),
+                new_peers,
+                new_peers_rx: Mutex::new(Some(new_peers_rx)),
+                sources: watch::channel(0).0,
+                next_conn_id: AtomicU64::new(0),
+                rechoke: Arc::new(Notify::new()),
+                choker_started: AtomicBool::new(false),
+                dht: Mutex::new(None),
+                config,
+                done,
+            }),
+        }
+    }
+
+    /// download only these pieces instead of the whole torrent
+    pub fn with_wanted(self, pieces: &[usize]) -> Self {
+        self.shared.picker.lock().unwrap().set_wanted(pieces);
+        self
+    }
+
+    /// per file priorities in torrent file order, 0 skips a file
+    pub fn with_file_priorities(self, layout: &FileLayout, priorities: &[Priority]) -> Self {
+        self.shared
+            .picker
+            .lock()
+            .unwrap()
+            .set_file_priorities(layout, priorities);
+        self
+    }
+
+    pub fn set_piece_priority(&self, piece: usize, priority: Priority) {
+        self.shared
+            .picker
+            .lock()
+            .unwrap()
+            .set_piece_priority(piece, priority);
+    }
+
+    /// advertise `dht` to peers with the `Port` message and add the nodes of peers, for
+    /// connections made from now on
+    pub fn set_dht(&self, dht: Dht) {
+        *self.shared.dht.lock().unwrap() = Some(dht);
+    }
+
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.shared.info_hash
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.shared.torrent.is_private()
+    }
+
+    /// hash the pieces already in the store and mark the good ones as ours, returns their
+    /// number
+    pub fn recheck(&self) -> Result<usize> {
+        let mut found = 0;
+        for index in 0..self.shared.torrent.piece_count() {
+            let size = self.shared.torrent.piece_size(index);
+            let Ok(data) = self.shared.store.read_block(index, 0, size) else {
+                continue;
+            };
+            if self.shared.hash_matches(index, &data) {
+                self.shared.picker.lock().unwrap().piece_verified(index);
+                found += 1;
+            }
+        }
+        if self.is_complete() {
+            self.shared.done.send_replace(true);
+        }
+        Ok(found)
+    }
+
+    /// take over an incoming connection whose handshake `theirs` was read already, fails
+    /// when the torrent has no free connection slot or the peer is ourselves
+    pub async fn accept(
+        &self,
+        conn: MseStream<PeerStream>,
+        addr: SocketAddr,
+        theirs: Handshake,
+    ) -> Result<()> {
+        ensure!(
+            theirs.peer_id != self.shared.session.peer_id,
+            "connection to ourselves"
+        );
+        let _permit = self
+            .shared
+            .slots
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| anyhow!("torrent has {} peers already", self.shared.config.max_peers))?;
+        self.shared.clone().accept_peer(conn, addr, theirs).await
+    }
+
+    /// connect to more peers while running, known ones are skipped. Dropped when too
+    /// many are waiting already.
+    pub fn add_peers(&self, peers: impl IntoIterator<Item = SocketAddr>) {
+        self.shared.add_peers(peers);
+    }
+
+    /// a handle to add peers through, e.g. from the DHT. While one is alive `run` keeps
+    /// waiting for peers instead of giving up when no connection is left.
+    pub fn peer_source(&self) -> PeerSource {
+        self.shared.sources.send_modify(|n| *n += 1);
+        PeerSource {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// connect to `peers` and those added while running, at most `max_peers` at a time,
+    /// until every wanted piece is stored
+    pub async fn run(&self, peers: Vec<SocketAddr>) -> Result<()> {
+        if self.shared.picker.lock().unwrap().is_complete() {
+            return Ok(());
+        }
+        let mut done = self.shared.done.subscribe();
+        let mut sources = self.shared.sources.subscribe();
+        let mut new_peers = self.shared.new_peers_rx.lock().unwrap().take();
+        let mut known = HashSet::new();
+        // peers waiting for a connection slot, a task is only spawned once one is free
+        let mut pending: VecDeque<SocketAddr> =
+            peers.into_iter().filter(|a| known.insert(*a)).collect();
+        let mut tasks = JoinSet::new();
+        for seed in self.shared.web_seeds() {
+            tasks.spawn(self.shared.clone().run_web_seed(seed));
+        }
+
+        loop {
+            tokio::select! {
+                biased;
+                _ = done.wait_for(|d| *d) => break,
+                permit = self.shared.slots.clone().acquire_owned(), if !pending.is_empty() => {
+                    let addr = pending.pop_front().expect("checked above");
+                    tasks.spawn(self.shared.clone().connect_peer(addr, permit?));
+                }
+                Some(addr) = recv(&mut new_peers), if pending.len() < NEW_PEERS_QUEUE => {
+                    if known.insert(addr) {
+                        pending.push_back(addr);
+                    }
+                }
+                res = tasks.join_next(), if !tasks.is_empty() => match res {
+                    None => break,
+                    Some(Ok(Err(e))) => println!("{:#}", e),
+                    Some(Err(e)) => println!("peer task failed: {}", e),
+                    Some(Ok(Ok(()))) => {}
+                },
+                _ = sources.wait_for(|n| *n == 0), if tasks.is_empty() && pending.is_empty() => break,
+            }
+        }
+        tasks.shutdown().await;
+        *self.shared.new_peers_rx.lock().unwrap() = new_peers;
+        println!(
+            "downloaded {} bytes, {} wasted, uploaded {} bytes",
+            self.shared.stats.downloaded(),
+            self.shared.stats.wasted(),
+            self.shared.stats.uploaded()
+        );
+
+        let picker = self.shared.picker.lock().unwrap();
+        if picker.is_complete() {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "no peers left, {} pieces missing",
+                picker.remaining()
+            ))
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.shared.picker.lock().unwrap().is_complete()
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.shared.stats
+    }
+}
+
+/// Adds peers to a running engine, see `Engine::peer_source`.
+pub struct PeerSource {
+    shared: Arc<Shared>,
+}
+
+impl PeerSource {
+    /// connect to more peers, like `Engine::add_peers`
+    pub fn add(&self, peers: impl IntoIterator<Item = SocketAddr>) {
+        self.shared.add_peers(peers);
+    }
+}
+
+impl Drop for PeerSource {
+    fn drop(&mut self) {
+        self.shared.sources.send_modify(|n| *n -= 1);
+    }
+}
+
+/// Sent to every connection of the torrent.
+#[derive(Debug, Clone, Copy)]
+enum PeerEvent {
+    /// a block requested from several peers in endgame arrived
+    Cancel(BlockReqPayload),
+    /// we verified a piece
+    Have(u32),
+}
+
+/// A connection as registered with the choker and peer exchange.
+struct PeerEntry {
+    candidate: ChokeCandidate,
+    unchoke: watch::Sender<bool>,
+    /// listen address and ut_pex flags, once known
+    pex: Option<(SocketAddr, u8)>,
+}
+
+/// State of one connection in the engine.
+struct PeerConn {
+    id: u64,
+    addr: SocketAddr,
+    outgoing: bool,
+    framed: Framed<MseStream<PeerStream>, MessageCodec>,
+    has: BitField,
+    requests: RequestQueue,
+    state: PeerState,
+    /// blocks the peer requested from us, in order
+    uploads: VecDeque<BlockReqPayload>,
+    upload_rate: RateMeter,
+    /// the choker's decision
+    unchoke: watch::Receiver<bool>,
+    /// both sides support the fast extension
+    fast: bool,
+    /// pieces we may request while choked
+    allowed_in: BitField,
+    /// pieces the peer may request while choked
+    allowed_out: Vec<u32>,
+    pex: PexState,
+}
+
+impl Shared {
+    fn handshake(&self) -> Handshake {
+        let mut ours = Handshake::new(self.info_hash, self.session.peer_id);
+        ours.reserved[5] |= extension::RESERVED_BIT;
+        ours.reserved[7] |= fast::RESERVED_BIT;
+        if self.dht.lock().unwrap().is_some() {
+            ours.reserved[7] |= dht::RESERVED_BIT;
+        }
+        ours
+    }
+
+    // waits for a free connection slot, then runs the peer
+    // `_permit` is the connection slot, held until the connection ends
+    async fn connect_peer(
+        self: Arc<Self>,
+        addr: SocketAddr,
+        _permit: OwnedSemaphorePermit,
+    ) -> Result<()> {
+        if *self.done.borrow() {
+            return Ok(());
+        }
+        self.run_peer(addr)
+            .await
+            .with_context(|| format!("peer {}", addr))
+    }
+
+    async fn run_peer(self: Arc<Self>, addr: SocketAddr) -> Result<()> {
+        let mut conn = peer::connect_encrypted(addr, &self.config.connect, &self.info_hash).await?;
+        let theirs = tokio::time::timeout(
+            self.config.handshake_timeout,
+            peer::handshake(&mut conn, &self.handshake()),
+        )
+        .await
+        .context("handshake timed out")??;
+        ensure!(
+            theirs.peer_id != self.session.peer_id,
+            "connection to ourselves"
+        );
+        self.run_conn(conn, addr, true, theirs).await
+    }
+
+    async fn accept_peer(
+        self: Arc<Self>,
+        mut conn: MseStream<PeerStream>,
+        addr: SocketAddr,
+        theirs: Handshake,
+    ) -> Result<()> {
+        conn.write_all(&self.handshake().to_bytes())
+            .await
+            .context("write handshake")?;
+        self.run_conn(conn, addr, false, theirs).await
+    }
+
+    async fn run_conn(
+        self: Arc<Self>,
+        conn: MseStream<PeerStream>,
+        addr: SocketAddr,
+        outgoing: bool,
+        theirs: Handshake,
+    ) -> Result<()> {
+        let piece_count = self.torrent.piece_count();
+        // we can only tell others how to reach peers we connected to, for incoming
+        // connections we learn the listen port from the extension handshake
+        let pex_addr = outgoing.then_some((addr, pex::FLAG_REACHABLE));
+        let (id, unchoke) = self.register_peer(pex_addr);
+        let mut peer = PeerConn {
+            id,
+            addr,
+            outgoing,
+            framed: Framed::new(conn, MessageCodec::for_torrent(BLOCK_MAX, piece_count)),
+            has: BitField::new(piece_count),
+            requests: RequestQueue::new(self.config.pipeline),
+            state: PeerState::new(),
+            uploads: VecDeque::new(),
+            upload_rate: RateMeter::new(),
+            unchoke,
+            fast: fast::supports_fast(&theirs.reserved),
+            allowed_in: BitField::new(piece_count),
+            allowed_out: Vec::new(),
+            pex: PexState::new(),
+        };
+        if extension::supports_extensions(&theirs.reserved) {
+            let mut m = BTreeMap::new();
+            // private torrents get peers from their tracker only
+            if !self.torrent.is_private() {
+                m.insert(pex::EXTENSION_NAME.to_string(), pex::LOCAL_ID as i64);
+            }
+            let handshake = ExtendedHandshake {
+                m,
+                p: Some(self.session.port),
+                v: Some(CLIENT_VERSION.to_string()),
+                reqq: Some(self.config.pipeline.max_depth),
+                ..Default::default()
+            };
+            peer.framed
+                .send(PeerMessage::Extended {
+                    id: extension::HANDSHAKE_ID,
+                    payload: handshake.to_bytes().into(),
+                })
+                .await?;
+        }
+        if dht::supports_dht(&theirs.reserved) {
+            if let Some(port) = self.dht_port() {
+                peer.framed.send(PeerMessage::Port(port)).await?;
+            }
+        }
+        let have = self.picker.lock().unwrap().have().clone();
+        if peer.fast && have.is_complete() {
+            peer.framed.send(PeerMessage::HaveAll).await?;
+        } else if peer.fast && have.count() == 0 {
+            peer.framed.send(PeerMessage::HaveNone).await?;
+        } else if have.count() > 0 {
+            peer.framed.send(PeerMessage::Bitfield(have.clone())).await?;
+        }
+        if peer.fast {
+            // let the peer start on a few pieces before the choker gets to it
+            peer.allowed_out = fast::allowed_fast_set(
+                &self.info_hash,
+                addr.ip(),
+                piece_count,
+                fast::ALLOWED_FAST_COUNT,
+            );
+            peer.allowed_out.retain(|i| have.get(*i as usize));
+            for index in &peer.allowed_out {
+                peer.framed
+                    .feed(PeerMessage::AllowedFast { index: *index })
+                    .await?;
+            }
+            peer.framed.flush().await?;
+        }
+
+        let result = self.exchange(&mut peer).await;
+
+        self.peers.lock().unwrap().remove(&peer.id);
+        self.rechoke.notify_one();
+        // hand the unanswered requests to other peers and forget what this one had
+        let mut picker = self.picker.lock().unwrap();
+        picker.abort(&peer.requests.drain());
+        picker.availability_mut().remove_bitfield(&peer.has);
+        result
+    }
+
+    async fn exchange(&self, peer: &mut PeerConn) -> Result<()> {
+        let mut done = self.done.subscribe();
+        let mut events = self.events.subscribe();
+        let mut keep_alive = tokio::time::interval_at(
+            tokio::time::Instant::now() + self.config.keep_alive,
+            self.config.keep_alive,
+        );
+        let mut last_heard = Instant::now();
+        loop {
+            // once complete we only stay for peers that download from us
+            if *done.borrow_and_update() && peer.has.is_complete() {
+                return Ok(());
+            }
+            let (wants, can_upload) = {
+                let picker = self.picker.lock().unwrap();
+                (picker.is_interesting(&peer.has), picker.have().count() > 0)
+            };
+            self.update_peer_entry(peer);
+            let unchoke = *peer.unchoke.borrow_and_update() && can_upload;
+            if peer.state.set_choking(!unchoke) {
+                let msg = if peer.state.am_choking {
+                    self.drop_uploads(peer).await?;
+                    PeerMessage::Choke
+                } else {
+                    PeerMessage::Unchoke
+                };
+                peer.framed.send(msg).await?;
+            }
+            if peer.state.set_interested(wants) {
+                let msg = if wants {
+                    PeerMessage::Interested
+                } else {
+                    PeerMessage::NotInterested
+                };
+                peer.framed.send(msg).await?;
+            }
+            let allowed_fast = peer.state.am_interested && peer.allowed_in.count() > 0;
+            if peer.state.can_request() || allowed_fast {
+                self.fill_requests(peer).await?;
+            }
+
+            let deadline = peer.state.unchoke_deadline(self.config.unchoke_timeout);
+            let msg = tokio::select! {
+                _ = done.changed() => continue,
+                _ = sleep_until(peer.pex.next_send()) => {
+                    self.send_pex(peer).await?;
+                    continue;
+                }
+                res = peer.unchoke.changed() => {
+                    res.context("choker stopped")?;
+                    continue;
+                }
+                _ = sleep_until(deadline) => {
+                    bail!("choked us for {:?}", self.config.unchoke_timeout)
+                }
+                _ = sleep_until(Some(last_heard + self.config.idle_timeout)) => {
+                    bail!("silent for {:?}", self.config.idle_timeout)
+                }
+                _ = keep_alive.tick() => {
+                    peer.framed.send(PeerMessage::KeepAlive).await?;
+                    continue;
+                }
+                event = events.recv() => {
+                    match event {
+                        Ok(PeerEvent::Cancel(req)) if peer.requests.cancel(&req) => {
+                            peer.framed
+                                .send(PeerMessage::Cancel {
+                                    index: req.index,
+                                    begin: req.begin,
+                                    length: req.length,
+                                })
+                                .await?;
+                        }
+                        Ok(PeerEvent::Have(index)) if !peer.has.get(index as usize) => {
+                            peer.framed.send(PeerMessage::Have { index }).await?;
+                        }
+                        // a missed cancel only costs a duplicate block, a missed have a
+                        // request the peer can't make
+                        Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                        Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                    }
+                    continue;
+                }
+                _ = std::future::ready(()), if !peer.uploads.is_empty() => {
+                    self.serve_block(peer).await?;
+                    continue;
+                }
+                msg = peer.framed.next() => msg.context("peer closed the connection")??,
+            };
+            last_heard = Instant::now();
+            self.on_message(peer, msg).await?;
+        }
+    }
+
+    async fn fill_requests(&self, peer: &mut PeerConn) -> Result<()> {
+        let room = peer.requests.capacity().saturating_sub(peer.requests.len());
+        if room == 0 {
+            return Ok(());
+        }
+        // while choked only allowed fast pieces can be requested
+        let has = if peer.state.peer_choking {
+            peer.has.intersection(&peer.allowed_in)
+        } else {
+            peer.has.clone()
+        };
+        let reqs = self
+            .picker
+            .lock()
+            .unwrap()
+            .pick(&has, peer.requests.outstanding(), room);
+        for req in reqs {
+            peer.framed
+                .feed(PeerMessage::Request {
+                    index: req.index,
+                    begin: req.begin,
+                    length: req.length,
+                })
+                .await?;
+            peer.requests.push(req);
+        }
+        peer.framed.flush().await
+    }
+
+    // choking drops the peer's pending requests, with the fast extension explicitly and
+    // except for allowed fast pieces
+    async fn drop_uploads(&self, peer: &mut PeerConn) -> Result<()> {
+        if !peer.fast {
+            peer.uploads.clear();
+            return Ok(());
+        }
+        let (kept, rejected): (VecDeque<_>, VecDeque<_>) = std::mem::take(&mut peer.uploads)
+            .into_iter()
+            .partition(|r| peer.allowed_out.contains(&r.index));
+        peer.uploads = kept;
+        for req in rejected {
+            peer.framed.feed(reject(&req)).await?;
+        }
+        Ok(())
+    }
+
+    async fn on_message(&self, peer: &mut PeerConn, msg: PeerMessage) -> Result<()> {
+        match msg {
+            PeerMessage::KeepAlive => {}
+            PeerMessage::Choke => {
+                // the peer drops our queued requests, someone else may serve them. Fast
+                // peers keep serving allowed fast pieces and reject the rest explicitly,
+                // requests aborted here early are still accepted if they arrive.
+                peer.state.on_choke();
+                let allowed = &peer.allowed_in;
+                let dropped = if peer.fast {
+                    peer.requests.drain_except(|r| allowed.get(r.index as usize))
+                } else {
+                    peer.requests.drain()
+                };
+                self.picker.lock().unwrap().abort(&dropped);
+            }
+            PeerMessage::Unchoke => peer.state.on_unchoke(),
+            PeerMessage::Interested => peer.state.on_interested(true),
+            PeerMessage::NotInterested => peer.state.on_interested(false),
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } => {
+                let req = BlockReqPayload::new(index, begin, length);
+                let accept = match self.check_request(&req) {
+                    Err(e) => {
+                        println!("reject request {:?}: {}", req, e);
+                        false
+                    }
+                    Ok(()) => {
+                        (!peer.state.am_choking || peer.allowed_out.contains(&index))
+                            && peer.uploads.len() < self.config.pipeline.max_depth
+                    }
+                };
+                if accept {
+                    if !peer.uploads.contains(&req) {
+                        peer.uploads.push_back(req);
+                    }
+                } else if peer.fast {
+                    peer.framed.send(reject(&req)).await?;
+                }
+            }
+            PeerMessage::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                let req = BlockReqPayload::new(index, begin, length);
+                peer.uploads.retain(|r| *r != req);
+            }
+            PeerMessage::HaveAll | PeerMessage::HaveNone => {
+                ensure!(peer.fast, "{:?} without the fast extension", msg);
+                let piece_count = self.torrent.piece_count();
+                let bits = if msg == PeerMessage::HaveAll {
+                    BitField::full(piece_count)
+                } else {
+                    BitField::new(piece_count)
+                };
+                let mut picker = self.picker.lock().unwrap();
+                picker.availability_mut().remove_bitfield(&peer.has);
+                picker.availability_mut().add_bitfield(&bits);
+                peer.has = bits;
+            }
+            PeerMessage::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                ensure!(peer.fast, "reject without the fast extension");
+                let req = BlockReqPayload::new(index, begin, length);
+                if peer.requests.cancel(&req) {
+                    self.picker.lock().unwrap().abort(&[req]);
+                }
+            }
+            // from a peer without the fast extension it is ignored like an unknown message
+            PeerMessage::AllowedFast { index }
+                if peer.fast && (index as usize) < peer.allowed_in.len() =>
+            {
+                peer.allowed_in.set(index as usize);
+            }
+            PeerMessage::Bitfield(bits) => {
+                let bits = bits.validate(self.torrent.piece_count())?;
+                let mut picker = self.picker.lock().unwrap();
+                picker.availability_mut().remove_bitfield(&peer.has);
+                picker.availability_mut().add_bitfield(&bits);
+                peer.has = bits;
+            }
+            PeerMessage::Have { index } => {
+                let index = index as usize;
+                if index < peer.has.len() && !peer.has.get(index) {
+                    peer.has.set(index);
+                    self.picker
+                        .lock()
+                        .unwrap()
+                        .availability_mut()
+                        .add_have(index);
+                }
+            }
+            PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                // false for late answers to requests we aborted or cancelled, the block
+                // may still be missing
+                let requested = peer.requests.complete(index, begin, block.len());
+                self.on_block(index, begin, &block, requested)?;
+            }
+            PeerMessage::Extended {
+                id: extension::HANDSHAKE_ID,
+                payload,
+            } => {
+                let theirs = ExtendedHandshake::from_bytes(&payload)?;
+                if let Some(reqq) = theirs.reqq {
+                    peer.requests.set_peer_reqq(reqq);
+                }
+                if !self.torrent.is_private() {
+                    peer.pex.remote_id = theirs
+                        .m
+                        .get(pex::EXTENSION_NAME)
+                        .and_then(|id| u8::try_from(*id).ok())
+                        .filter(|id| *id != 0);
+                }
+                if let (false, Some(port)) = (peer.outgoing, theirs.p) {
+                    let listen = SocketAddr::new(peer.addr.ip(), port);
+                    if let Some(entry) = self.peers.lock().unwrap().get_mut(&peer.id) {
+                        entry.pex = Some((listen, 0));
+                    }
+                }
+            }
+            PeerMessage::Extended {
+                id: pex::LOCAL_ID,
+                payload,
+            } if !self.torrent.is_private() && peer.pex.accept() => {
+                let msg = PexMessage::from_bytes(&payload)?;
+                let added = msg.added().into_iter().take(pex::MAX_PEERS);
+                self.add_peers(added.map(|(addr, _)| addr));
+            }
+            PeerMessage::Port(port) if port != 0 => {
+                // the node is pinged first, it enters the routing table once it answers
+                if let Some(dht) = self.dht.lock().unwrap().clone() {
+                    let node = SocketAddr::new(peer.addr.ip(), port);
+                    tokio::spawn(async move { dht.add_node(node).await });
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn dht_port(&self) -> Option<u16> {
+        let dht = self.dht.lock().unwrap();
+        dht.as_ref()?.local_addr().ok().map(|addr| addr.port())
+    }
+
+    /// a request we can answer: at most one block of a piece we have
+    fn check_request(&self, req: &BlockReqPayload) -> Result<()> {
+        let index = req.index as usize;
+        ensure!(
+            req.length > 0 && req.length as usize <= BLOCK_MAX,
+            "block length {} exceeds {}",
+            req.length,
+            BLOCK_MAX
+        );
+        let picker = self.picker.lock().unwrap();
+        ensure!(
+            index < picker.have().len() && picker.have().get(index),
+            "we don't have piece {}",
+            index
+        );
+        ensure!(
+            req.begin as usize + req.length as usize <= picker.piece_size(index),
+            "block beyond the end of piece {}",
+            index
+        );
+        Ok(())
+    }
+
+    async fn serve_block(&self, peer: &mut PeerConn) -> Result<()> {
+        let Some(req) = peer.uploads.pop_front() else {
+            return Ok(());
+        };
+        let block = self.store.read_block(
+            req.index as usize,
+            req.begin as usize,
+            req.length as usize,
+        )?;
+        peer.framed
+            .send(PeerMessage::Piece {
+                index: req.index,
+                begin: req.begin,
+                block: block.into(),
+            })
+            .await?;
+        self.stats.add_uploaded(req.length as u64);
+        peer.upload_rate.add(req.length as usize);
+        Ok(())
+    }
+
+    fn register_peer(
+        self: &Arc<Self>,
+        pex: Option<(SocketAddr, u8)>,
+    ) -> (u64, watch::Receiver<bool>) {
+        if !self.choker_started.swap(true, Ordering::Relaxed) {
+            tokio::spawn(run_choker(
+                Arc::downgrade(self),
+                self.rechoke.clone(),
+                self.config.choker,
+            ));
+        }
+        let id = self.next_conn_id.fetch_add(1, Ordering::Relaxed);
+        let (unchoke, rx) = watch::channel(false);
+        let candidate = ChokeCandidate {
+            id,
+            interested: false,
+            download_rate: 0.0,
+            upload_rate: 0.0,
+            connected_at: Instant::now(),
+        };
+        self.peers
+            .lock()
+            .unwrap()
+            .insert(
+                id,
+                PeerEntry {
+                    candidate,
+                    unchoke,
+                    pex,
+                },
+            );
+        (id, rx)
+    }
+
+    // report the peer's interest and rates to the choker
+    fn update_peer_entry(&self, peer: &PeerConn) {
+        let mut peers = self.peers.lock().unwrap();
+        let Some(entry) = peers.get_mut(&peer.id) else {
+            return;
+        };
+        entry.candidate.download_rate = peer.requests.rate();
+        entry.candidate.upload_rate = peer.upload_rate.rate();
+        if let Some((_, flags)) = &mut entry.pex {
+            if peer.has.is_complete() {
+                *flags |= pex::FLAG_SEED;
+            }
+        }
+        if entry.candidate.interested != peer.state.peer_interested {
+            entry.candidate.interested = peer.state.peer_interested;
+            self.rechoke.notify_one();
+        }
+    }
+
+    fn add_peers(&self, peers: impl IntoIterator<Item = SocketAddr>) {
+        for addr in peers {
+            if self.new_peers.try_send(addr).is_err() {
+                break;
+            }
+        }
+    }
+
+    async fn send_pex(&self, peer: &mut PeerConn) -> Result<()> {
+        let current: HashMap<SocketAddr, u8> = self
+            .peers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(id, _)| **id != peer.id)
+            .filter_map(|(_, e)| e.pex)
+            .filter(|(addr, _)| *addr != peer.addr)
+            .collect();
+        let (Some(id), Some(msg)) = (peer.pex.remote_id, peer.pex.update(&current)) else {
+            return Ok(());
+        };
+        peer.framed
+            .send(PeerMessage::Extended {
+                id,
+                payload: msg.to_bytes().into(),
+            })
+            .await
+    }
+
+    // a full choker round, or only filling free slots between rounds
+    fn rechoke(&self, choker: &mut Choker, full_round: bool) {
+        let seeding = *self.done.borrow();
+        let peers = self.peers.lock().unwrap();
+        let candidates: Vec<ChokeCandidate> = peers.values().map(|p| p.candidate).collect();
+        let unchoked = if full_round {
+            choker.choose(&candidates, seeding, Instant::now())
+        } else {
+            choker.fill(&candidates, seeding, Instant::now())
+        };
+        for entry in peers.values() {
+            let unchoke = unchoked.contains(&entry.candidate.id);
+            entry.unchoke.send_if_modified(|u| std::mem::replace(u, unchoke) != unchoke);
+        }
+    }
+
+    // store a downloaded block, `requested` as for `PiecePicker::on_block`
+    fn on_block(&self, index: u32, begin: u32, block: &[u8], requested: bool) -> Result<()> {
+        self.stats.add_downloaded(block.len() as u64);
+        let outcome = self
+            .picker
+            .lock()
+            .unwrap()
+            .on_block(index, begin, block, requested)?;
+        match outcome {
+            BlockOutcome::Wasted => self.stats.add_wasted(block.len() as u64),
+            BlockOutcome::Stored { cancel, piece } => {
+                if cancel {
+                    let req = BlockReqPayload::new(index, begin, block.len() as u32);
+                    // no receivers only means no other peer is connected
+                    let _ = self.events.send(PeerEvent::Cancel(req));
+                }
+                if let Some(data) = piece {
+                    self.finish_piece(index as usize, &data)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // the torrent's web seeds, unless disabled
+    fn web_seeds(&self) -> Vec<WebSeed> {
+        if !self.config.web_seeds {
+            return Vec::new();
+        }
+        let proxy = self.config.connect.proxy.as_ref();
+        self.torrent
+            .url_list
+            .iter()
+            .filter_map(|url| match WebSeed::new(url, &self.torrent, proxy) {
+                Ok(seed) => Some(seed),
+                Err(e) => {
+                    println!("web seed {}: {:#}", url, e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    // download from a web seed like from a peer having every piece, a piece's worth of
+    // blocks at a time, until the torrent is complete or the seed keeps failing
+    async fn run_web_seed(self: Arc<Self>, seed: WebSeed) -> Result<()> {
+        let all = BitField::full(self.torrent.piece_count());
+        let max = (self.torrent.info.piece_length / BLOCK_MAX).max(1);
+        let mut failures = 0;
+        while !*self.done.borrow() {
+            let reqs = self.picker.lock().unwrap().pick(&all, &[], max);
+            if reqs.is_empty() {
+                tokio::time::sleep(WEB_SEED_IDLE).await;
+                continue;
+            }
+            match self.fetch_blocks(&seed, &reqs).await {
+                Ok(()) => failures = 0,
+                Err(e) => {
+                    self.picker.lock().unwrap().abort(&reqs);
+                    failures += 1;
+                    if failures >= WEB_SEED_MAX_FAILURES {
+                        return Err(e.context(format!("giving up on web seed {}", seed.url())));
+                    }
+                    println!("{:#}", e);
+                    tokio::time::sleep(WEB_SEED_RETRY).await;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // consecutive blocks are fetched with one request
+    async fn fetch_blocks(&self, seed: &WebSeed, reqs: &[BlockReqPayload]) -> Result<()> {
+        let piece_length = self.torrent.info.piece_length as u64;
+        let offset = |r: &BlockReqPayload| r.index as u64 * piece_length + r.begin as u64;
+        let mut reqs = reqs.to_vec();
+        reqs.sort_by_key(offset);
+        for run in reqs.chunk_by(|a, b| offset(b) == offset(a) + a.length as u64) {
+            let length = run.iter().map(|r| r.length as u64).sum();
+            let data = seed.fetch(offset(&run[0]), length).await?;
+            let mut at = 0;
+            for req in run {
+                let block = &data[at..at + req.length as usize];
+                self.on_block(req.index, req.begin, block, true)?;
+                at += req.length as usize;
+            }
+        }
+        Ok(())
+    }
+
+    fn hash_matches(&self, index: usize, data: &[u8]) -> bool {
+        let mut hasher = sha1::Sha1::new();
+        Digest::update(&mut hasher, data);
+        hasher.finalize().as_slice() == self.torrent.info.pieces.0[index]
+    }
+
+    fn finish_piece(&self, index: usize, data: &[u8]) -> Result<()> {
+        let matches = self.hash_matches(index, data);
+        let mut picker = self.picker.lock().unwrap();
+        if !matches {
+            println!("piece {} failed hash check", index);
+            picker.piece_failed(index);
+            self.stats.add_wasted(data.len() as u64);
+            return Ok(());
+        }
+        self.store.write_piece(index, data)?;
+        picker.piece_verified(index);
+        let _ = self.events.send(PeerEvent::Have(index as u32));
+        println!(
+            "Downloaded piece {} ({} left)",
+            index,
+            picker.remaining()
+        );
+        if picker.is_complete() {
+            self.done.send_replace(true);
+        }
+        Ok(())
+    }
+}
+
+fn reject(req: &BlockReqPayload) -> PeerMessage {
+    PeerMessage::RejectRequest {
+        index: req.index,
+        begin: req.begin,
+        length: req.length,
+    }
+}
+
+// next value of an optional channel, forever without one
+async fn recv<T>(rx: &mut Option<mpsc::Receiver<T>>) -> Option<T> {
+    match rx {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+// waits until `deadline`, forever without one
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+// choker rounds every `interval`, free slots filled when woken, until the engine is dropped
+async fn run_choker(shared: Weak<Shared>, rechoke: Arc<Notify>, config: ChokerConfig) {
+    let mut choker = Choker::new(config);
+    let mut tick = tokio::time::interval(config.interval);
+    loop {
+        let full_round = tokio::select! {
+            _ = tick.tick() => true,
+            _ = rechoke.notified() => false,
+        };
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        shared.rechoke(&mut choker, full_round);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::torrent::listener::{Listener, ListenerConfig, TorrentTable};
+    use crate::torrent::serde::hashes::Hashes;
+    use crate::torrent::storage::Storage;
+    use crate::torrent::torrent::{FileInfo, Info, Keys};
+    use crate::torrent::transport::MemoryNetwork;
+
+    const PIECE_LENGTH: usize = 32 * 1024;
+
+    // files of uneven sizes so pieces straddle them and the last piece is short
+    fn multi_file_torrent(files: &[(&str, Vec<u8>)]) -> Torrent {
+        let data: Vec<u8> = files.iter().flat_map(|(_, d)| d.clone()).collect();
+        let pieces = data
+            .chunks(PIECE_LENGTH)
+            .map(|piece| sha1::Sha1::digest(piece).into())
+            .collect();
+        Torrent {
+            announce: String::new(),
+            created_by: String::new(),
+            url_list: Vec::new(),
+            info: Info {
+                name: "album".to_string(),
+                piece_length: PIECE_LENGTH,
+                pieces: Hashes(pieces),
+                private: None,
+                keys: Keys::Multiple {
+                    files: files
+                        .iter()
+                        .map(|(name, d)| FileInfo {
+                            length: d.len(),
+                            path: vec![name.to_string()],
+                        })
+                        .collect(),
+                },
+            },
+        }
+    }
+
+    fn content(len: u32) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 + i / 251) as u8).collect()
+    }
+
+    fn read_only_engine(torrent: &Torrent, dir: &Path) -> Engine {
+        let layout = FileLayout::new(torrent, dir).unwrap();
+        let store = Storage::open_read_only(layout).unwrap();
+        Engine::new(
+            torrent.clone(),
+            Session::new(),
+            EngineConfig::default(),
+            Box::new(store),
+        )
+        .with_wanted(&[])
+    }
+
+    #[tokio::test]
+    async fn downloads_from_a_seeder_over_a_memory_network() {
+        let files = [
+            ("one", content(70_001)),
+            ("two", content(13)),
+            ("three", content(150_000)),
+        ];
+        let torrent = multi_file_torrent(&files);
+        let seed_dir = tempfile::tempdir().unwrap();
+        for (name, data) in &files {
+            std::fs::write(seed_dir.path().join(name), data).unwrap();
+        }
+
+        let network = MemoryNetwork::new();
+        let seeder_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let seeder = read_only_engine(&torrent, seed_dir.path());
+        assert_eq!(seeder.recheck().unwrap(), torrent.piece_count());
+        let torrents = TorrentTable::new();
+        torrents.insert(seeder);
+        let listener = Listener::new(ListenerConfig::default(), torrents)
+            .with_acceptor(network.endpoint(seeder_addr));
+        let serve = tokio::spawn(listener.serve());
+
+        let leech_dir = tempfile::tempdir().unwrap();
+        let layout = FileLayout::new(&torrent, leech_dir.path()).unwrap();
+        let mut config = EngineConfig::default();
+        config.connect.custom = Some(Arc::new(network.endpoint("10.0.0.2:6881".parse().unwrap())));
+        let leecher = Engine::new(
+            torrent.clone(),
+            Session::new(),
+            config,
+            Box::new(Storage::open(layout).unwrap()),
+        );
+        tokio::time::timeout(Duration::from_secs(30), leecher.run(vec![seeder_addr]))
+            .await
+            .expect("download timed out")
+            .unwrap();
+        serve.abort();
+
+        assert!(leecher.is_complete());
+        assert_eq!(leecher.stats().downloaded(), torrent.length() as u64);
+        for (name, data) in &files {
+            assert_eq!(&std::fs::read(leech_dir.path().join(name)).unwrap(), data);
+        }
+        // every piece on disk matches its hash
+        let check = read_only_engine(&torrent, leech_dir.path());
+        assert_eq!(check.recheck().unwrap(), torrent.piece_count());
+    }
+}
@@ -1,17 +1,27 @@
-use anyhow::{anyhow, Result};
-use bytes::buf::Reader;
+use anyhow::Result;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use serde::Deserialize;
-use tokio::io::{AsyncRead, AsyncReadExt};
-
-#[derive(Deserialize, Debug)]
-pub struct ExchangeMsg {
-    pub len_prefix: u32,
-    pub message_id: Option<MsgType>,
-    #[serde(default)]
-    pub payload: Vec<u8>,
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::torrent::bitfield::BitField;
+
+/// size of the blocks we request, the de facto standard
+pub const BLOCK_MAX: usize = 1 << 14;
+// room for extension messages carrying a full block plus their bencoded header
+const FRAME_SLACK: usize = 1 << 10;
+
+/// Violations of the peer wire protocol, returned (wrapped in `anyhow::Error`) by [`MessageCodec`].
+#[derive(Debug, thiserror::Error)]
+pub enum ProtocolError {
+    #[error("frame of length {len} exceeds the limit of {max} bytes")]
+    FrameTooLarge { len: usize, max: usize },
+    #[error("unknown message id {0}")]
+    UnknownMessageId(u8),
+    #[error("invalid {msg_type:?} payload: {reason}")]
+    InvalidPayload { msg_type: MsgType, reason: String },
 }
 
-#[derive(Deserialize, Debug, PartialEq)]
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum MsgType {
     Choke = 0,
@@ -23,6 +33,13 @@ pub enum MsgType {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    Port = 9,
+    SuggestPiece = 13,
+    HaveAll = 14,
+    HaveNone = 15,
+    RejectRequest = 16,
+    AllowedFast = 17,
+    Extended = 20,
 }
 
 impl TryFrom<u8> for MsgType {
@@ -38,106 +55,558 @@ impl TryFrom<u8> for MsgType {
             6 => Ok(Self::Request),
             7 => Ok(Self::Piece),
             8 => Ok(Self::Cancel),
-            v => Err(anyhow!("invalid message type {}", v).context("parse message type")),
+            9 => Ok(Self::Port),
+            13 => Ok(Self::SuggestPiece),
+            14 => Ok(Self::HaveAll),
+            15 => Ok(Self::HaveNone),
+            16 => Ok(Self::RejectRequest),
+            17 => Ok(Self::AllowedFast),
+            20 => Ok(Self::Extended),
+            v => Err(ProtocolError::UnknownMessageId(v).into()),
         }
     }
 }
 
-impl ExchangeMsg {
-    pub fn new(t: MsgType, payload: Vec<u8>) -> Self {
-        ExchangeMsg {
-            len_prefix: payload.len() as u32,
-            message_id: Some(t),
-            payload,
+/// A decoded peer wire message, the `len_prefix` and id are implied by the variant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerMessage {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have { index: u32 },
+    Bitfield(BitField),
+    Request { index: u32, begin: u32, length: u32 },
+    Piece { index: u32, begin: u32, block: Bytes },
+    Cancel { index: u32, begin: u32, length: u32 },
+    /// listen port of the peer's DHT node
+    Port(u16),
+    /// BEP 6: the peer recommends downloading this piece
+    SuggestPiece { index: u32 },
+    /// BEP 6: replaces the bitfield of a seed
+    HaveAll,
+    /// BEP 6: replaces the bitfield of a peer without pieces
+    HaveNone,
+    /// BEP 6: the request won't be answered
+    RejectRequest { index: u32, begin: u32, length: u32 },
+    /// BEP 6: the piece may be requested while choked
+    AllowedFast { index: u32 },
+    /// BEP 10 message, id 0 is the extension handshake
+    Extended { id: u8, payload: Bytes },
+}
+
+impl PeerMessage {
+    /// `None` for keep-alive, which has no id
+    pub fn msg_type(&self) -> Option<MsgType> {
+        let t = match self {
+            PeerMessage::KeepAlive => return None,
+            PeerMessage::Choke => MsgType::Choke,
+            PeerMessage::Unchoke => MsgType::Unchoke,
+            PeerMessage::Interested => MsgType::Interested,
+            PeerMessage::NotInterested => MsgType::NotInterested,
+            PeerMessage::Have { .. } => MsgType::Have,
+            PeerMessage::Bitfield(_) => MsgType::BitField,
+            PeerMessage::Request { .. } => MsgType::Request,
+            PeerMessage::Piece { .. } => MsgType::Piece,
+            PeerMessage::Cancel { .. } => MsgType::Cancel,
+            PeerMessage::Port(_) => MsgType::Port,
+            PeerMessage::SuggestPiece { .. } => MsgType::SuggestPiece,
+            PeerMessage::HaveAll => MsgType::HaveAll,
+            PeerMessage::HaveNone => MsgType::HaveNone,
+            PeerMessage::RejectRequest { .. } => MsgType::RejectRequest,
+            PeerMessage::AllowedFast { .. } => MsgType::AllowedFast,
+            PeerMessage::Extended { .. } => MsgType::Extended,
+        };
+        Some(t)
+    }
+
+    /// length of id + payload, the value of the length prefix on the wire
+    fn wire_len(&self) -> usize {
+        match self {
+            PeerMessage::KeepAlive => 0,
+            PeerMessage::Choke
+            | PeerMessage::Unchoke
+            | PeerMessage::Interested
+            | PeerMessage::NotInterested
+            | PeerMessage::HaveAll
+            | PeerMessage::HaveNone => 1,
+            PeerMessage::Have { .. }
+            | PeerMessage::SuggestPiece { .. }
+            | PeerMessage::AllowedFast { .. } => 5,
+            PeerMessage::Bitfield(bits) => 1 + bits.as_bytes().len(),
+            PeerMessage::Request { .. }
+            | PeerMessage::Cancel { .. }
+            | PeerMessage::RejectRequest { .. } => 1 + BlockReqPayload::SIZE,
+            PeerMessage::Piece { block, .. } => 9 + block.len(),
+            PeerMessage::Port(_) => 3,
+            PeerMessage::Extended { payload, .. } => 2 + payload.len(),
         }
     }
-    pub async fn read_from<R>(reader: &mut R) -> Result<Self>
-    where
-        R: AsyncRead + Unpin,
-    {
-        let len = reader.read_u32().await?;
-        if len > 0 {
-            return match reader.read_u8().await?.try_into()? {
-                MsgType::BitField => {
-                    let mut buf = Vec::with_capacity((len - 1) as usize); // TODO: Vec::new ?
-                    reader.read_buf(&mut buf).await?; // read and advance
-                    println!("message payload: {:?}", buf);
-                    Ok(ExchangeMsg {
-                        len_prefix: len,
-                        message_id: Some(MsgType::BitField),
-                        payload: buf,
-                    })
+
+    /// parse the payload following message id `t`
+    pub fn from_parts(t: MsgType, mut payload: Bytes) -> Result<Self> {
+        let invalid = |reason: String| ProtocolError::InvalidPayload {
+            msg_type: t,
+            reason,
+        };
+        let expect_len = |len: usize| -> Result<()> {
+            if payload.len() != len {
+                return Err(invalid(format!("expect {} bytes, got {}", len, payload.len())).into());
+            }
+            Ok(())
+        };
+        let msg = match t {
+            MsgType::Choke
+            | MsgType::Unchoke
+            | MsgType::Interested
+            | MsgType::NotInterested
+            | MsgType::HaveAll
+            | MsgType::HaveNone => {
+                expect_len(0)?;
+                match t {
+                    MsgType::Choke => PeerMessage::Choke,
+                    MsgType::Unchoke => PeerMessage::Unchoke,
+                    MsgType::Interested => PeerMessage::Interested,
+                    MsgType::NotInterested => PeerMessage::NotInterested,
+                    MsgType::HaveAll => PeerMessage::HaveAll,
+                    _ => PeerMessage::HaveNone,
                 }
-                _ => Err(anyhow!("failed to read")),
-            };
-        }
+            }
+            MsgType::Have | MsgType::SuggestPiece | MsgType::AllowedFast => {
+                expect_len(4)?;
+                let index = payload.get_u32();
+                match t {
+                    MsgType::Have => PeerMessage::Have { index },
+                    MsgType::SuggestPiece => PeerMessage::SuggestPiece { index },
+                    _ => PeerMessage::AllowedFast { index },
+                }
+            }
+            MsgType::BitField => PeerMessage::Bitfield(BitField::from_bytes(&payload)),
+            MsgType::Request | MsgType::Cancel | MsgType::RejectRequest => {
+                expect_len(BlockReqPayload::SIZE)?;
+                let BlockReqPayload {
+                    index,
+                    begin,
+                    length,
+                } = BlockReqPayload::from_bytes(&payload)?;
+                match t {
+                    MsgType::Request => PeerMessage::Request {
+                        index,
+                        begin,
+                        length,
+                    },
+                    MsgType::Cancel => PeerMessage::Cancel {
+                        index,
+                        begin,
+                        length,
+                    },
+                    _ => PeerMessage::RejectRequest {
+                        index,
+                        begin,
+                        length,
+                    },
+                }
+            }
+            MsgType::Piece => {
+                if payload.len() < 8 {
+                    return Err(invalid(format!("{} bytes is too short", payload.len())).into());
+                }
+                let index = payload.get_u32();
+                let begin = payload.get_u32();
+                PeerMessage::Piece {
+                    index,
+                    begin,
+                    block: payload,
+                }
+            }
+            MsgType::Port => {
+                expect_len(2)?;
+                PeerMessage::Port(payload.get_u16())
+            }
+            MsgType::Extended => {
+                if payload.is_empty() {
+                    return Err(invalid("missing extended message id".to_string()).into());
+                }
+                let id = payload.get_u8();
+                PeerMessage::Extended { id, payload }
+            }
+        };
+        Ok(msg)
+    }
 
-        Err(anyhow!(""))
+    /// write length prefix, id and payload
+    pub fn write_to(&self, dst: &mut BytesMut) {
+        let len = self.wire_len();
+        dst.reserve(4 + len);
+        dst.put_u32(len as u32);
+        if let Some(t) = self.msg_type() {
+            dst.put_u8(t as u8);
+        }
+        match self {
+            PeerMessage::Have { index }
+            | PeerMessage::SuggestPiece { index }
+            | PeerMessage::AllowedFast { index } => dst.put_u32(*index),
+            PeerMessage::Bitfield(bits) => dst.extend_from_slice(bits.as_bytes()),
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            }
+            | PeerMessage::Cancel {
+                index,
+                begin,
+                length,
+            }
+            | PeerMessage::RejectRequest {
+                index,
+                begin,
+                length,
+            } => dst.extend_from_slice(&BlockReqPayload::new(*index, *begin, *length).to_bytes()),
+            PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                dst.put_u32(*index);
+                dst.put_u32(*begin);
+                dst.extend_from_slice(block);
+            }
+            PeerMessage::Port(port) => dst.put_u16(*port),
+            PeerMessage::Extended { id, payload } => {
+                dst.put_u8(*id);
+                dst.extend_from_slice(payload);
+            }
+            _ => {}
+        }
     }
 }
 
-#[derive(Debug)]
-#[repr(C)]
+/// payload of a `Request` (and `Cancel`) message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct BlockReqPayload {
-    pub index: [u8; 4], // piece index
-    pub begin: [u8; 4],
-    pub length: [u8; 4],
+    pub index: u32, // piece index
+    pub begin: u32,
+    pub length: u32,
 }
 
 impl BlockReqPayload {
+    pub const SIZE: usize = 12;
+
     pub fn new(index: u32, begin: u32, length: u32) -> Self {
         BlockReqPayload {
-            index: index.to_be_bytes(),
-            begin: begin.to_be_bytes(),
-            length: length.to_be_bytes(),
+            index,
+            begin,
+            length,
         }
     }
 
-    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-        let bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()];
-        // Safety: Handshake is a POD with repr(c)
-        let bytes: &mut [u8; std::mem::size_of::<Self>()] = unsafe { &mut *bytes };
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[0..4].copy_from_slice(&self.index.to_be_bytes());
+        bytes[4..8].copy_from_slice(&self.begin.to_be_bytes());
+        bytes[8..12].copy_from_slice(&self.length.to_be_bytes());
         bytes
     }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        anyhow::ensure!(
+            data.len() == Self::SIZE,
+            "request payload must be {} bytes, got {}",
+            Self::SIZE,
+            data.len()
+        );
+        Ok(BlockReqPayload {
+            index: read_u32(&data[0..4]),
+            begin: read_u32(&data[4..8]),
+            length: read_u32(&data[8..12]),
+        })
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes.try_into().expect("guaranteed to be length of 4"))
+}
+
+/// Length-prefixed framing of [`PeerMessage`]s, used for every read and write after the handshake.
+#[derive(Debug, Clone, Copy)]
+pub struct MessageCodec {
+    max_frame: usize,
+}
+
+impl MessageCodec {
+    /// `max_frame` bounds the length prefix (id + payload) of incoming messages
+    pub fn new(max_frame: usize) -> Self {
+        Self { max_frame }
+    }
+
+    /// the largest legit frame is either a piece message carrying one block
+    /// or the bitfield of the torrent
+    pub fn for_torrent(block_size: usize, piece_count: usize) -> Self {
+        let piece_frame = 9 + block_size + FRAME_SLACK;
+        let bitfield_frame = 1 + piece_count.div_ceil(8);
+        Self::new(piece_frame.max(bitfield_frame))
+    }
+
+    pub fn max_frame(&self) -> usize {
+        self.max_frame
+    }
+}
+
+impl Default for MessageCodec {
+    fn default() -> Self {
+        Self::for_torrent(BLOCK_MAX, 0)
+    }
+}
+
+impl Encoder<PeerMessage> for MessageCodec {
+    type Error = anyhow::Error;
+
+    fn encode(&mut self, item: PeerMessage, dst: &mut BytesMut) -> Result<()> {
+        item.write_to(dst);
+        Ok(())
+    }
 }
 
-#[repr(C)]
-pub struct BlockRespPayload<T: ?Sized = [u8]> {
-    pub index: [u8; 4], // piece index
-    pub begin: [u8; 4],
-    pub data: T,
+impl Decoder for MessageCodec {
+    type Item = PeerMessage;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+
+        let mut len_bytes = [0u8; 4];
+        len_bytes.copy_from_slice(&src[..4]);
+
+        // len is the total bytes of messageId + Payload
+        let len: usize = u32::from_be_bytes(len_bytes) as usize;
+        if len == 0 {
+            src.advance(4);
+            return Ok(Some(PeerMessage::KeepAlive));
+        }
+
+        // Check that the length is not too large to avoid a denial of
+        // service attack where the server runs out of memory.
+        if len > self.max_frame {
+            return Err(ProtocolError::FrameTooLarge {
+                len,
+                max: self.max_frame,
+            }
+            .into());
+        }
+
+        // if not all data arrived, we need to wait
+        if src.len() < 4 + len {
+            // We reserve more space in the buffer. This is not strictly
+            // necessary, but is a good idea performance-wise.
+            src.reserve(4 + len - src.len());
+            return Ok(None);
+        }
+
+        let msg_type = src[4].try_into()?;
+        src.advance(5);
+        let payload = src.split_to(len - 1).freeze();
+        Ok(Some(PeerMessage::from_parts(msg_type, payload)?))
+    }
 }
 
-impl BlockRespPayload {
-    pub fn index(&self) -> u32 {
-        u32::from_be_bytes(self.begin)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_messages() -> Vec<PeerMessage> {
+        vec![
+            PeerMessage::KeepAlive,
+            PeerMessage::Choke,
+            PeerMessage::Unchoke,
+            PeerMessage::Interested,
+            PeerMessage::NotInterested,
+            PeerMessage::Have { index: 7 },
+            PeerMessage::Bitfield(BitField::from_bytes(&[0b1010_0000, 0x01])),
+            PeerMessage::Request {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+            PeerMessage::Piece {
+                index: 2,
+                begin: 32768,
+                block: Bytes::from(vec![0xab; BLOCK_MAX]),
+            },
+            PeerMessage::Cancel {
+                index: 3,
+                begin: 0,
+                length: 100,
+            },
+            PeerMessage::Port(6881),
+            PeerMessage::SuggestPiece { index: 4 },
+            PeerMessage::HaveAll,
+            PeerMessage::HaveNone,
+            PeerMessage::RejectRequest {
+                index: 5,
+                begin: 16,
+                length: 32,
+            },
+            PeerMessage::AllowedFast { index: 6 },
+            PeerMessage::Extended {
+                id: 0,
+                payload: Bytes::from_static(b"d1:md6:ut_pexi1eee"),
+            },
+        ]
+    }
+
+    fn encoded(msg: &PeerMessage) -> BytesMut {
+        let mut buf = BytesMut::new();
+        MessageCodec::default()
+            .encode(msg.clone(), &mut buf)
+            .unwrap();
+        buf
     }
 
-    pub fn begin(&self) -> u32 {
-        u32::from_be_bytes(self.begin)
+    #[test]
+    fn round_trips_every_message() {
+        let mut codec = MessageCodec::default();
+        for msg in all_messages() {
+            let mut buf = encoded(&msg);
+            assert_eq!(buf.len(), 4 + msg.wire_len(), "{:?}", msg.msg_type());
+            assert_eq!(codec.decode(&mut buf).unwrap(), Some(msg));
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn decodes_back_to_back_frames() {
+        let mut codec = MessageCodec::default();
+        let mut buf = BytesMut::new();
+        for msg in all_messages() {
+            buf.extend_from_slice(&encoded(&msg));
+        }
+        let mut decoded = Vec::new();
+        while let Some(msg) = codec.decode(&mut buf).unwrap() {
+            decoded.push(msg);
+        }
+        assert_eq!(decoded, all_messages());
+    }
+
+    #[test]
+    fn waits_for_partial_frames() {
+        let mut codec = MessageCodec::default();
+        for msg in all_messages() {
+            let wire = encoded(&msg);
+            let mut buf = BytesMut::new();
+            for (i, byte) in wire.iter().enumerate() {
+                assert_eq!(codec.decode(&mut buf).unwrap(), None, "after {} bytes", i);
+                buf.put_u8(*byte);
+            }
+            assert_eq!(codec.decode(&mut buf).unwrap(), Some(msg));
+        }
+    }
+
+    #[test]
+    fn keep_alive_is_a_zero_length() {
+        let mut buf = BytesMut::from(&[0u8, 0, 0, 0, 0, 0][..]);
+        let mut codec = MessageCodec::default();
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(PeerMessage::KeepAlive)
+        );
+        assert_eq!(buf.len(), 2);
+        assert_eq!(&encoded(&PeerMessage::KeepAlive)[..], &[0, 0, 0, 0]);
     }
 
-    pub fn block(&self) -> &[u8] {
-        &self.begin 
+    #[test]
+    fn rejects_oversize_frames() {
+        let mut codec = MessageCodec::new(100);
+        let mut buf = BytesMut::new();
+        buf.put_u32(101);
+        buf.put_u8(MsgType::Piece as u8);
+        let err = codec.decode(&mut buf).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ProtocolError>(),
+            Some(ProtocolError::FrameTooLarge { len: 101, max: 100 })
+        ));
+        // a piece message with a whole block fits the default limit
+        let piece = all_messages()
+            .into_iter()
+            .find(|m| matches!(m, PeerMessage::Piece { .. }))
+            .unwrap();
+        assert!(MessageCodec::default().decode(&mut encoded(&piece)).is_ok());
     }
 
-    const PIECE_LEAD: usize = std::mem::size_of::<BlockRespPayload<()>>();
+    #[test]
+    fn rejects_truncated_payloads() {
+        let frames: [&[u8]; 6] = [
+            // have with a 3-byte index
+            &[0, 0, 0, 4, 4, 0, 0, 1],
+            // request missing its length
+            &[0, 0, 0, 9, 6, 0, 0, 0, 1, 0, 0, 0, 0],
+            // piece without a begin
+            &[0, 0, 0, 5, 7, 0, 0, 0, 1],
+            // port of one byte
+            &[0, 0, 0, 2, 9, 0x1a],
+            // extended without its id
+            &[0, 0, 0, 1, 20],
+            // have with a trailing byte
+            &[0, 0, 0, 6, 4, 0, 0, 0, 1, 0],
+        ];
+        for frame in frames {
+            let mut buf = BytesMut::from(frame);
+            let err = MessageCodec::default().decode(&mut buf).unwrap_err();
+            assert!(
+                matches!(
+                    err.downcast_ref::<ProtocolError>(),
+                    Some(ProtocolError::InvalidPayload { .. })
+                ),
+                "{:?}: {}",
+                frame,
+                err
+            );
+        }
+    }
 
-    pub fn from_bytes(data: &[u8]) -> Option<&Self> {
-        if data.len() < Self::PIECE_LEAD {
-            return None;
+    #[test]
+    fn rejects_payloads_of_bare_messages() {
+        for t in [
+            MsgType::Choke,
+            MsgType::Unchoke,
+            MsgType::Interested,
+            MsgType::NotInterested,
+            MsgType::HaveAll,
+            MsgType::HaveNone,
+        ] {
+            let mut buf = BytesMut::from(&[0u8, 0, 0, 2, t as u8, 0][..]);
+            let err = MessageCodec::default().decode(&mut buf).unwrap_err();
+            assert!(
+                matches!(
+                    err.downcast_ref::<ProtocolError>(),
+                    Some(ProtocolError::InvalidPayload { msg_type, .. }) if *msg_type == t
+                ),
+                "{:?}: {}",
+                t,
+                err
+            );
         }
-        // TODO don't understand
-        let n = data.len();
-        // NOTE: The slicing here looks really weird. The reason we do it is because we need the
-        // length part of the fat pointer to Piece to hold the length of _just_ the `block` field.
-        // And the only way we can change the length of the fat pointer to Piece is by changing the
-        // length of the fat pointer to the slice, which we do by slicing it. We can't slice it at
-        // the front (as it would invalidate the ptr part of the fat pointer), so we slice it at
-        // the back!
-        let piece = &data[..n - Self::PIECE_LEAD] as *const [u8] as *const BlockRespPayload;
-        // Safety: Piece is a POD with repr(c) and repr(packed), _and_ the fat pointer data length
-        // is the length of the trailing DST field (thanks to the PIECE_LEAD offset).
-        Some(unsafe { &*piece })
-    }
-}
\ No newline at end of file
+    }
+
+    #[test]
+    fn rejects_unknown_ids() {
+        let mut buf = BytesMut::from(&[0u8, 0, 0, 1, 42][..]);
+        let err = MessageCodec::default().decode(&mut buf).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ProtocolError>(),
+            Some(ProtocolError::UnknownMessageId(42))
+        ));
+    }
+
+    #[test]
+    fn encodes_requests_big_endian() {
+        let req = BlockReqPayload::new(1, 0x4000, 0x0102_0304);
+        let bytes = req.to_bytes();
+        assert_eq!(bytes, [0, 0, 0, 1, 0, 0, 0x40, 0, 1, 2, 3, 4]);
+        assert_eq!(BlockReqPayload::from_bytes(&bytes).unwrap(), req);
+        assert!(BlockReqPayload::from_bytes(&bytes[..11]).is_err());
+        assert!(BlockReqPayload::from_bytes(&[0; 13]).is_err());
+    }
+}
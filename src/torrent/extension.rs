@@ -0,0 +1,44 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+/// id of the extension handshake within `Extended` messages
+pub const HANDSHAKE_ID: u8 = 0;
+/// bit in `Handshake.reserved[5]` announcing the extension protocol (BEP 10)
+pub const RESERVED_BIT: u8 = 0x10;
+
+/// Payload of the extension handshake (BEP 10).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExtendedHandshake {
+    /// extension name to the message id the sender wants to receive it with
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    /// listen port
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+    /// client name and version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+    /// number of outstanding requests the sender keeps without dropping
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<usize>,
+    /// our address as seen by the sender
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub yourip: Option<ByteBuf>,
+}
+
+impl ExtendedHandshake {
+    pub fn from_bytes(payload: &[u8]) -> Result<Self> {
+        serde_bencode::from_bytes(payload).context("invalid extension handshake")
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_bencode::to_bytes(self).expect("extension handshake is always encodable")
+    }
+}
+
+pub fn supports_extensions(reserved: &[u8; 8]) -> bool {
+    reserved[5] & RESERVED_BIT != 0
+}
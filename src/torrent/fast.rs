@@ -0,0 +1,86 @@
+use std::net::IpAddr;
+
+use sha1::{Digest, Sha1};
+
+/// bit in `Handshake.reserved[7]` announcing the fast extension (BEP 6)
+pub const RESERVED_BIT: u8 = 0x04;
+/// size of the allowed fast set we grant a peer
+pub const ALLOWED_FAST_COUNT: usize = 10;
+
+pub fn supports_fast(reserved: &[u8; 8]) -> bool {
+    reserved[7] & RESERVED_BIT != 0
+}
+
+/// The pieces a peer at `ip` may request while choked, `k` of them at most (BEP 6). The
+/// set only depends on the peer's /24 network, so reconnecting doesn't get a peer more.
+/// The BEP defines the set for IPv4 only, IPv6 peers get none.
+pub fn allowed_fast_set(
+    info_hash: &[u8; 20],
+    ip: IpAddr,
+    piece_count: usize,
+    k: usize,
+) -> Vec<u32> {
+    let IpAddr::V4(ip) = ip.to_canonical() else {
+        return Vec::new();
+    };
+    let k = k.min(piece_count);
+    let mut set = Vec::with_capacity(k);
+    let mut x = Vec::with_capacity(24);
+    x.extend_from_slice(&(u32::from(ip) & 0xffff_ff00).to_be_bytes());
+    x.extend_from_slice(info_hash);
+    while set.len() < k {
+        x = Sha1::digest(&x).to_vec();
+        for chunk in x.chunks_exact(4) {
+            if set.len() >= k {
+                break;
+            }
+            let y = u32::from_be_bytes(chunk.try_into().expect("4 byte chunk"));
+            let index = y % piece_count as u32;
+            if !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+    set
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    // the worked example of BEP 6
+    const INFO_HASH: [u8; 20] = [0xaa; 20];
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(80, 4, 4, 200));
+
+    #[test]
+    fn matches_the_bep_example() {
+        assert_eq!(
+            allowed_fast_set(&INFO_HASH, IP, 1313, 7),
+            [1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(
+            allowed_fast_set(&INFO_HASH, IP, 1313, 9),
+            [1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+    }
+
+    #[test]
+    fn depends_on_the_network_only() {
+        let neighbour = IpAddr::V4(Ipv4Addr::new(80, 4, 4, 1));
+        let mapped = IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0x5004, 0x04c8));
+        let set = allowed_fast_set(&INFO_HASH, IP, 1313, 7);
+        assert_eq!(allowed_fast_set(&INFO_HASH, neighbour, 1313, 7), set);
+        assert_eq!(allowed_fast_set(&INFO_HASH, mapped, 1313, 7), set);
+        assert!(allowed_fast_set(&INFO_HASH, IpAddr::V6(Ipv6Addr::LOCALHOST), 1313, 7).is_empty());
+    }
+
+    #[test]
+    fn grants_every_piece_of_a_small_torrent() {
+        let mut set = allowed_fast_set(&INFO_HASH, IP, 5, ALLOWED_FAST_COUNT);
+        set.sort_unstable();
+        assert_eq!(set, [0, 1, 2, 3, 4]);
+        assert!(allowed_fast_set(&INFO_HASH, IP, 0, ALLOWED_FAST_COUNT).is_empty());
+    }
+}
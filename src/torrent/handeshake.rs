@@ -1,4 +1,8 @@
-#[repr(C)]
+use anyhow::{ensure, Result};
+
+pub const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Handshake {
     pub length: u8,
     pub bittorrent: [u8; 19],
@@ -8,13 +12,64 @@ pub struct Handshake {
 }
 
 impl Handshake {
+    /// bytes on the wire: 1 + 19 + 8 + 20 + 20
+    pub const SIZE: usize = 68;
+
     pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
         Self {
             length: 19,
-            bittorrent: *b"BitTorrent protocol",
+            bittorrent: *PROTOCOL,
             reserved: [0; 8],
             info_hash,
             peer_id,
         }
     }
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[0] = self.length;
+        bytes[1..20].copy_from_slice(&self.bittorrent);
+        bytes[20..28].copy_from_slice(&self.reserved);
+        bytes[28..48].copy_from_slice(&self.info_hash);
+        bytes[48..68].copy_from_slice(&self.peer_id);
+        bytes
+    }
+
+    /// parse and validate the protocol string of a handshake
+    pub fn from_bytes(bytes: &[u8; Self::SIZE]) -> Result<Self> {
+        ensure!(bytes[0] == 19, "invalid protocol string length {}", bytes[0]);
+        ensure!(&bytes[1..20] == PROTOCOL, "unknown protocol");
+        Ok(Self {
+            length: bytes[0],
+            bittorrent: bytes[1..20].try_into()?,
+            reserved: bytes[20..28].try_into()?,
+            info_hash: bytes[28..48].try_into()?,
+            peer_id: bytes[48..68].try_into()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_a_handshake() {
+        let handshake = Handshake::new([1; 20], [2; 20]);
+        let bytes = handshake.to_bytes();
+        assert_eq!(bytes[0], 19);
+        assert_eq!(&bytes[1..20], PROTOCOL);
+        assert_eq!(&bytes[28..48], &[1; 20]);
+        assert_eq!(&bytes[48..], &[2; 20]);
+        assert_eq!(Handshake::from_bytes(&bytes).unwrap(), handshake);
+    }
+
+    #[test]
+    fn rejects_other_protocols() {
+        let mut bytes = Handshake::new([1; 20], [2; 20]).to_bytes();
+        bytes[1] = b'b';
+        assert!(Handshake::from_bytes(&bytes).is_err());
+        bytes[0] = 18;
+        assert!(Handshake::from_bytes(&bytes).is_err());
+    }
 }
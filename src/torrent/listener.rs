@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{ensure, Context, Result};
+use tokio::io::AsyncReadExt;
+use futures_util::future;
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+
+use crate::torrent::engine::Engine;
+use crate::torrent::handeshake::Handshake;
+use crate::torrent::mse;
+use crate::torrent::mse::{EncryptionPolicy, MseStream};
+use crate::torrent::transport::{Acceptor, PeerStream};
+
+// pause after a failed accept, e.g. when out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Settings of the incoming connection listener.
+#[derive(Debug, Clone)]
+pub struct ListenerConfig {
+    /// incoming connections over all torrents
+    pub max_connections: usize,
+    /// time a new connection gets to send its handshake
+    pub handshake_timeout: Duration,
+    /// which of plaintext and encrypted connections are accepted
+    pub encryption: EncryptionPolicy,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 200,
+            handshake_timeout: Duration::from_secs(10),
+            encryption: EncryptionPolicy::default(),
+        }
+    }
+}
+
+/// Torrents reachable through a listener, by info hash. Clones share the table so torrents
+/// can be added while the listener runs.
+#[derive(Clone, Default)]
+pub struct TorrentTable {
+    engines: Arc<Mutex<HashMap<[u8; 20], Engine>>>,
+}
+
+impl TorrentTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, engine: Engine) {
+        self.engines
+            .lock()
+            .unwrap()
+            .insert(engine.info_hash(), engine);
+    }
+
+    pub fn remove(&self, info_hash: &[u8; 20]) -> Option<Engine> {
+        self.engines.lock().unwrap().remove(info_hash)
+    }
+
+    pub fn get(&self, info_hash: &[u8; 20]) -> Option<Engine> {
+        self.engines.lock().unwrap().get(info_hash).cloned()
+    }
+
+    pub fn engines(&self) -> Vec<Engine> {
+        self.engines.lock().unwrap().values().cloned().collect()
+    }
+}
+
+/// Accepts peer connections and hands them to the torrent named in their handshake.
+pub struct Listener {
+    acceptors: Vec<Box<dyn Acceptor>>,
+    config: ListenerConfig,
+    torrents: TorrentTable,
+    limit: Arc<Semaphore>,
+}
+
+impl Listener {
+    /// a listener without connections to accept yet, see `with_acceptor`
+    pub fn new(config: ListenerConfig, torrents: TorrentTable) -> Self {
+        Self {
+            acceptors: Vec::new(),
+            limit: Arc::new(Semaphore::new(config.max_connections.max(1))),
+            config,
+            torrents,
+        }
+    }
+
+    /// a listener accepting tcp connections on `addr`
+    pub async fn bind(
+        addr: SocketAddr,
+        config: ListenerConfig,
+        torrents: TorrentTable,
+    ) -> Result<Self> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("listen on {}", addr))?;
+        Ok(Self::new(config, torrents).with_acceptor(listener))
+    }
+
+    /// also accept the connections of `acceptor`, e.g. a uTP socket. All of them share the
+    /// connection limit.
+    pub fn with_acceptor(mut self, acceptor: impl Acceptor + 'static) -> Self {
+        self.acceptors.push(Box::new(acceptor));
+        self
+    }
+
+    /// address of the first acceptor
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.acceptors
+            .first()
+            .context("listener without acceptors")?
+            .local_addr()
+    }
+
+    pub async fn serve(self) -> Result<()> {
+        ensure!(!self.acceptors.is_empty(), "listener without acceptors");
+        loop {
+            let (res, _, _) = future::select_all(self.acceptors.iter().map(|a| a.accept())).await;
+            let (conn, addr) = match res {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    println!("accept failed: {:#}", e);
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+            let Ok(permit) = self.limit.clone().try_acquire_owned() else {
+                println!(
+                    "reject {}: {} connections open",
+                    addr, self.config.max_connections
+                );
+                continue;
+            };
+            let torrents = self.torrents.clone();
+            let config = self.config.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+                if let Err(e) = handle(conn, addr, torrents, config).await {
+                    println!("incoming peer {}: {:#}", addr, e);
+                }
+            });
+        }
+    }
+}
+
+async fn handle(
+    conn: PeerStream,
+    addr: SocketAddr,
+    torrents: TorrentTable,
+    config: ListenerConfig,
+) -> Result<()> {
+    let (conn, theirs) = tokio::time::timeout(
+        config.handshake_timeout,
+        read_handshake(conn, &torrents, config.encryption),
+    )
+    .await
+    .context("handshake timed out")??;
+    let engine = torrents
+        .get(&theirs.info_hash)
+        .with_context(|| format!("unknown info hash {}", hex::encode(theirs.info_hash)))?;
+    engine.accept(conn, addr, theirs).await
+}
+
+// negotiate encryption, then read the BitTorrent handshake
+async fn read_handshake(
+    conn: PeerStream,
+    torrents: &TorrentTable,
+    policy: EncryptionPolicy,
+) -> Result<(MseStream<PeerStream>, Handshake)> {
+    let info_hashes: Vec<[u8; 20]> = torrents.engines().iter().map(Engine::info_hash).collect();
+    let (mut conn, secret) = mse::accept(conn, &info_hashes, policy).await?;
+    let mut buf = [0u8; Handshake::SIZE];
+    conn.read_exact(&mut buf).await.context("read handshake")?;
+    let theirs = Handshake::from_bytes(&buf)?;
+    // an encrypted peer must ask for the torrent it used as the encryption secret
+    ensure!(
+        secret.is_none_or(|ih| ih == theirs.info_hash),
+        "handshake for another torrent than the encryption"
+    );
+    Ok((conn, theirs))
+}
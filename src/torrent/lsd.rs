@@ -0,0 +1,166 @@
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context, Result};
+use tokio::net::UdpSocket;
+
+use crate::torrent::listener::TorrentTable;
+use crate::torrent::random;
+
+/// multicast group and port of local service discovery (BEP 14)
+pub const MULTICAST_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), 6771);
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// info hashes per announce, keeps the datagram below 1400 bytes
+const MAX_HASHES: usize = 20;
+
+/// A `BT-SEARCH` announce: the sender serves these torrents on `port`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announce {
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+    /// tells our own announces apart when they loop back
+    pub cookie: Option<String>,
+}
+
+impl Announce {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut msg = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n",
+            MULTICAST_ADDR, self.port
+        );
+        for info_hash in &self.info_hashes {
+            msg.push_str(&format!("Infohash: {}\r\n", hex::encode(info_hash)));
+        }
+        if let Some(cookie) = &self.cookie {
+            msg.push_str(&format!("cookie: {}\r\n", cookie));
+        }
+        msg.push_str("\r\n\r\n");
+        msg.into_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let text = std::str::from_utf8(bytes).context("announce is not utf-8")?;
+        let mut lines = text.split("\r\n");
+        ensure!(
+            lines.next() == Some("BT-SEARCH * HTTP/1.1"),
+            "not a BT-SEARCH announce"
+        );
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+        for line in lines.take_while(|l| !l.is_empty()) {
+            let Some((name, value)) = line.split_once(':') else {
+                bail!("invalid header {:?}", line);
+            };
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = Some(value.parse().context("invalid port")?),
+                "infohash" => {
+                    let bytes = hex::decode(value).context("invalid info hash")?;
+                    let info_hash = <[u8; 20]>::try_from(bytes.as_slice())
+                        .ok()
+                        .context("info hash is not 20 bytes")?;
+                    info_hashes.push(info_hash);
+                }
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        Ok(Self {
+            port: port.context("announce without port")?,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+/// Local service discovery: announces the torrents of a table to the LAN and adds the
+/// peers announcing the same torrents. Private torrents are neither announced nor joined.
+pub struct Lsd {
+    socket: UdpSocket,
+    port: u16,
+    cookie: String,
+}
+
+impl Lsd {
+    /// join the multicast group, `port` is where we accept peer connections. When another
+    /// program has the lsd port we announce from an ephemeral one, without hearing others.
+    pub async fn bind(port: u16) -> Result<Self> {
+        let addr = SocketAddr::from(([0, 0, 0, 0], MULTICAST_ADDR.port()));
+        let socket = match UdpSocket::bind(addr).await {
+            Ok(socket) => {
+                socket
+                    .join_multicast_v4(*MULTICAST_ADDR.ip(), Ipv4Addr::UNSPECIFIED)
+                    .context("join lsd multicast group")?;
+                socket
+            }
+            Err(e) => {
+                println!("lsd: cannot listen on {}, announce only: {}", addr, e);
+                UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0))).await?
+            }
+        };
+        Ok(Self {
+            socket,
+            port,
+            cookie: format!("{:016x}", random::next_u64()),
+        })
+    }
+
+    /// announce every `ANNOUNCE_INTERVAL` and hand discovered peers to their torrents
+    pub async fn run(self, torrents: TorrentTable) -> Result<()> {
+        let mut tick = tokio::time::interval(ANNOUNCE_INTERVAL);
+        let mut buf = vec![0u8; 1500];
+        loop {
+            tokio::select! {
+                _ = tick.tick() => self.announce(&torrents).await,
+                res = self.socket.recv_from(&mut buf) => {
+                    let (n, from) = res.context("receive lsd announce")?;
+                    self.on_announce(&buf[..n], from, &torrents);
+                }
+            }
+        }
+    }
+
+    async fn announce(&self, torrents: &TorrentTable) {
+        let info_hashes: Vec<[u8; 20]> = torrents
+            .engines()
+            .iter()
+            .filter(|e| !e.is_private())
+            .map(|e| e.info_hash())
+            .collect();
+        for chunk in info_hashes.chunks(MAX_HASHES) {
+            let announce = Announce {
+                port: self.port,
+                info_hashes: chunk.to_vec(),
+                cookie: Some(self.cookie.clone()),
+            };
+            if let Err(e) = self
+                .socket
+                .send_to(&announce.to_bytes(), MULTICAST_ADDR)
+                .await
+            {
+                println!("lsd: announce failed: {}", e);
+                return;
+            }
+        }
+    }
+
+    fn on_announce(&self, bytes: &[u8], from: SocketAddr, torrents: &TorrentTable) {
+        let Ok(announce) = Announce::from_bytes(bytes) else {
+            return;
+        };
+        if announce.cookie.as_deref() == Some(self.cookie.as_str()) {
+            return;
+        }
+        let peer = SocketAddr::new(from.ip(), announce.port);
+        for info_hash in &announce.info_hashes {
+            match torrents.get(info_hash) {
+                Some(engine) if !engine.is_private() => {
+                    println!("lsd: found peer {} for {}", peer, hex::encode(info_hash));
+                    engine.add_peers([peer]);
+                }
+                _ => {}
+            }
+        }
+    }
+}
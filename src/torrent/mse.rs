@@ -0,0 +1,565 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context as TaskContext, Poll};
+
+use anyhow::{bail, ensure, Context, Result};
+use bytes::{Buf, BytesMut};
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::torrent::mse::bignum::{ModNum, KEY_SIZE};
+use crate::torrent::mse::rc4::Rc4;
+use crate::torrent::random;
+
+pub mod bignum;
+pub mod rc4;
+
+/// crypto methods of the MSE handshake
+pub const CRYPTO_PLAINTEXT: u32 = 0x01;
+pub const CRYPTO_RC4: u32 = 0x02;
+// random padding after the public keys and inside the handshake
+const MAX_PAD: usize = 512;
+// verification constant, eight zero bytes
+const VC: [u8; 8] = [0; 8];
+const PROTOCOL: &[u8; 20] = b"\x13BitTorrent protocol";
+
+/// Whether peer connections use Message Stream Encryption.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EncryptionPolicy {
+    /// plain BitTorrent handshakes, encrypted connections are refused
+    #[default]
+    PlaintextOnly,
+    /// start with MSE and fall back to plaintext, accept both
+    PreferEncrypted,
+    /// only RC4 encrypted connections
+    RequireEncrypted,
+}
+
+impl EncryptionPolicy {
+    /// `plaintext`, `prefer` or `require`
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "plaintext" => Ok(Self::PlaintextOnly),
+            "prefer" => Ok(Self::PreferEncrypted),
+            "require" => Ok(Self::RequireEncrypted),
+            _ => bail!("unknown encryption policy {}", s),
+        }
+    }
+
+    // the crypto methods we offer when we connect
+    fn provide(self) -> u32 {
+        match self {
+            Self::RequireEncrypted => CRYPTO_RC4,
+            _ => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+        }
+    }
+
+    // our pick of the methods a connecting peer offers, RC4 whenever possible
+    fn select(self, provided: u32) -> Option<u32> {
+        if provided & CRYPTO_RC4 != 0 {
+            Some(CRYPTO_RC4)
+        } else if provided & CRYPTO_PLAINTEXT != 0 && self != Self::RequireEncrypted {
+            Some(CRYPTO_PLAINTEXT)
+        } else {
+            None
+        }
+    }
+}
+
+// the first 20 bytes of a connection start a plain BitTorrent handshake, otherwise they
+// are the start of an MSE public key
+fn is_plaintext_handshake(first: &[u8]) -> bool {
+    first.starts_with(PROTOCOL)
+}
+
+/// Run the MSE handshake as the connecting side, the torrent's info hash is the shared
+/// secret. The returned stream is RC4 encrypted unless the peer picked plaintext.
+pub async fn initiate<S>(
+    mut stream: S,
+    info_hash: &[u8; 20],
+    policy: EncryptionPolicy,
+) -> Result<MseStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (private, public) = key_pair()?;
+    let mut out = public.to_vec();
+    out.extend(random_pad()?);
+    stream.write_all(&out).await.context("write public key")?;
+
+    let mut buf = Vec::new();
+    let theirs = take(&mut stream, &mut buf, KEY_SIZE).await?;
+    let secret = shared_secret(&theirs, &private);
+    let mut encrypt = Rc4::for_mse(&hash(&[b"keyA", &secret, info_hash]));
+    let mut decrypt = Rc4::for_mse(&hash(&[b"keyB", &secret, info_hash]));
+
+    let mut msg = hash(&[b"req1", &secret]).to_vec();
+    msg.extend(xor(
+        &hash(&[b"req2", info_hash]),
+        &hash(&[b"req3", &secret]),
+    ));
+    let mut plain = VC.to_vec();
+    plain.extend(policy.provide().to_be_bytes());
+    // no padding and no initial payload, the BitTorrent handshake follows on the stream
+    plain.extend(0u16.to_be_bytes());
+    plain.extend(0u16.to_be_bytes());
+    encrypt.apply(&mut plain);
+    msg.extend(plain);
+    stream.write_all(&msg).await.context("write crypto offer")?;
+
+    // the peer's padding ends where its encrypted VC starts
+    let mut vc = VC;
+    decrypt.apply(&mut vc);
+    sync(&mut stream, &mut buf, &vc).await?;
+    let mut head = take(&mut stream, &mut buf, 6).await?;
+    decrypt.apply(&mut head);
+    let select = u32::from_be_bytes(head[..4].try_into().unwrap());
+    let pad = u16::from_be_bytes([head[4], head[5]]) as usize;
+    ensure!(pad <= MAX_PAD, "padding of {} bytes", pad);
+    let mut pad = take(&mut stream, &mut buf, pad).await?;
+    decrypt.apply(&mut pad);
+    ensure!(
+        (select == CRYPTO_RC4 || select == CRYPTO_PLAINTEXT) && policy.provide() & select != 0,
+        "peer selected crypto method {}",
+        select
+    );
+    let ciphers = (select == CRYPTO_RC4).then_some(Ciphers { encrypt, decrypt });
+    Ok(MseStream::new(stream, Vec::new(), buf, ciphers))
+}
+
+/// Run the MSE handshake as the accepting side; `received` are the bytes read already,
+/// `info_hashes` the torrents we serve. Returns the stream and the torrent the peer asked
+/// for.
+pub async fn respond<S>(
+    mut stream: S,
+    received: Vec<u8>,
+    info_hashes: &[[u8; 20]],
+    policy: EncryptionPolicy,
+) -> Result<(MseStream<S>, [u8; 20])>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = received;
+    let theirs = take(&mut stream, &mut buf, KEY_SIZE).await?;
+    let (private, public) = key_pair()?;
+    let mut out = public.to_vec();
+    out.extend(random_pad()?);
+    stream.write_all(&out).await.context("write public key")?;
+    let secret = shared_secret(&theirs, &private);
+
+    // the peer's padding ends where its first hash starts
+    sync(&mut stream, &mut buf, &hash(&[b"req1", &secret])).await?;
+    let obfuscated = take(&mut stream, &mut buf, 20).await?;
+    let req3 = hash(&[b"req3", &secret]);
+    let info_hash = *info_hashes
+        .iter()
+        .find(|ih| xor(&hash(&[b"req2", ih.as_slice()]), &req3) == obfuscated)
+        .context("encrypted connection for an unknown torrent")?;
+    let mut decrypt = Rc4::for_mse(&hash(&[b"keyA", &secret, &info_hash]));
+    let mut encrypt = Rc4::for_mse(&hash(&[b"keyB", &secret, &info_hash]));
+
+    let mut head = take(&mut stream, &mut buf, 14).await?;
+    decrypt.apply(&mut head);
+    ensure!(head[..8] == VC, "invalid verification constant");
+    let provide = u32::from_be_bytes(head[8..12].try_into().unwrap());
+    let pad = u16::from_be_bytes([head[12], head[13]]) as usize;
+    ensure!(pad <= MAX_PAD, "padding of {} bytes", pad);
+    let mut pad = take(&mut stream, &mut buf, pad).await?;
+    decrypt.apply(&mut pad);
+    let mut len = take(&mut stream, &mut buf, 2).await?;
+    decrypt.apply(&mut len);
+    let mut initial = take(
+        &mut stream,
+        &mut buf,
+        u16::from_be_bytes([len[0], len[1]]) as usize,
+    )
+    .await?;
+    decrypt.apply(&mut initial);
+
+    let select = policy
+        .select(provide)
+        .with_context(|| format!("no acceptable crypto method in {}", provide))?;
+    let mut resp = VC.to_vec();
+    resp.extend(select.to_be_bytes());
+    resp.extend(0u16.to_be_bytes());
+    encrypt.apply(&mut resp);
+    stream
+        .write_all(&resp)
+        .await
+        .context("write crypto select")?;
+
+    let ciphers = (select == CRYPTO_RC4).then_some(Ciphers { encrypt, decrypt });
+    Ok((MseStream::new(stream, initial, buf, ciphers), info_hash))
+}
+
+/// Accept a connection as `policy` allows, telling plain BitTorrent handshakes from MSE
+/// ones by their first bytes. `info_hashes` are the torrents we serve, an encrypted peer
+/// picks one of them and it is returned.
+pub async fn accept<S>(
+    mut stream: S,
+    info_hashes: &[[u8; 20]],
+    policy: EncryptionPolicy,
+) -> Result<(MseStream<S>, Option<[u8; 20]>)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut first = [0u8; 20];
+    stream
+        .read_exact(&mut first)
+        .await
+        .context("read handshake")?;
+    if is_plaintext_handshake(&first) {
+        ensure!(
+            policy != EncryptionPolicy::RequireEncrypted,
+            "plaintext connection refused"
+        );
+        return Ok((MseStream::plaintext(stream, first.to_vec()), None));
+    }
+    ensure!(
+        policy != EncryptionPolicy::PlaintextOnly,
+        "encrypted connection refused"
+    );
+    let (stream, info_hash) = respond(stream, first.to_vec(), info_hashes, policy).await?;
+    Ok((stream, Some(info_hash)))
+}
+
+struct Ciphers {
+    encrypt: Rc4,
+    decrypt: Rc4,
+}
+
+/// A peer connection after the MSE handshake, encrypting and decrypting with RC4 when
+/// that was negotiated and passing bytes through otherwise.
+pub struct MseStream<S> {
+    inner: S,
+    // received ahead of the reader during the handshake, plaintext
+    pending: BytesMut,
+    ciphers: Option<Ciphers>,
+    // encrypted bytes the inner stream didn't take yet
+    out: Vec<u8>,
+    written: usize,
+}
+
+impl<S> MseStream<S> {
+    /// an unencrypted connection whose first `received` bytes were read already
+    pub fn plaintext(inner: S, received: Vec<u8>) -> Self {
+        Self::new(inner, received, Vec::new(), None)
+    }
+
+    // `plain` bytes come first, then the `raw` ones left over from the handshake
+    fn new(inner: S, plain: Vec<u8>, mut raw: Vec<u8>, mut ciphers: Option<Ciphers>) -> Self {
+        if let Some(c) = &mut ciphers {
+            c.decrypt.apply(&mut raw);
+        }
+        let mut pending = BytesMut::from(plain.as_slice());
+        pending.extend_from_slice(&raw);
+        Self {
+            inner,
+            pending,
+            ciphers,
+            out: Vec::new(),
+            written: 0,
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.ciphers.is_some()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncWrite + Unpin> MseStream<S> {
+    fn poll_drain(&mut self, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.out.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.out[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.out.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MseStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.pending.is_empty() {
+            let n = this.pending.len().min(buf.remaining());
+            buf.put_slice(&this.pending[..n]);
+            this.pending.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(c) = &mut this.ciphers {
+            c.decrypt.apply(&mut buf.filled_mut()[before..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MseStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.ciphers.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, data);
+        }
+        ready!(this.poll_drain(cx))?;
+        // the keystream moves on as we encrypt, so the bytes are ours from here on and
+        // whatever the inner stream doesn't take now goes out on the next write or flush
+        let mut out = data.to_vec();
+        if let Some(c) = &mut this.ciphers {
+            c.encrypt.apply(&mut out);
+        }
+        this.out = out;
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+// a random private key and its public key
+fn key_pair() -> Result<([u8; 20], [u8; KEY_SIZE])> {
+    let mut private = [0u8; 20];
+    random::os_fill(&mut private)?;
+    Ok((private, ModNum::public_key(&private).to_be_bytes()))
+}
+
+fn shared_secret(theirs: &[u8], private: &[u8; 20]) -> [u8; KEY_SIZE] {
+    let theirs: &[u8; KEY_SIZE] = theirs.try_into().unwrap();
+    ModNum::from_be_bytes(theirs).pow(private).to_be_bytes()
+}
+
+fn random_pad() -> Result<Vec<u8>> {
+    let mut len = [0u8; 2];
+    random::os_fill(&mut len)?;
+    let mut pad = vec![0u8; u16::from_be_bytes(len) as usize % (MAX_PAD + 1)];
+    random::os_fill(&mut pad)?;
+    Ok(pad)
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn xor(a: &[u8; 20], b: &[u8; 20]) -> Vec<u8> {
+    a.iter().zip(b).map(|(x, y)| x ^ y).collect()
+}
+
+// the first `n` bytes of the connection after those in `buf`
+async fn take<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    n: usize,
+) -> Result<Vec<u8>> {
+    while buf.len() < n {
+        read_more(stream, buf).await?;
+    }
+    Ok(buf.drain(..n).collect())
+}
+
+// skip the peer's random padding up to and including `marker`
+async fn sync<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    marker: &[u8],
+) -> Result<()> {
+    loop {
+        if let Some(pos) = buf.windows(marker.len()).position(|w| w == marker) {
+            buf.drain(..pos + marker.len());
+            return Ok(());
+        }
+        ensure!(
+            buf.len() < MAX_PAD + marker.len(),
+            "no encryption handshake within {} bytes",
+            MAX_PAD
+        );
+        read_more(stream, buf).await?;
+    }
+}
+
+async fn read_more<S: AsyncRead + Unpin>(stream: &mut S, buf: &mut Vec<u8>) -> Result<()> {
+    let mut chunk = [0u8; 1024];
+    let n = stream
+        .read(&mut chunk)
+        .await
+        .context("read encryption handshake")?;
+    ensure!(
+        n > 0,
+        "peer closed the connection during the encryption handshake"
+    );
+    buf.extend_from_slice(&chunk[..n]);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, DuplexStream};
+
+    use super::*;
+    use crate::torrent::mse::EncryptionPolicy::*;
+
+    const INFO_HASH: [u8; 20] = [7; 20];
+    const POLICIES: [EncryptionPolicy; 3] = [PlaintextOnly, PreferEncrypted, RequireEncrypted];
+
+    type Pair = (MseStream<DuplexStream>, MseStream<DuplexStream>);
+
+    // one connection attempt, the initiator as `peer::connect_encrypted` makes it
+    async fn attempt(
+        initiator: EncryptionPolicy,
+        responder: EncryptionPolicy,
+        plaintext: bool,
+    ) -> Result<Pair> {
+        let (ours, theirs) = duplex(4096);
+        let accepted = tokio::spawn(async move { accept(theirs, &[INFO_HASH], responder).await });
+        let connected = if plaintext {
+            // the handshake goes out right after connecting
+            let mut conn = MseStream::plaintext(ours, Vec::new());
+            conn.write_all(PROTOCOL).await?;
+            Ok(conn)
+        } else {
+            initiate(ours, &INFO_HASH, initiator).await
+        };
+        let accepted = accepted.await.unwrap();
+        let (mut accepted, info_hash) = accepted?;
+        let conn = connected?;
+        assert_eq!(info_hash.is_some(), !plaintext);
+        if plaintext {
+            let mut first = [0u8; 20];
+            accepted.read_exact(&mut first).await?;
+            assert_eq!(&first, PROTOCOL);
+        }
+        Ok((conn, accepted))
+    }
+
+    // both sides, with a plaintext retry when encryption is only preferred
+    async fn connect(initiator: EncryptionPolicy, responder: EncryptionPolicy) -> Result<Pair> {
+        if initiator == PlaintextOnly {
+            return attempt(initiator, responder, true).await;
+        }
+        match attempt(initiator, responder, false).await {
+            Err(_) if initiator == PreferEncrypted => attempt(initiator, responder, true).await,
+            res => res,
+        }
+    }
+
+    async fn exchange(a: &mut MseStream<DuplexStream>, b: &mut MseStream<DuplexStream>) {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7 + i / 257) as u8).collect();
+        let sent = data.clone();
+        let write = async {
+            a.write_all(&sent).await.unwrap();
+            // encrypted bytes wait in the stream until flushed
+            a.flush().await.unwrap();
+        };
+        let (_, received) = tokio::join!(write, async {
+            let mut received = vec![0u8; data.len()];
+            b.read_exact(&mut received).await.unwrap();
+            received
+        });
+        assert_eq!(received, data);
+        b.write_all(b"and back").await.unwrap();
+        b.flush().await.unwrap();
+        let mut back = [0u8; 8];
+        a.read_exact(&mut back).await.unwrap();
+        assert_eq!(&back, b"and back");
+    }
+
+    #[tokio::test]
+    async fn connects_for_every_policy_pair() {
+        for initiator in POLICIES {
+            for responder in POLICIES {
+                let res = connect(initiator, responder).await;
+                let encrypted = initiator != PlaintextOnly && responder != PlaintextOnly;
+                let refused = (initiator == RequireEncrypted && responder == PlaintextOnly)
+                    || (initiator == PlaintextOnly && responder == RequireEncrypted);
+                let case = format!("{:?} to {:?}", initiator, responder);
+                if refused {
+                    assert!(res.is_err(), "{}", case);
+                    continue;
+                }
+                let (mut a, mut b) = res.unwrap_or_else(|e| panic!("{}: {:#}", case, e));
+                assert_eq!(a.is_encrypted(), encrypted, "{}", case);
+                assert_eq!(b.is_encrypted(), encrypted, "{}", case);
+                exchange(&mut a, &mut b).await;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_unknown_torrents() {
+        let (ours, theirs) = duplex(4096);
+        let accepted =
+            tokio::spawn(async move { accept(theirs, &[[1; 20]], PreferEncrypted).await });
+        let connected = initiate(ours, &INFO_HASH, PreferEncrypted).await;
+        let err = accepted.await.unwrap().err().unwrap();
+        assert!(
+            format!("{:#}", err).contains("unknown torrent"),
+            "{:#}",
+            err
+        );
+        assert!(connected.is_err());
+    }
+
+    #[test]
+    fn selects_rc4_whenever_offered() {
+        let both = CRYPTO_RC4 | CRYPTO_PLAINTEXT;
+        assert_eq!(PreferEncrypted.select(both), Some(CRYPTO_RC4));
+        assert_eq!(
+            PreferEncrypted.select(CRYPTO_PLAINTEXT),
+            Some(CRYPTO_PLAINTEXT)
+        );
+        assert_eq!(RequireEncrypted.select(CRYPTO_PLAINTEXT), None);
+        assert_eq!(RequireEncrypted.select(both), Some(CRYPTO_RC4));
+        assert_eq!(PreferEncrypted.select(0), None);
+        assert_eq!(RequireEncrypted.provide(), CRYPTO_RC4);
+        assert_eq!(PreferEncrypted.provide(), both);
+    }
+
+    #[test]
+    fn both_sides_derive_the_same_secret() {
+        let (a_private, a_public) = key_pair().unwrap();
+        let (b_private, b_public) = key_pair().unwrap();
+        assert_ne!(a_public, b_public);
+        let secret = shared_secret(&b_public, &a_private);
+        assert_eq!(secret, shared_secret(&a_public, &b_private));
+        assert_ne!(secret, [0u8; KEY_SIZE]);
+    }
+
+    #[test]
+    fn pads_up_to_max_pad() {
+        for _ in 0..20 {
+            assert!(random_pad().unwrap().len() <= MAX_PAD);
+        }
+    }
+}
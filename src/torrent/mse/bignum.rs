@@ -0,0 +1,181 @@
+use std::cmp::Ordering;
+
+/// bytes of a key, the size of the MSE prime
+pub const KEY_SIZE: usize = 96;
+const LIMBS: usize = KEY_SIZE / 8;
+
+/// the 768 bit prime of the MSE key exchange, big endian
+const PRIME: [u8; KEY_SIZE] = [
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xC9, 0x0F, 0xDA, 0xA2, 0x21, 0x68, 0xC2, 0x34,
+    0xC4, 0xC6, 0x62, 0x8B, 0x80, 0xDC, 0x1C, 0xD1, 0x29, 0x02, 0x4E, 0x08, 0x8A, 0x67, 0xCC, 0x74,
+    0x02, 0x0B, 0xBE, 0xA6, 0x3B, 0x13, 0x9B, 0x22, 0x51, 0x4A, 0x08, 0x79, 0x8E, 0x34, 0x04, 0xDD,
+    0xEF, 0x95, 0x19, 0xB3, 0xCD, 0x3A, 0x43, 0x1B, 0x30, 0x2B, 0x0A, 0x6D, 0xF2, 0x5F, 0x14, 0x37,
+    0x4F, 0xE1, 0x35, 0x6D, 0x6D, 0x51, 0xC2, 0x45, 0xE4, 0x85, 0xB5, 0x76, 0x62, 0x5E, 0x7E, 0xC6,
+    0xF4, 0x4C, 0x42, 0xE9, 0xA6, 0x3A, 0x36, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x05, 0x63,
+];
+const GENERATOR: u64 = 2;
+
+/// A number modulo the MSE prime, as little endian 64 bit limbs. Only what the
+/// Diffie-Hellman exchange needs; speed doesn't matter for two exponentiations per
+/// connection, so multiplication is plain double-and-add.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModNum([u64; LIMBS]);
+
+impl ModNum {
+    /// a big endian number, reduced modulo the prime
+    pub fn from_be_bytes(bytes: &[u8; KEY_SIZE]) -> Self {
+        let mut limbs = [0u64; LIMBS];
+        for (i, chunk) in bytes.rchunks_exact(8).enumerate() {
+            limbs[i] = u64::from_be_bytes(chunk.try_into().unwrap());
+        }
+        let mut n = ModNum(limbs);
+        // the prime has its top bit set, so one subtraction reduces any 768 bit number
+        if n.cmp_limbs(&prime()) != Ordering::Less {
+            n.sub_assign(&prime());
+        }
+        n
+    }
+
+    pub fn to_be_bytes(self) -> [u8; KEY_SIZE] {
+        let mut bytes = [0u8; KEY_SIZE];
+        for (i, chunk) in bytes.rchunks_exact_mut(8).enumerate() {
+            chunk.copy_from_slice(&self.0[i].to_be_bytes());
+        }
+        bytes
+    }
+
+    /// our public key for private key `exp`
+    pub fn public_key(exp: &[u8]) -> Self {
+        let mut g = [0u64; LIMBS];
+        g[0] = GENERATOR;
+        ModNum(g).pow(exp)
+    }
+
+    /// self ^ exp, `exp` big endian
+    pub fn pow(self, exp: &[u8]) -> Self {
+        let mut result = ModNum::one();
+        for byte in exp {
+            for bit in (0..8).rev() {
+                result = result.mul(&result);
+                if byte >> bit & 1 == 1 {
+                    result = result.mul(&self);
+                }
+            }
+        }
+        result
+    }
+
+    fn one() -> Self {
+        let mut limbs = [0u64; LIMBS];
+        limbs[0] = 1;
+        ModNum(limbs)
+    }
+
+    fn mul(&self, other: &ModNum) -> ModNum {
+        let mut result = ModNum([0; LIMBS]);
+        for limb in other.0.iter().rev() {
+            for bit in (0..64).rev() {
+                result = result.add(&result);
+                if limb >> bit & 1 == 1 {
+                    result = result.add(self);
+                }
+            }
+        }
+        result
+    }
+
+    fn add(&self, other: &ModNum) -> ModNum {
+        let mut sum = [0u64; LIMBS];
+        let mut carry = false;
+        for (i, limb) in sum.iter_mut().enumerate() {
+            let (s, c1) = self.0[i].overflowing_add(other.0[i]);
+            let (s, c2) = s.overflowing_add(carry as u64);
+            *limb = s;
+            carry = c1 || c2;
+        }
+        let mut sum = ModNum(sum);
+        // both are below the prime, so the sum is below twice the prime
+        if carry || sum.cmp_limbs(&prime()) != Ordering::Less {
+            sum.sub_assign(&prime());
+        }
+        sum
+    }
+
+    // wrapping subtraction, callers make sure the result isn't negative
+    fn sub_assign(&mut self, other: &ModNum) {
+        let mut borrow = false;
+        for i in 0..LIMBS {
+            let (d, b1) = self.0[i].overflowing_sub(other.0[i]);
+            let (d, b2) = d.overflowing_sub(borrow as u64);
+            self.0[i] = d;
+            borrow = b1 || b2;
+        }
+    }
+
+    fn cmp_limbs(&self, other: &ModNum) -> Ordering {
+        self.0.iter().rev().cmp(other.0.iter().rev())
+    }
+}
+
+fn prime() -> ModNum {
+    let mut limbs = [0u64; LIMBS];
+    for (i, chunk) in PRIME.rchunks_exact(8).enumerate() {
+        limbs[i] = u64::from_be_bytes(chunk.try_into().unwrap());
+    }
+    ModNum(limbs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_hex(s: &str) -> ModNum {
+        ModNum::from_be_bytes(&hex::decode(s).unwrap().try_into().unwrap())
+    }
+
+    fn small(n: u64) -> ModNum {
+        let mut limbs = [0u64; LIMBS];
+        limbs[0] = n;
+        ModNum(limbs)
+    }
+
+    #[test]
+    fn raises_the_generator() {
+        assert_eq!(ModNum::public_key(&[0]), small(1));
+        assert_eq!(ModNum::public_key(&[1]), small(2));
+        assert_eq!(ModNum::public_key(&[10]), small(1024));
+        assert_eq!(ModNum::public_key(&[0, 63]), small(1 << 63));
+    }
+
+    #[test]
+    fn reduces_modulo_the_prime() {
+        assert_eq!(ModNum::from_be_bytes(&PRIME), small(0));
+        let mut above = PRIME;
+        above[KEY_SIZE - 1] += 5;
+        assert_eq!(ModNum::from_be_bytes(&above), small(5));
+        assert_eq!(ModNum::from_be_bytes(&above).to_be_bytes()[KEY_SIZE - 1], 5);
+    }
+
+    // reference values from Python's pow(2, a, p) and pow(B, a, p)
+    #[test]
+    fn matches_reference_key_exchange() {
+        let a: Vec<u8> = (1..=20).collect();
+        let b: Vec<u8> = (0xa0..=0xb3).collect();
+        let a_public = ModNum::public_key(&a);
+        assert_eq!(
+            a_public,
+            from_hex(
+                "96e112dab29e8c5272accb9b17b26887ce54a144a4e3b697c7d159b7a817e556b0918db2b4c658e0\
+                 2a87f7e5fb14b18a553e084cbf3dad2d30f16596ccb982d406258c61b30c5c1dae2ddc60bdbd48d7\
+                 9896312aad63238c39e1a633821eb693"
+            )
+        );
+        let secret = from_hex(
+            "1aea23a0431eeac96cfe444068c2674f97b4ac97054382d31445f162f8b1e576cf94207839779de0\
+             a37f42501cf321226af0d346b0b7cdb8ff4a0123b228b38dc7e696ead6b6f17264c28d21f1dc7453\
+             6a94993f7943401a06e4b2f99febf21a",
+        );
+        assert_eq!(ModNum::public_key(&b).pow(&a), secret);
+        assert_eq!(a_public.pow(&b), secret);
+    }
+}
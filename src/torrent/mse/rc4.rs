@@ -0,0 +1,81 @@
+/// RC4 keystream, MSE uses it with SHA-1 derived keys and drops the first 1024 bytes.
+#[derive(Clone)]
+pub struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut s = [0u8; 256];
+        for (i, b) in s.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+        Self { s, i: 0, j: 0 }
+    }
+
+    /// the MSE variant: keyed and with the first 1024 bytes of keystream discarded
+    pub fn for_mse(key: &[u8]) -> Self {
+        let mut rc4 = Self::new(key);
+        rc4.apply(&mut [0u8; 1024]);
+        rc4
+    }
+
+    /// xor `data` with the next keystream bytes, encrypts and decrypts alike
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for b in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+            let k = self.s[self.s[self.i as usize].wrapping_add(self.s[self.j as usize]) as usize];
+            *b ^= k;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keystream(mut rc4: Rc4, len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        rc4.apply(&mut data);
+        data
+    }
+
+    #[test]
+    fn encrypts_known_answers() {
+        let cases: [(&[u8], &[u8], &str); 3] = [
+            (b"Key", b"Plaintext", "bbf316e8d940af0ad3"),
+            (b"Wiki", b"pedia", "1021bf0420"),
+            (b"Secret", b"Attack at dawn", "45a01f645fc35b383552544b9bf5"),
+        ];
+        for (key, plain, cipher) in cases {
+            let mut data = plain.to_vec();
+            Rc4::new(key).apply(&mut data);
+            assert_eq!(hex::encode(&data), cipher);
+            Rc4::new(key).apply(&mut data);
+            assert_eq!(data, plain);
+        }
+    }
+
+    // RFC 6229, the 40 bit key at offsets 0 and 1024
+    #[test]
+    fn drops_the_first_1024_bytes_for_mse() {
+        let key = [1, 2, 3, 4, 5];
+        assert_eq!(
+            hex::encode(keystream(Rc4::new(&key), 16)),
+            "b2396305f03dc027ccc3524a0a1118a8"
+        );
+        assert_eq!(
+            hex::encode(keystream(Rc4::for_mse(&key), 16)),
+            "30abbcc7c20b01609f23ee2d5f6bb7df"
+        );
+    }
+}
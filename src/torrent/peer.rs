@@ -0,0 +1,246 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::torrent::handeshake::Handshake;
+use crate::torrent::mse;
+use crate::torrent::mse::{EncryptionPolicy, MseStream};
+use crate::torrent::proxy::ProxyConfig;
+use crate::torrent::transport::{PeerStream, TcpTransport, Transport};
+use crate::utp::UtpSocket;
+
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// how long we stay interested in a peer that keeps us choked
+pub const UNCHOKE_TIMEOUT: Duration = Duration::from_secs(60);
+/// how long a peer we connected to gets to answer our handshake
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// a peer sending nothing, not even a keep-alive, for this long is dropped
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(150);
+/// how often we send a keep-alive, well within the idle timeout of other clients
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+
+/// How outgoing peer connections are made.
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    pub proxy: Option<ProxyConfig>,
+    pub timeout: Duration,
+    pub encryption: EncryptionPolicy,
+    pub transport: TransportPreference,
+    /// socket for uTP connections, without one all connections are tcp
+    pub utp: Option<UtpSocket>,
+    /// connect through this transport only instead, e.g. an in-memory network
+    pub custom: Option<Arc<dyn Transport>>,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self {
+            proxy: None,
+            timeout: CONNECT_TIMEOUT,
+            encryption: EncryptionPolicy::default(),
+            transport: TransportPreference::default(),
+            utp: None,
+            custom: None,
+        }
+    }
+}
+
+impl ConnectOptions {
+    /// transports to try in order for an outgoing connection. Peers are reached through a
+    /// tunneling proxy by tcp only.
+    pub fn transports(&self) -> Vec<Arc<dyn Transport>> {
+        if let Some(custom) = &self.custom {
+            return vec![custom.clone()];
+        }
+        let tcp: Arc<dyn Transport> = Arc::new(TcpTransport {
+            proxy: self.proxy.clone(),
+        });
+        let tunneled = self.proxy.as_ref().is_some_and(|p| p.tunnels_peers());
+        let utp: Arc<dyn Transport> = match &self.utp {
+            Some(utp) if !tunneled => Arc::new(utp.clone()),
+            _ => return vec![tcp],
+        };
+        match self.transport {
+            TransportPreference::TcpOnly => vec![tcp],
+            TransportPreference::PreferTcp => vec![tcp, utp],
+            TransportPreference::PreferUtp => vec![utp, tcp],
+            TransportPreference::UtpOnly => vec![utp],
+        }
+    }
+}
+
+/// Which transport outgoing connections use, the preferred one is tried first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TransportPreference {
+    #[default]
+    TcpOnly,
+    PreferTcp,
+    PreferUtp,
+    UtpOnly,
+}
+
+impl TransportPreference {
+    /// `tcp`, `prefer-tcp`, `prefer-utp` or `utp`
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "tcp" => Ok(Self::TcpOnly),
+            "prefer-tcp" => Ok(Self::PreferTcp),
+            "prefer-utp" => Ok(Self::PreferUtp),
+            "utp" => Ok(Self::UtpOnly),
+            _ => anyhow::bail!("unknown transport {}", s),
+        }
+    }
+}
+
+/// connection to a peer over the first of `opts.transports()` that reaches it
+pub async fn connect_stream(peer: SocketAddr, opts: &ConnectOptions) -> Result<PeerStream> {
+    let transports = opts.transports();
+    let (last, first) = transports.split_last().context("no transport")?;
+    for transport in first {
+        match connect_with(transport.as_ref(), peer, opts.timeout).await {
+            Ok(conn) => return Ok(conn),
+            Err(e) => println!("{:#}, trying the next transport", e),
+        }
+    }
+    connect_with(last.as_ref(), peer, opts.timeout).await
+}
+
+async fn connect_with(
+    transport: &dyn Transport,
+    peer: SocketAddr,
+    timeout: Duration,
+) -> Result<PeerStream> {
+    tokio::time::timeout(timeout, transport.connect(peer))
+        .await
+        .map_err(|_| anyhow!("connect to {} timed out", peer))?
+}
+
+/// connect to `peer` and negotiate encryption for torrent `info_hash` as `opts.encryption`
+/// asks for. When encryption is only preferred, peers failing the MSE handshake are
+/// connected to again in plaintext.
+pub async fn connect_encrypted(
+    peer: SocketAddr,
+    opts: &ConnectOptions,
+    info_hash: &[u8; 20],
+) -> Result<MseStream<PeerStream>> {
+    let conn = connect_stream(peer, opts).await?;
+    if opts.encryption == EncryptionPolicy::PlaintextOnly {
+        return Ok(MseStream::plaintext(conn, Vec::new()));
+    }
+    let encrypted = tokio::time::timeout(opts.timeout, mse::initiate(conn, info_hash, opts.encryption))
+        .await
+        .map_err(|_| anyhow!("encryption handshake timed out"))
+        .and_then(|res| res);
+    match encrypted {
+        Err(e) if opts.encryption == EncryptionPolicy::PreferEncrypted => {
+            println!("encryption with {} failed, retry in plaintext: {:#}", peer, e);
+            Ok(MseStream::plaintext(connect_stream(peer, opts).await?, Vec::new()))
+        }
+        res => res,
+    }
+}
+
+/// send our handshake and read the peer's, which must be for the same torrent
+pub async fn handshake<S>(conn: &mut S, ours: &Handshake) -> Result<Handshake>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    conn.write_all(&ours.to_bytes())
+        .await
+        .context("write handshake")?;
+    let mut resp = [0u8; Handshake::SIZE];
+    conn.read_exact(&mut resp)
+        .await
+        .context("read handshake")?;
+    let theirs = Handshake::from_bytes(&resp)?;
+    anyhow::ensure!(
+        theirs.info_hash == ours.info_hash,
+        "peer answered with a different info hash"
+    );
+    Ok(theirs)
+}
+
+/// Choke and interest flags of both ends of a connection. Connections start choked and
+/// not interested in both directions; the messages may arrive in any order.
+#[derive(Debug, Clone)]
+pub struct PeerState {
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+    // since when we are interested but choked
+    waiting_since: Option<Instant>,
+}
+
+impl Default for PeerState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PeerState {
+    pub fn new() -> Self {
+        Self {
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            waiting_since: None,
+        }
+    }
+
+    /// the peer choked us, outstanding requests are dropped on its side
+    pub fn on_choke(&mut self) {
+        self.peer_choking = true;
+        self.update_waiting();
+    }
+
+    pub fn on_unchoke(&mut self) {
+        self.peer_choking = false;
+        self.update_waiting();
+    }
+
+    pub fn on_interested(&mut self, interested: bool) {
+        self.peer_interested = interested;
+    }
+
+    /// change our interest, true if it changed and the peer must be told
+    pub fn set_interested(&mut self, interested: bool) -> bool {
+        if self.am_interested == interested {
+            return false;
+        }
+        self.am_interested = interested;
+        self.update_waiting();
+        true
+    }
+
+    /// change whether we choke the peer, true if it changed and the peer must be told
+    pub fn set_choking(&mut self, choking: bool) -> bool {
+        if self.am_choking == choking {
+            return false;
+        }
+        self.am_choking = choking;
+        true
+    }
+
+    /// we may send requests
+    pub fn can_request(&self) -> bool {
+        self.am_interested && !self.peer_choking
+    }
+
+    /// the time we give up on a peer which doesn't unchoke us
+    pub fn unchoke_deadline(&self, timeout: Duration) -> Option<Instant> {
+        self.waiting_since.map(|since| since + timeout)
+    }
+
+    fn update_waiting(&mut self) {
+        if self.am_interested && self.peer_choking {
+            self.waiting_since.get_or_insert_with(Instant::now);
+        } else {
+            self.waiting_since = None;
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::torrent::serde::peers::{self, Peer};
+
+/// name of the extension in the extension handshake (BEP 11)
+pub const EXTENSION_NAME: &str = "ut_pex";
+/// id we want to receive ut_pex messages with
+pub const LOCAL_ID: u8 = 1;
+/// time between two messages to the same peer
+pub const INTERVAL: Duration = Duration::from_secs(60);
+/// at most this many added and this many dropped peers per message
+pub const MAX_PEERS: usize = 50;
+// messages arriving faster than this from one peer are ignored
+const MIN_RECEIVE_INTERVAL: Duration = Duration::from_secs(45);
+
+pub const FLAG_ENCRYPTION: u8 = 0x01;
+pub const FLAG_SEED: u8 = 0x02;
+pub const FLAG_UTP: u8 = 0x04;
+pub const FLAG_HOLEPUNCH: u8 = 0x08;
+/// we connected to the peer, so it accepts incoming connections
+pub const FLAG_REACHABLE: u8 = 0x10;
+
+/// Payload of a ut_pex message: compact peers connected and disconnected since the last
+/// message, with one flag byte per added peer.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PexMessage {
+    #[serde(default)]
+    pub added: ByteBuf,
+    #[serde(rename = "added.f", default)]
+    pub added_f: ByteBuf,
+    #[serde(default, skip_serializing_if = "is_empty")]
+    pub added6: ByteBuf,
+    #[serde(rename = "added6.f", default, skip_serializing_if = "is_empty")]
+    pub added6_f: ByteBuf,
+    #[serde(default)]
+    pub dropped: ByteBuf,
+    #[serde(default, skip_serializing_if = "is_empty")]
+    pub dropped6: ByteBuf,
+}
+
+fn is_empty(bytes: &ByteBuf) -> bool {
+    bytes.is_empty()
+}
+
+impl PexMessage {
+    pub fn new(added: &[(SocketAddr, u8)], dropped: &[SocketAddr]) -> Self {
+        let (added4, added6): (Vec<_>, Vec<_>) = added.iter().partition(|(a, _)| a.is_ipv4());
+        let compact = |addrs: &[&(SocketAddr, u8)]| {
+            let peers: Vec<Peer> = addrs.iter().map(|(a, _)| Peer::from(*a)).collect();
+            (
+                ByteBuf::from(peers::to_compact(&peers)),
+                ByteBuf::from(peers::to_compact6(&peers)),
+            )
+        };
+        let flags = |addrs: &[&(SocketAddr, u8)]| {
+            ByteBuf::from(addrs.iter().map(|(_, f)| *f).collect::<Vec<u8>>())
+        };
+        let dropped: Vec<Peer> = dropped.iter().map(|a| Peer::from(*a)).collect();
+        Self {
+            added: compact(&added4).0,
+            added_f: flags(&added4),
+            added6: compact(&added6).1,
+            added6_f: flags(&added6),
+            dropped: ByteBuf::from(peers::to_compact(&dropped)),
+            dropped6: ByteBuf::from(peers::to_compact6(&dropped)),
+        }
+    }
+
+    pub fn from_bytes(payload: &[u8]) -> Result<Self> {
+        serde_bencode::from_bytes(payload).context("invalid ut_pex message")
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_bencode::to_bytes(self).expect("pex message is always encodable")
+    }
+
+    /// added peers with their flags, 0 where the flags are missing
+    pub fn added(&self) -> Vec<(SocketAddr, u8)> {
+        let with_flags = |addrs: Vec<SocketAddr>, flags: &ByteBuf| {
+            addrs
+                .into_iter()
+                .enumerate()
+                .map(|(i, a)| (a, flags.get(i).copied().unwrap_or(0)))
+                .collect::<Vec<_>>()
+        };
+        let mut added = with_flags(peers::from_compact(&self.added), &self.added_f);
+        added.extend(with_flags(
+            peers::from_compact6(&self.added6),
+            &self.added6_f,
+        ));
+        added
+    }
+
+    pub fn dropped(&self) -> Vec<SocketAddr> {
+        let mut dropped = peers::from_compact(&self.dropped);
+        dropped.extend(peers::from_compact6(&self.dropped6));
+        dropped
+    }
+}
+
+/// ut_pex bookkeeping of one connection.
+#[derive(Debug)]
+pub struct PexState {
+    /// id the peer receives ut_pex messages with, `None` if it doesn't support them
+    pub remote_id: Option<u8>,
+    // peers the peer knows about from us
+    sent: HashSet<SocketAddr>,
+    next_send: Instant,
+    last_received: Option<Instant>,
+}
+
+impl Default for PexState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PexState {
+    pub fn new() -> Self {
+        Self {
+            remote_id: None,
+            sent: HashSet::new(),
+            next_send: Instant::now() + INTERVAL,
+            last_received: None,
+        }
+    }
+
+    /// when the next message is due, `None` if the peer doesn't support ut_pex
+    pub fn next_send(&self) -> Option<Instant> {
+        self.remote_id.map(|_| self.next_send)
+    }
+
+    /// the message telling the peer how `current` (our other peers' listen addresses with
+    /// flags) changed since the last one, `None` if nothing changed
+    pub fn update(&mut self, current: &HashMap<SocketAddr, u8>) -> Option<PexMessage> {
+        self.next_send = Instant::now() + INTERVAL;
+        let added: Vec<(SocketAddr, u8)> = current
+            .iter()
+            .filter(|(a, _)| !self.sent.contains(a))
+            .take(MAX_PEERS)
+            .map(|(a, f)| (*a, *f))
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .sent
+            .iter()
+            .filter(|a| !current.contains_key(a))
+            .take(MAX_PEERS)
+            .copied()
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+        self.sent.extend(added.iter().map(|(a, _)| *a));
+        for a in &dropped {
+            self.sent.remove(a);
+        }
+        Some(PexMessage::new(&added, &dropped))
+    }
+
+    /// whether to process a message received now, peers may send one per minute
+    pub fn accept(&mut self) -> bool {
+        let now = Instant::now();
+        if self
+            .last_received
+            .is_some_and(|at| now.duration_since(at) < MIN_RECEIVE_INTERVAL)
+        {
+            return false;
+        }
+        self.last_received = Some(now);
+        true
+    }
+}
@@ -0,0 +1,640 @@
+use std::collections::HashMap;
+
+use anyhow::{ensure, Result};
+
+use crate::torrent::availability::Availability;
+use crate::torrent::bitfield::BitField;
+use crate::torrent::exchange::{BlockReqPayload, BLOCK_MAX};
+use crate::torrent::pipeline::{piece_blocks, PieceBuffer};
+use crate::torrent::random;
+use crate::torrent::storage::FileLayout;
+use crate::torrent::torrent::Torrent;
+
+/// Piece priority: 0 skips the piece, higher values are picked first.
+pub type Priority = u8;
+pub const PRIORITY_SKIP: Priority = 0;
+pub const PRIORITY_NORMAL: Priority = 4;
+pub const PRIORITY_MAX: Priority = 7;
+
+/// Until this many pieces are verified we pick at random: rare pieces are slow to get and
+/// any complete piece lets us start trading.
+const RANDOM_FIRST_PIECES: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PickMode {
+    /// rarest pieces in the swarm first
+    #[default]
+    RarestFirst,
+    /// lowest index first, for streaming
+    Sequential,
+}
+
+/// In endgame a missing block is requested from at most this many peers at once.
+const ENDGAME_MAX_REQUESTS: u8 = 3;
+
+/// A piece some of whose blocks are requested or received.
+#[derive(Debug)]
+struct PartialPiece {
+    buffer: PieceBuffer,
+    // number of peers a block is requested from and not received yet
+    requested: Vec<u8>,
+}
+
+/// What became of a received block.
+#[derive(Debug)]
+pub enum BlockOutcome {
+    /// the block was received before or its piece isn't downloading, the bytes are wasted
+    Wasted,
+    Stored {
+        /// other peers still have the block requested and should get a cancel
+        cancel: bool,
+        /// data of the piece once all of its blocks arrived
+        piece: Option<Vec<u8>>,
+    },
+}
+
+/// Decides which blocks to request from which peer, shared by all peer connections
+/// of a torrent so every block is requested once. Only in endgame, when every missing
+/// block is requested already, blocks are requested again from other peers so a slow
+/// peer can't stall the end of the download.
+#[derive(Debug)]
+pub struct PiecePicker {
+    piece_sizes: Vec<usize>,
+    /// verified pieces
+    have: BitField,
+    priorities: Vec<Priority>,
+    mode: PickMode,
+    availability: Availability,
+    partial: HashMap<u32, PartialPiece>,
+}
+
+impl PiecePicker {
+    pub fn new(torrent: &Torrent) -> Self {
+        let piece_count = torrent.piece_count();
+        Self {
+            piece_sizes: (0..piece_count).map(|i| torrent.piece_size(i)).collect(),
+            have: BitField::new(piece_count),
+            priorities: vec![PRIORITY_NORMAL; piece_count],
+            mode: PickMode::default(),
+            availability: Availability::new(piece_count),
+            partial: HashMap::new(),
+        }
+    }
+
+    /// download only `pieces`
+    pub fn set_wanted(&mut self, pieces: &[usize]) {
+        self.priorities.fill(PRIORITY_SKIP);
+        for p in pieces {
+            self.set_piece_priority(*p, PRIORITY_NORMAL);
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: PickMode) {
+        self.mode = mode;
+    }
+
+    pub fn set_piece_priority(&mut self, piece: usize, priority: Priority) {
+        if let Some(p) = self.priorities.get_mut(piece) {
+            *p = priority.min(PRIORITY_MAX);
+        }
+    }
+
+    /// a piece gets the highest priority of the files it overlaps, files beyond
+    /// `priorities` keep the normal priority
+    pub fn set_file_priorities(&mut self, layout: &FileLayout, priorities: &[Priority]) {
+        for piece in 0..self.piece_sizes.len() {
+            let priority = layout
+                .piece_files(piece, self.piece_sizes[piece] as u64)
+                .into_iter()
+                .map(|f| priorities.get(f).copied().unwrap_or(PRIORITY_NORMAL))
+                .max()
+                .unwrap_or(PRIORITY_SKIP);
+            self.set_piece_priority(piece, priority);
+        }
+    }
+
+    pub fn piece_priority(&self, piece: usize) -> Priority {
+        self.priorities.get(piece).copied().unwrap_or(PRIORITY_SKIP)
+    }
+
+    /// pieces we already have, e.g. found on disk
+    pub fn set_have(&mut self, have: BitField) {
+        self.have = have;
+    }
+
+    pub fn have(&self) -> &BitField {
+        &self.have
+    }
+
+    pub fn availability(&self) -> &Availability {
+        &self.availability
+    }
+
+    pub fn availability_mut(&mut self) -> &mut Availability {
+        &mut self.availability
+    }
+
+    pub fn piece_size(&self, index: usize) -> usize {
+        self.piece_sizes[index]
+    }
+
+    fn is_needed(&self, piece: usize) -> bool {
+        self.piece_priority(piece) != PRIORITY_SKIP && !self.have.get(piece)
+    }
+
+    fn wanted(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.priorities.len()).filter(|p| self.priorities[*p] != PRIORITY_SKIP)
+    }
+
+    /// whether a peer with `peer_has` has anything we need
+    pub fn is_interesting(&self, peer_has: &BitField) -> bool {
+        peer_has.iter().any(|p| self.is_needed(p))
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.wanted().all(|p| self.have.get(p))
+    }
+
+    /// number of wanted pieces not verified yet
+    pub fn remaining(&self) -> usize {
+        self.wanted().filter(|p| !self.have.get(*p)).count()
+    }
+
+    /// up to `max` blocks to request from a peer having `peer_has`. Partially downloaded
+    /// pieces are finished first so they can be verified and shared, then new pieces are
+    /// started by priority and the pick mode. In endgame blocks already requested from
+    /// other peers are picked, except those in `outstanding` to this peer.
+    pub fn pick(
+        &mut self,
+        peer_has: &BitField,
+        outstanding: &[BlockReqPayload],
+        max: usize,
+    ) -> Vec<BlockReqPayload> {
+        let mut picked = self.pick_fresh(peer_has, max);
+        if picked.is_empty() && self.in_endgame() {
+            self.pick_endgame(peer_has, outstanding, max, &mut picked);
+        }
+        picked
+    }
+
+    /// every block still missing is requested from some peer
+    pub fn in_endgame(&self) -> bool {
+        let all_started = self
+            .wanted()
+            .all(|p| self.have.get(p) || self.partial.contains_key(&(p as u32)));
+        all_started
+            && self.partial.values().all(|p| {
+                p.requested
+                    .iter()
+                    .enumerate()
+                    .all(|(b, n)| *n > 0 || p.buffer.has_block((b * BLOCK_MAX) as u32))
+            })
+    }
+
+    fn pick_fresh(&mut self, peer_has: &BitField, max: usize) -> Vec<BlockReqPayload> {
+        let mut picked = Vec::new();
+        let mut partial: Vec<u32> = self
+            .partial
+            .keys()
+            .copied()
+            .filter(|i| peer_has.get(*i as usize) && self.is_needed(*i as usize))
+            .collect();
+        partial.sort_unstable_by_key(|i| {
+            (
+                std::cmp::Reverse(self.piece_priority(*i as usize)),
+                self.sort_key(*i as usize),
+            )
+        });
+        for index in partial {
+            if picked.len() >= max {
+                return picked;
+            }
+            self.pick_from(index, max, &mut picked);
+        }
+
+        let mut candidates: Vec<usize> = peer_has
+            .iter()
+            .filter(|p| self.is_needed(*p) && !self.partial.contains_key(&(*p as u32)))
+            .collect();
+        let random_first =
+            self.mode == PickMode::RarestFirst && self.have.count() < RANDOM_FIRST_PIECES;
+        if random_first {
+            // shuffle, then the stable sort keeps the random order within a priority
+            for i in (1..candidates.len()).rev() {
+                candidates.swap(i, random::below(i + 1));
+            }
+            candidates.sort_by_key(|p| std::cmp::Reverse(self.piece_priority(*p)));
+        } else {
+            // random tie breaker so peers don't all start on the same rare piece
+            let salt = random::next_u64();
+            candidates.sort_by_key(|p| {
+                (
+                    std::cmp::Reverse(self.piece_priority(*p)),
+                    self.sort_key(*p),
+                    (*p as u64 ^ salt).wrapping_mul(0x9e3779b97f4a7c15),
+                )
+            });
+        }
+        for piece in candidates {
+            if picked.len() >= max {
+                break;
+            }
+            self.start_piece(piece);
+            self.pick_from(piece as u32, max, &mut picked);
+        }
+        picked
+    }
+
+    // rarest first: number of peers having the piece; sequential: the index
+    fn sort_key(&self, piece: usize) -> usize {
+        match self.mode {
+            PickMode::RarestFirst => self.availability.count(piece) as usize,
+            PickMode::Sequential => piece,
+        }
+    }
+
+    fn start_piece(&mut self, piece: usize) {
+        let size = self.piece_sizes[piece];
+        let buffer = PieceBuffer::new(piece as u32, size);
+        let requested = vec![0; buffer.block_count()];
+        self.partial
+            .insert(piece as u32, PartialPiece { buffer, requested });
+    }
+
+    fn pick_from(&mut self, index: u32, max: usize, picked: &mut Vec<BlockReqPayload>) {
+        let size = self.piece_sizes[index as usize];
+        let Some(p) = self.partial.get_mut(&index) else {
+            return;
+        };
+        for req in piece_blocks(index, size) {
+            if picked.len() >= max {
+                return;
+            }
+            let b = req.begin as usize / BLOCK_MAX;
+            if p.requested[b] == 0 && !p.buffer.has_block(req.begin) {
+                p.requested[b] = 1;
+                picked.push(req);
+            }
+        }
+    }
+
+    // duplicate requests for the missing blocks, least requested first
+    fn pick_endgame(
+        &mut self,
+        peer_has: &BitField,
+        outstanding: &[BlockReqPayload],
+        max: usize,
+        picked: &mut Vec<BlockReqPayload>,
+    ) {
+        let mut candidates = Vec::new();
+        for (index, p) in &self.partial {
+            if !peer_has.get(*index as usize) {
+                continue;
+            }
+            for req in piece_blocks(*index, self.piece_sizes[*index as usize]) {
+                let n = p.requested[req.begin as usize / BLOCK_MAX];
+                if n < ENDGAME_MAX_REQUESTS
+                    && !p.buffer.has_block(req.begin)
+                    && !outstanding.contains(&req)
+                {
+                    candidates.push((n, req));
+                }
+            }
+        }
+        candidates.sort_by_key(|(n, req)| (*n, req.index, req.begin));
+        for (_, req) in candidates.into_iter().take(max.saturating_sub(picked.len())) {
+            let p = self.partial.get_mut(&req.index).expect("collected above");
+            p.requested[req.begin as usize / BLOCK_MAX] += 1;
+            picked.push(req);
+        }
+    }
+
+    /// requests that won't be answered (peer choked or disconnected), make them pickable again
+    pub fn abort(&mut self, reqs: &[BlockReqPayload]) {
+        for req in reqs {
+            if let Some(p) = self.partial.get_mut(&req.index) {
+                if let Some(r) = p.requested.get_mut(req.begin as usize / BLOCK_MAX) {
+                    *r = r.saturating_sub(1);
+                }
+            }
+        }
+    }
+
+    /// store a received block. `requested` tells whether it answers a request still
+    /// outstanding to the sending peer, which then doesn't need a cancel.
+    pub fn on_block(
+        &mut self,
+        index: u32,
+        begin: u32,
+        block: &[u8],
+        requested: bool,
+    ) -> Result<BlockOutcome> {
+        let Some(p) = self.partial.get_mut(&index) else {
+            ensure!(
+                (index as usize) < self.piece_sizes.len(),
+                "block of piece {} beyond the last piece",
+                index
+            );
+            return Ok(BlockOutcome::Wasted);
+        };
+        if p.buffer.has_block(begin) {
+            return Ok(BlockOutcome::Wasted);
+        }
+        p.buffer.add_block(begin, block)?;
+        let mut cancel = false;
+        if let Some(r) = p.requested.get_mut(begin as usize / BLOCK_MAX) {
+            cancel = *r > u8::from(requested);
+            *r = 0;
+        }
+        let piece = if p.buffer.is_complete() {
+            let p = self.partial.remove(&index).expect("checked above");
+            Some(p.buffer.into_data())
+        } else {
+            None
+        };
+        Ok(BlockOutcome::Stored { cancel, piece })
+    }
+
+    /// the piece passed the hash check and was stored
+    pub fn piece_verified(&mut self, index: usize) {
+        self.have.set(index);
+    }
+
+    /// the piece failed the hash check, download it again from scratch
+    pub fn piece_failed(&mut self, index: usize) {
+        self.partial.remove(&(index as u32));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::serde::hashes::Hashes;
+    use crate::torrent::torrent::{Info, Keys};
+
+    // two blocks per piece
+    const PIECE_LENGTH: usize = 2 * BLOCK_MAX;
+
+    fn picker(piece_count: usize) -> PiecePicker {
+        let torrent = Torrent {
+            announce: String::new(),
+            created_by: String::new(),
+            url_list: Vec::new(),
+            info: Info {
+                name: "file".to_string(),
+                piece_length: PIECE_LENGTH,
+                pieces: Hashes(vec![[0; 20]; piece_count]),
+                private: None,
+                keys: Keys::Single {
+                    length: piece_count * PIECE_LENGTH,
+                },
+            },
+        };
+        PiecePicker::new(&torrent)
+    }
+
+    fn bits(len: usize, pieces: &[usize]) -> BitField {
+        let mut bits = BitField::new(len);
+        pieces.iter().for_each(|p| bits.set(*p));
+        bits
+    }
+
+    // piece `piece` is had by `peers` peers
+    fn make_available(picker: &mut PiecePicker, piece: usize, peers: usize) {
+        (0..peers).for_each(|_| picker.availability_mut().add_have(piece));
+    }
+
+    // pieces of the picked blocks in the order they were first picked
+    fn pieces(picked: &[BlockReqPayload]) -> Vec<u32> {
+        let mut pieces: Vec<u32> = Vec::new();
+        for req in picked {
+            if !pieces.contains(&req.index) {
+                pieces.push(req.index);
+            }
+        }
+        pieces
+    }
+
+    // enough pieces verified to stop picking at random
+    fn past_random_first(piece_count: usize) -> PiecePicker {
+        let mut picker = picker(piece_count);
+        picker.set_have(bits(
+            piece_count,
+            &(0..RANDOM_FIRST_PIECES).collect::<Vec<_>>(),
+        ));
+        picker
+    }
+
+    #[test]
+    fn picks_the_rarest_pieces_first() {
+        let mut picker = past_random_first(8);
+        for (piece, peers) in [(4, 2), (5, 1), (6, 3), (7, 2)] {
+            make_available(&mut picker, piece, peers);
+        }
+
+        let picked = picker.pick(&BitField::full(8), &[], 8);
+        assert_eq!(picked.len(), 8);
+        let order = pieces(&picked);
+        assert_eq!(order[0], 5);
+        assert!(order[1..3].contains(&4) && order[1..3].contains(&7));
+        assert_eq!(order[3], 6);
+        // both blocks of a piece are picked together
+        assert_eq!(
+            picked[..2],
+            [
+                BlockReqPayload::new(5, 0, BLOCK_MAX as u32),
+                BlockReqPayload::new(5, BLOCK_MAX as u32, BLOCK_MAX as u32)
+            ]
+        );
+    }
+
+    #[test]
+    fn picks_at_random_until_a_few_pieces_are_verified() {
+        let mut first = Vec::new();
+        for _ in 0..50 {
+            let mut picker = picker(8);
+            make_available(&mut picker, 0, 1);
+            (1..8).for_each(|p| make_available(&mut picker, p, 5));
+            first.push(picker.pick(&BitField::full(8), &[], 1)[0].index);
+        }
+        // rarest first would always start on piece 0
+        assert!(first.iter().any(|p| *p != 0), "{:?}", first);
+
+        let mut picker = past_random_first(8);
+        make_available(&mut picker, 7, 1);
+        (4..7).for_each(|p| make_available(&mut picker, p, 5));
+        assert_eq!(picker.pick(&BitField::full(8), &[], 1)[0].index, 7);
+    }
+
+    #[test]
+    fn finishes_partial_pieces_first() {
+        let mut picker = past_random_first(8);
+        make_available(&mut picker, 7, 1);
+        (4..7).for_each(|p| make_available(&mut picker, p, 3));
+        assert_eq!(pieces(&picker.pick(&BitField::full(8), &[], 1)), [7]);
+
+        // piece 7 is no longer the rarest but still comes first
+        make_available(&mut picker, 7, 5);
+        let picked = picker.pick(&BitField::full(8), &[], 2);
+        assert_eq!(
+            picked[0],
+            BlockReqPayload::new(7, BLOCK_MAX as u32, BLOCK_MAX as u32)
+        );
+        assert_ne!(picked[1].index, 7);
+        // a peer without the partial piece starts another one
+        let picked = picker.pick(&bits(8, &[5, 6]), &[], 1);
+        assert_ne!(picked[0].index, 7);
+    }
+
+    #[test]
+    fn picks_by_priority_and_never_picks_skipped_pieces() {
+        let mut picker = past_random_first(8);
+        make_available(&mut picker, 4, 3);
+        make_available(&mut picker, 5, 9);
+        make_available(&mut picker, 6, 1);
+        make_available(&mut picker, 7, 3);
+        picker.set_piece_priority(5, PRIORITY_MAX);
+        picker.set_piece_priority(6, PRIORITY_SKIP);
+        picker.set_piece_priority(7, PRIORITY_NORMAL + 1);
+
+        let picked = picker.pick(&BitField::full(8), &[], 8);
+        assert_eq!(pieces(&picked), [5, 7, 4]);
+        assert!(!picker.is_interesting(&bits(8, &[0, 6])));
+        assert!(picker.is_interesting(&bits(8, &[4])));
+        assert_eq!(picker.remaining(), 3);
+
+        picker.set_piece_priority(3, PRIORITY_MAX + 3);
+        assert_eq!(picker.piece_priority(3), PRIORITY_MAX);
+        picker.set_wanted(&[1, 2]);
+        assert_eq!(picker.piece_priority(4), PRIORITY_SKIP);
+        assert_eq!(picker.remaining(), 0);
+        assert!(picker.is_complete());
+    }
+
+    #[test]
+    fn picks_in_index_order_when_sequential() {
+        let mut picker = picker(6);
+        picker.set_mode(PickMode::Sequential);
+        (0..6).for_each(|p| make_available(&mut picker, p, 6 - p));
+        picker.set_piece_priority(4, PRIORITY_MAX);
+
+        let picked = picker.pick(&bits(6, &[1, 2, 3, 4, 5]), &[], 10);
+        assert_eq!(pieces(&picked), [4, 1, 2, 3, 5]);
+    }
+
+    #[test]
+    fn returns_the_blocks_of_a_dropped_peer() {
+        let mut picker = past_random_first(8);
+        let dropped_has = BitField::full(8);
+        let other_has = bits(8, &[4, 5, 6, 7]);
+        picker.availability_mut().add_bitfield(&dropped_has);
+        picker.availability_mut().add_bitfield(&other_has);
+
+        let dropped = picker.pick(&dropped_has, &[], 3);
+        let other = picker.pick(&other_has, &[], 8);
+        assert_eq!(other.len(), 5);
+        assert!(other.iter().all(|req| !dropped.contains(req)));
+
+        picker.abort(&dropped);
+        picker.availability_mut().remove_bitfield(&dropped_has);
+        assert_eq!(picker.availability().count(4), 1);
+        assert_eq!(picker.availability().count(0), 0);
+        let mut picked = picker.pick(&other_has, &[], 8);
+        picked.sort_by_key(|req| (req.index, req.begin));
+        let mut dropped = dropped;
+        dropped.sort_by_key(|req| (req.index, req.begin));
+        assert_eq!(picked, dropped);
+    }
+
+    fn block(req: &BlockReqPayload) -> Vec<u8> {
+        vec![req.index as u8; req.length as usize]
+    }
+
+    #[test]
+    fn enters_endgame_once_every_missing_block_is_requested() {
+        let mut picker = past_random_first(6);
+        let all = BitField::full(6);
+        let first = picker.pick(&all, &[], 3);
+        assert!(!picker.in_endgame());
+        let second = picker.pick(&all, &[], 8);
+        assert_eq!(second.len(), 1);
+        assert!(picker.in_endgame());
+
+        // a peer gets the blocks requested from others, not its own again
+        let mut dup = picker.pick(&all, &second, 8);
+        dup.sort_by_key(|req| (req.index, req.begin));
+        let mut first = first;
+        first.sort_by_key(|req| (req.index, req.begin));
+        assert_eq!(dup, first);
+        // an aborted request is picked fresh and ends the endgame until then
+        picker.abort(&second);
+        assert!(!picker.in_endgame());
+        assert_eq!(picker.pick(&all, &[], 8), second);
+    }
+
+    #[test]
+    fn requests_a_block_from_at_most_three_peers_in_endgame() {
+        let mut picker = past_random_first(5);
+        let all = BitField::full(5);
+        let first = picker.pick(&all, &[], 8);
+        assert_eq!(first.len(), 2);
+        assert_eq!(picker.pick(&all, &[], 8), first);
+        assert_eq!(picker.pick(&all, &[], 8), first);
+        assert!(picker.pick(&all, &[], 8).is_empty());
+        assert!(picker.pick(&bits(5, &[0]), &[], 8).is_empty());
+
+        // a dropped duplicate frees a slot for another peer
+        picker.abort(&first[1..]);
+        assert_eq!(picker.pick(&all, &[], 8), first[1..]);
+    }
+
+    #[test]
+    fn cancels_duplicates_when_a_block_arrives() {
+        let mut picker = past_random_first(5);
+        let all = BitField::full(5);
+        let reqs = picker.pick(&all, &[], 8);
+        picker.pick(&all, &[], 8);
+        let [a, b] = reqs[..] else {
+            panic!("{:?}", reqs)
+        };
+
+        // requested from two peers: the other one gets a cancel
+        let outcome = picker.on_block(a.index, a.begin, &block(&a), true).unwrap();
+        assert!(matches!(
+            outcome,
+            BlockOutcome::Stored {
+                cancel: true,
+                piece: None
+            }
+        ));
+        // the duplicate arrives anyway
+        let outcome = picker
+            .on_block(a.index, a.begin, &block(&a), false)
+            .unwrap();
+        assert!(matches!(outcome, BlockOutcome::Wasted));
+
+        // requested from one peer only, after the other dropped it
+        picker.abort(&[b]);
+        let outcome = picker.on_block(b.index, b.begin, &block(&b), true).unwrap();
+        let BlockOutcome::Stored {
+            cancel: false,
+            piece: Some(piece),
+        } = outcome
+        else {
+            panic!("{:?}", outcome)
+        };
+        assert_eq!(piece, vec![4; PIECE_LENGTH]);
+        // a late answer to a request aborted at the sender cancels it at the peer
+        // now requesting the block
+        let mut picker = past_random_first(5);
+        let reqs = picker.pick(&all, &[], 1);
+        let outcome = picker.on_block(4, 0, &block(&reqs[0]), false).unwrap();
+        assert!(matches!(
+            outcome,
+            BlockOutcome::Stored {
+                cancel: true,
+                piece: None
+            }
+        ));
+    }
+}
@@ -0,0 +1,204 @@
+use std::time::Duration;
+
+use anyhow::{ensure, Result};
+
+use crate::torrent::exchange::{BlockReqPayload, BLOCK_MAX};
+use crate::torrent::rate::RateMeter;
+
+/// Limits of the outstanding request queue of one peer connection.
+#[derive(Debug, Clone, Copy)]
+pub struct PipelineConfig {
+    /// depth before any rate was measured
+    pub initial_depth: usize,
+    pub min_depth: usize,
+    pub max_depth: usize,
+    /// keep enough requests in flight to cover this much time at the measured rate
+    pub queue_time: Duration,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            initial_depth: 5,
+            min_depth: 2,
+            max_depth: 250,
+            queue_time: Duration::from_secs(3),
+        }
+    }
+}
+
+/// Requests sent to a peer and not answered yet.
+#[derive(Debug)]
+pub struct RequestQueue {
+    outstanding: Vec<BlockReqPayload>,
+    config: PipelineConfig,
+    // `reqq` from the peer's extension handshake
+    peer_reqq: Option<usize>,
+    target: usize,
+    rate: RateMeter,
+}
+
+impl RequestQueue {
+    pub fn new(config: PipelineConfig) -> Self {
+        Self {
+            outstanding: Vec::new(),
+            target: config.initial_depth.clamp(config.min_depth, config.max_depth),
+            config,
+            peer_reqq: None,
+            rate: RateMeter::new(),
+        }
+    }
+
+    /// the peer told us how many requests it queues, more would be dropped
+    pub fn set_peer_reqq(&mut self, reqq: usize) {
+        self.peer_reqq = Some(reqq.max(1));
+    }
+
+    /// number of requests we may have in flight right now
+    pub fn capacity(&self) -> usize {
+        self.target.min(self.peer_reqq.unwrap_or(usize::MAX)).max(1)
+    }
+
+    pub fn has_room(&self) -> bool {
+        self.outstanding.len() < self.capacity()
+    }
+
+    pub fn len(&self) -> usize {
+        self.outstanding.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outstanding.is_empty()
+    }
+
+    pub fn push(&mut self, req: BlockReqPayload) {
+        self.outstanding.push(req);
+    }
+
+    /// a block arrived, false if we never requested it
+    pub fn complete(&mut self, index: u32, begin: u32, length: usize) -> bool {
+        let Some(pos) = self
+            .outstanding
+            .iter()
+            .position(|r| r.index == index && r.begin == begin && r.length as usize == length)
+        else {
+            return false;
+        };
+        self.outstanding.swap_remove(pos);
+        self.rate.add(length);
+        self.adapt();
+        true
+    }
+
+    /// forget a request we cancel, false if it isn't outstanding
+    pub fn cancel(&mut self, req: &BlockReqPayload) -> bool {
+        let Some(pos) = self.outstanding.iter().position(|r| r == req) else {
+            return false;
+        };
+        self.outstanding.swap_remove(pos);
+        true
+    }
+
+    /// forget the outstanding requests not matching `keep`
+    pub fn drain_except(
+        &mut self,
+        keep: impl Fn(&BlockReqPayload) -> bool,
+    ) -> Vec<BlockReqPayload> {
+        let (kept, dropped) = std::mem::take(&mut self.outstanding)
+            .into_iter()
+            .partition(|r| keep(r));
+        self.outstanding = kept;
+        dropped
+    }
+
+    /// forget every outstanding request, e.g. when the peer chokes us
+    pub fn drain(&mut self) -> Vec<BlockReqPayload> {
+        std::mem::take(&mut self.outstanding)
+    }
+
+    pub fn outstanding(&self) -> &[BlockReqPayload] {
+        &self.outstanding
+    }
+
+    /// measured download rate from this peer
+    pub fn rate(&self) -> f64 {
+        self.rate.rate()
+    }
+
+    // enough blocks to keep the link busy for `queue_time` at the current rate
+    fn adapt(&mut self) {
+        let wanted = self.rate.rate() * self.config.queue_time.as_secs_f64() / BLOCK_MAX as f64;
+        self.target = (wanted.ceil() as usize).clamp(self.config.min_depth, self.config.max_depth);
+    }
+}
+
+/// Blocks of one piece, assembled in whatever order they arrive.
+#[derive(Debug)]
+pub struct PieceBuffer {
+    pub index: u32,
+    data: Vec<u8>,
+    received: Vec<bool>,
+    remaining: usize,
+}
+
+impl PieceBuffer {
+    pub fn new(index: u32, size: usize) -> Self {
+        let blocks = size.div_ceil(BLOCK_MAX);
+        Self {
+            index,
+            data: vec![0; size],
+            received: vec![false; blocks],
+            remaining: blocks,
+        }
+    }
+
+    /// place a block at its `begin` offset, duplicates are ignored
+    pub fn add_block(&mut self, begin: u32, block: &[u8]) -> Result<()> {
+        let begin = begin as usize;
+        ensure!(begin.is_multiple_of(BLOCK_MAX), "block offset {} isn't block aligned", begin);
+        let b = begin / BLOCK_MAX;
+        ensure!(b < self.received.len(), "block offset {} beyond piece end", begin);
+        let expect = BLOCK_MAX.min(self.data.len() - begin);
+        ensure!(
+            block.len() == expect,
+            "block at {} has {} bytes, expect {}",
+            begin,
+            block.len(),
+            expect
+        );
+        if !self.received[b] {
+            self.data[begin..begin + expect].copy_from_slice(block);
+            self.received[b] = true;
+            self.remaining -= 1;
+        }
+        Ok(())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.remaining == 0
+    }
+
+    /// whether the block starting at `begin` arrived already
+    pub fn has_block(&self, begin: u32) -> bool {
+        self.received
+            .get(begin as usize / BLOCK_MAX)
+            .copied()
+            .unwrap_or(false)
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.received.len()
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// requests covering a piece of `size` bytes
+pub fn piece_blocks(index: u32, size: usize) -> impl Iterator<Item = BlockReqPayload> {
+    (0..size.div_ceil(BLOCK_MAX)).map(move |b| {
+        let begin = b * BLOCK_MAX;
+        BlockReqPayload::new(index, begin as u32, BLOCK_MAX.min(size - begin) as u32)
+    })
+}
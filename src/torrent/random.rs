@@ -0,0 +1,47 @@
+use std::collections::hash_map::RandomState;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+
+// std has no public RNG, but every `RandomState` is seeded from the OS. Hashing a counter and the
+// clock through a fresh one gives us unpredictable values without pulling in another crate.
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+pub fn next_u64() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    hasher.write_u128(nanos);
+    hasher.finish()
+}
+
+pub fn next_u32() -> u32 {
+    next_u64() as u32
+}
+
+/// random number in `0..bound`, `bound` must not be 0
+pub fn below(bound: usize) -> usize {
+    (next_u64() % bound as u64) as usize
+}
+
+pub fn fill(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(8) {
+        let bytes = next_u64().to_le_bytes();
+        chunk.copy_from_slice(&bytes[..chunk.len()]);
+    }
+}
+
+/// fill `buf` straight from the OS's CSPRNG, for secrets like key material. The values
+/// above are unpredictable enough for ids and shuffling, not for keys.
+pub fn os_fill(buf: &mut [u8]) -> Result<()> {
+    File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(buf))
+        .context("read /dev/urandom")
+}
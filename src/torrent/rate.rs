@@ -0,0 +1,66 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_secs(5);
+
+/// Transfer rate over a sliding window of the last few seconds.
+#[derive(Debug, Clone)]
+pub struct RateMeter {
+    samples: VecDeque<(Instant, usize)>,
+    started: Instant,
+    total: u64,
+}
+
+impl RateMeter {
+    pub fn new() -> Self {
+        Self {
+            samples: VecDeque::new(),
+            started: Instant::now(),
+            total: 0,
+        }
+    }
+
+    pub fn add(&mut self, bytes: usize) {
+        let now = Instant::now();
+        self.samples.push_back((now, bytes));
+        self.total += bytes as u64;
+        self.trim(now);
+    }
+
+    /// bytes per second within the window
+    pub fn rate(&self) -> f64 {
+        let now = Instant::now();
+        let bytes: usize = self
+            .samples
+            .iter()
+            .filter(|(t, _)| now.duration_since(*t) < WINDOW)
+            .map(|(_, b)| b)
+            .sum();
+        // a young meter hasn't seen a full window yet
+        let span = now.duration_since(self.started).min(WINDOW).as_secs_f64();
+        if span <= 0.0 {
+            return 0.0;
+        }
+        bytes as f64 / span.max(0.1)
+    }
+
+    /// bytes ever added
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    fn trim(&mut self, now: Instant) {
+        while let Some((t, _)) = self.samples.front() {
+            if now.duration_since(*t) < WINDOW {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+}
+
+impl Default for RateMeter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -63,13 +63,73 @@ pub mod bytes_or_string {
     }
 }
 
+pub mod url_list {
+    use std::fmt;
+
+    use serde::de::{Error, SeqAccess, Visitor};
+    use serde::{Deserialize, Deserializer};
+
+    /// Deserialize `url-list` (BEP 19): a single url or a list of them, empty ones dropped
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(UrlListVisitor)
+    }
+
+    struct Url(String);
+
+    impl<'de> Deserialize<'de> for Url {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            super::bytes_or_string::deserialize(deserializer).map(Url)
+        }
+    }
+
+    struct UrlListVisitor;
+
+    impl<'de> Visitor<'de> for UrlListVisitor {
+        type Value = Vec<String>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a url or a list of urls")
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E> {
+            Ok(Some(v.to_string()).filter(|u| !u.is_empty()).into_iter().collect())
+        }
+
+        fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+        where
+            E: Error,
+        {
+            self.visit_str(&String::from_utf8_lossy(v))
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            let mut urls = Vec::new();
+            while let Some(Url(url)) = seq.next_element()? {
+                if !url.is_empty() {
+                    urls.push(url);
+                }
+            }
+            Ok(urls)
+        }
+    }
+}
+
 pub mod hashes {
     use std::fmt::{Formatter};
 
     use serde::{Deserialize, Deserializer, Serialize, Serializer};
     use serde::de::{Error, SeqAccess, Visitor};
 
-    #[derive(Debug)]
+    #[derive(Debug, Clone)]
     pub struct Hashes(pub Vec<[u8; 20]>);
 
     struct HashesVisitor;
@@ -85,7 +145,7 @@ pub mod hashes {
         where
             E: Error,
         {
-            if v.len() % 20 != 0 {
+            if !v.len().is_multiple_of(20) {
                 return Err(E::custom(format!("length is {}", v.len())));
             }
             let hashes = Hashes(
@@ -132,6 +192,7 @@ pub mod hashes {
 
 pub mod peers {
     use std::fmt::{Debug, Display, Formatter};
+    use std::net::{IpAddr, SocketAddr};
 
     use serde::{Deserialize, Deserializer};
     use serde::de::{Error, SeqAccess, Visitor};
@@ -151,6 +212,65 @@ pub mod peers {
         }
     }
 
+    impl Peer {
+        pub fn socket_addr(&self) -> Option<SocketAddr> {
+            let ip = self.0.parse::<IpAddr>().ok()?;
+            Some(SocketAddr::new(ip, self.1))
+        }
+    }
+
+    impl From<SocketAddr> for Peer {
+        fn from(addr: SocketAddr) -> Self {
+            Peer(addr.ip().to_string(), addr.port())
+        }
+    }
+
+    /// compact form of ipv4 peers: 4 bytes ip + 2 bytes port each, other peers are skipped
+    pub fn to_compact(peers: &[Peer]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(peers.len() * 6);
+        for addr in peers.iter().filter_map(Peer::socket_addr) {
+            if let SocketAddr::V4(v4) = addr {
+                bytes.extend_from_slice(&v4.ip().octets());
+                bytes.extend_from_slice(&v4.port().to_be_bytes());
+            }
+        }
+        bytes
+    }
+
+    /// compact form of ipv6 peers (`peers6`): 16 bytes ip + 2 bytes port each
+    pub fn to_compact6(peers: &[Peer]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(peers.len() * 18);
+        for addr in peers.iter().filter_map(Peer::socket_addr) {
+            if let SocketAddr::V6(v6) = addr {
+                bytes.extend_from_slice(&v6.ip().octets());
+                bytes.extend_from_slice(&v6.port().to_be_bytes());
+            }
+        }
+        bytes
+    }
+
+    /// parse compact ipv4 peers, a trailing partial entry is ignored
+    pub fn from_compact(bytes: &[u8]) -> Vec<SocketAddr> {
+        bytes
+            .chunks_exact(6)
+            .map(|c| {
+                let ip: [u8; 4] = c[..4].try_into().expect("chunk of 6");
+                SocketAddr::new(ip.into(), u16::from_be_bytes([c[4], c[5]]))
+            })
+            .collect()
+    }
+
+    /// parse compact ipv6 peers, a trailing partial entry is ignored
+    pub fn from_compact6(bytes: &[u8]) -> Vec<SocketAddr> {
+        bytes
+            .chunks_exact(18)
+            .map(|c| {
+                let ip: [u8; 16] = c[..16].try_into().expect("chunk of 18");
+                SocketAddr::new(ip.into(), u16::from_be_bytes([c[16], c[17]]))
+            })
+            .collect()
+    }
+
     struct PeersVisitor;
 
     impl<'de> Visitor<'de> for PeersVisitor {
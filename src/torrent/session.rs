@@ -0,0 +1,69 @@
+use std::net::IpAddr;
+
+use crate::torrent::random;
+
+/// Azureus-style client prefix: `-` + 2 letter client id + 4 digit version + `-`
+pub const CLIENT_PREFIX: &[u8; 8] = b"-RB0010-";
+pub const DEFAULT_PORT: u16 = 6881;
+pub const DEFAULT_NUMWANT: u32 = 50;
+
+const PEER_ID_CHARS: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+/// Identity of this client for one run, shared by tracker announces and peer handshakes.
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub peer_id: [u8; 20],
+    // lets the tracker recognize us when our ip changes, must stay the same for the whole session
+    pub key: u32,
+    pub numwant: u32,
+    pub port: u16,
+    pub ip: Option<IpAddr>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self {
+            peer_id: generate_peer_id(),
+            key: random::next_u32(),
+            numwant: DEFAULT_NUMWANT,
+            port: DEFAULT_PORT,
+            ip: None,
+        }
+    }
+
+    pub fn with_numwant(mut self, numwant: u32) -> Self {
+        self.numwant = numwant;
+        self
+    }
+
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub fn with_ip(mut self, ip: IpAddr) -> Self {
+        self.ip = Some(ip);
+        self
+    }
+
+    /// tracker expects the key as a hex string
+    pub fn key_hex(&self) -> String {
+        format!("{:08x}", self.key)
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// client prefix followed by 12 random url-safe characters
+pub fn generate_peer_id() -> [u8; 20] {
+    let mut peer_id = [0u8; 20];
+    peer_id[..CLIENT_PREFIX.len()].copy_from_slice(CLIENT_PREFIX);
+    for b in peer_id[CLIENT_PREFIX.len()..].iter_mut() {
+        *b = PEER_ID_CHARS[random::below(PEER_ID_CHARS.len())];
+    }
+    peer_id
+}
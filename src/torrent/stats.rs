@@ -0,0 +1,42 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Transfer counters of a torrent, shared by its peer connections.
+#[derive(Debug, Default)]
+pub struct Stats {
+    downloaded: AtomicU64,
+    wasted: AtomicU64,
+    uploaded: AtomicU64,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// payload bytes received in blocks, useful or not
+    pub fn add_downloaded(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// bytes thrown away: duplicate blocks from endgame and pieces failing the hash check
+    pub fn add_wasted(&self, bytes: u64) {
+        self.wasted.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// payload bytes sent in blocks
+    pub fn add_uploaded(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    pub fn wasted(&self) -> u64 {
+        self.wasted.load(Ordering::Relaxed)
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+}
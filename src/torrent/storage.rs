@@ -0,0 +1,223 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{bail, ensure, Context, Result};
+
+use crate::torrent::torrent::{Keys, Torrent};
+
+/// Where downloaded pieces end up and where uploaded blocks are read from.
+pub trait PieceStore: Send + Sync {
+    /// write a verified piece
+    fn write_piece(&self, index: usize, data: &[u8]) -> Result<()>;
+    /// read `length` bytes at `begin` within piece `index`
+    fn read_block(&self, index: usize, begin: usize, length: usize) -> Result<Vec<u8>>;
+}
+
+#[derive(Debug, Clone)]
+pub struct FileEntry {
+    pub path: PathBuf,
+    pub length: u64,
+    /// offset of the file within the torrent's concatenated data
+    pub offset: u64,
+}
+
+/// Maps the torrent's byte stream onto its files.
+#[derive(Debug, Clone)]
+pub struct FileLayout {
+    pub files: Vec<FileEntry>,
+    pub piece_length: u64,
+    pub total_length: u64,
+}
+
+impl FileLayout {
+    /// a single-file torrent is stored at `output`, a multi-file torrent below the directory
+    /// `output`
+    pub fn new(torrent: &Torrent, output: &Path) -> Result<Self> {
+        let mut files = Vec::new();
+        let mut offset = 0u64;
+        match &torrent.info.keys {
+            Keys::Single { length } => {
+                files.push(FileEntry {
+                    path: output.to_path_buf(),
+                    length: *length as u64,
+                    offset,
+                });
+                offset += *length as u64;
+            }
+            Keys::Multiple { files: infos } => {
+                for info in infos {
+                    let mut path = output.to_path_buf();
+                    for part in &info.path {
+                        // refuse `..`, absolute paths and the like escaping the download directory
+                        ensure!(
+                            matches!(Path::new(part).components().next(), Some(Component::Normal(_)))
+                                && Path::new(part).components().count() == 1,
+                            "invalid path component {:?}",
+                            part
+                        );
+                        path.push(part);
+                    }
+                    files.push(FileEntry {
+                        path,
+                        length: info.length as u64,
+                        offset,
+                    });
+                    offset += info.length as u64;
+                }
+            }
+        }
+        Ok(Self {
+            files,
+            piece_length: torrent.info.piece_length as u64,
+            total_length: offset,
+        })
+    }
+
+    /// (file index, offset in file, length) of the byte range `[start, start + length)`
+    pub fn segments(&self, start: u64, length: u64) -> Vec<(usize, u64, u64)> {
+        let end = start + length;
+        self.files
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| f.length > 0 && f.offset < end && start < f.offset + f.length)
+            .map(|(i, f)| {
+                let from = start.max(f.offset);
+                let to = end.min(f.offset + f.length);
+                (i, from - f.offset, to - from)
+            })
+            .collect()
+    }
+
+    /// files overlapping piece `index`
+    pub fn piece_files(&self, index: usize, piece_size: u64) -> Vec<usize> {
+        self.segments(index as u64 * self.piece_length, piece_size)
+            .into_iter()
+            .map(|(i, _, _)| i)
+            .collect()
+    }
+}
+
+/// Files on disk, created and preallocated on open, or opened read-only to seed them.
+#[derive(Debug)]
+pub struct Storage {
+    layout: FileLayout,
+    handles: Mutex<Vec<File>>,
+    read_only: bool,
+}
+
+impl Storage {
+    pub fn open(layout: FileLayout) -> Result<Self> {
+        let mut handles = Vec::with_capacity(layout.files.len());
+        for f in &layout.files {
+            if let Some(dir) = f.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+                std::fs::create_dir_all(dir)
+                    .with_context(|| format!("create directory {}", dir.display()))?;
+            }
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&f.path)
+                .with_context(|| format!("open {}", f.path.display()))?;
+            if file.metadata()?.len() != f.length {
+                file.set_len(f.length)?;
+            }
+            handles.push(file);
+        }
+        Ok(Self {
+            layout,
+            handles: Mutex::new(handles),
+            read_only: false,
+        })
+    }
+
+    /// the existing files of a complete download, left as they are: fails if one is
+    /// missing or has another size than the torrent says
+    pub fn open_read_only(layout: FileLayout) -> Result<Self> {
+        let mut handles = Vec::with_capacity(layout.files.len());
+        for f in &layout.files {
+            let file = File::open(&f.path).with_context(|| format!("open {}", f.path.display()))?;
+            let len = file.metadata()?.len();
+            ensure!(
+                len == f.length,
+                "{} is {} bytes, the torrent expects {}",
+                f.path.display(),
+                len,
+                f.length
+            );
+            handles.push(file);
+        }
+        Ok(Self {
+            layout,
+            handles: Mutex::new(handles),
+            read_only: true,
+        })
+    }
+
+    pub fn layout(&self) -> &FileLayout {
+        &self.layout
+    }
+
+    fn range(&self, index: usize, begin: usize, length: usize) -> Result<u64> {
+        let start = index as u64 * self.layout.piece_length + begin as u64;
+        if start + length as u64 > self.layout.total_length {
+            bail!("block {}@{}+{} beyond end of torrent", index, begin, length);
+        }
+        Ok(start)
+    }
+}
+
+impl PieceStore for Storage {
+    fn write_piece(&self, index: usize, data: &[u8]) -> Result<()> {
+        ensure!(!self.read_only, "piece {} not written, storage is read-only", index);
+        let start = self.range(index, 0, data.len())?;
+        let mut handles = self.handles.lock().unwrap();
+        let mut written = 0usize;
+        for (i, offset, len) in self.layout.segments(start, data.len() as u64) {
+            let file = &mut handles[i];
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&data[written..written + len as usize])?;
+            written += len as usize;
+        }
+        Ok(())
+    }
+
+    fn read_block(&self, index: usize, begin: usize, length: usize) -> Result<Vec<u8>> {
+        let start = self.range(index, begin, length)?;
+        let mut handles = self.handles.lock().unwrap();
+        let mut buf = vec![0u8; length];
+        let mut read = 0usize;
+        for (i, offset, len) in self.layout.segments(start, length as u64) {
+            let file = &mut handles[i];
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut buf[read..read + len as usize])?;
+            read += len as usize;
+        }
+        Ok(buf)
+    }
+}
+
+/// Keeps only one piece, written on its own to a file (the `download_piece` command).
+#[derive(Debug)]
+pub struct SinglePieceFile {
+    pub path: PathBuf,
+    pub index: usize,
+}
+
+impl PieceStore for SinglePieceFile {
+    fn write_piece(&self, index: usize, data: &[u8]) -> Result<()> {
+        ensure!(index == self.index, "piece {} isn't stored here", index);
+        std::fs::write(&self.path, data).with_context(|| format!("write {}", self.path.display()))
+    }
+
+    fn read_block(&self, index: usize, begin: usize, length: usize) -> Result<Vec<u8>> {
+        ensure!(index == self.index, "piece {} isn't stored here", index);
+        let data = std::fs::read(&self.path)?;
+        data.get(begin..begin + length)
+            .map(<[u8]>::to_vec)
+            .context("block beyond end of piece")
+    }
+}
@@ -10,17 +10,24 @@ use crate::torrent::serde::bytes_or_string;
 use crate::torrent::serde::hashes::Hashes;
 use crate::torrent::serde::peers;
 use crate::torrent::serde::peers::Peer;
+use crate::torrent::serde::url_list;
 use crate::torrent::torrent::Keys::{Multiple, Single};
 
 #[serde_as]
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Torrent {
-    #[serde(deserialize_with = "bytes_or_string::deserialize")]
+    /// empty for trackerless torrents
+    #[serde(default, deserialize_with = "bytes_or_string::deserialize")]
     pub announce: String,
     #[serde(rename = "created by")]
     #[serde(default)] // if no value, then use String::default
     #[serde(deserialize_with = "bytes_or_string::deserialize")]
     pub created_by: String,
+    /// web seeds (BEP 19)
+    #[serde(rename = "url-list")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[serde(deserialize_with = "url_list::deserialize")]
+    pub url_list: Vec<String>,
     pub info: Info,
 }
 
@@ -30,7 +37,7 @@ impl Torrent {
         let mut hasher = sha1::Sha1::new();
         Digest::update(&mut hasher, encoded_info.clone());
         let hash = hasher.finalize();
-        hash.try_into().unwrap()
+        hash.into()
     }
 
     pub fn from_file_old(file_path: &str) -> Self {
@@ -47,11 +54,31 @@ impl Torrent {
         from_bytes::<Torrent>(&encoded_content).unwrap()
     }
 
-    fn get_file_length(&self) -> usize {
-        match self.info.keys {
-            Single { length } => length,
-            Multiple { .. } => 0,
+    /// total bytes of all files in the torrent
+    pub fn length(&self) -> usize {
+        match &self.info.keys {
+            Single { length } => *length,
+            Multiple { files } => files.iter().map(|f| f.length).sum(),
+        }
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.info.private == Some(1)
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.info.pieces.0.len()
+    }
+
+    /// bytes of piece `index`, only the last piece may be shorter than the piece length
+    pub fn piece_size(&self, index: usize) -> usize {
+        if index + 1 == self.piece_count() {
+            let md = self.length() % self.info.piece_length;
+            if md != 0 {
+                return md;
+            }
         }
+        self.info.piece_length
     }
 
     pub fn format_info(&self) -> String {
@@ -63,7 +90,7 @@ Piece Length: {}
 Piece Hashes:
 {}"#,
             self.announce,
-            self.get_file_length(),
+            self.length(),
             hex::encode(self.info_hash()),
             self.info.piece_length,
             self.info
@@ -79,28 +106,31 @@ Piece Hashes:
 }
 
 #[serde_as]
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Info {
     #[serde(deserialize_with = "bytes_or_string::deserialize")]
     pub name: String,
     #[serde(rename = "piece length")]
     pub piece_length: usize,  // byte size for each piece
     pub pieces: Hashes,
+    /// 1 for private torrents (BEP 27): peers come from the tracker only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<i64>,
     #[serde(flatten)]
     pub keys: Keys,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum Keys {
     Single { length: usize },  // total bytes for file
     Multiple { files: Vec<FileInfo> },
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileInfo {
-    length: usize,
-    path: Vec<String>,
+    pub length: usize,
+    pub path: Vec<String>,
 }
 
 /// peers
@@ -117,3 +147,11 @@ pub struct PeersResponse {
     #[serde(deserialize_with = "peers::deserialize_vec")]
     pub peers: Vec<Peer>,
 }
+
+/// An announce the tracker refused.
+#[derive(Deserialize, Debug)]
+pub struct FailureResponse {
+    #[serde(rename = "failure reason")]
+    #[serde(deserialize_with = "bytes_or_string::deserialize")]
+    pub failure_reason: String,
+}
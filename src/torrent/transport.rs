@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Context, Result};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use crate::torrent::proxy::ProxyConfig;
+use crate::utp::UtpSocket;
+
+// bytes in flight in each direction of an in-memory pipe
+const PIPE_BUFFER: usize = 64 * 1024;
+// connections waiting for `accept` on an in-memory endpoint
+const MEMORY_BACKLOG: usize = 32;
+
+/// A byte stream to a peer, whatever carries it.
+pub trait PeerIo: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> PeerIo for T {}
+
+/// A peer connection over any transport.
+pub type PeerStream = Box<dyn PeerIo>;
+
+/// Opens connections to peers.
+pub trait Transport: Debug + Send + Sync {
+    fn connect(&self, peer: SocketAddr) -> BoxFuture<'_, Result<PeerStream>>;
+}
+
+/// Hands out the connections peers open to us.
+pub trait Acceptor: Send + Sync {
+    fn accept(&self) -> BoxFuture<'_, Result<(PeerStream, SocketAddr)>>;
+
+    fn local_addr(&self) -> Result<SocketAddr>;
+}
+
+impl<T: Transport + ?Sized> Transport for Arc<T> {
+    fn connect(&self, peer: SocketAddr) -> BoxFuture<'_, Result<PeerStream>> {
+        (**self).connect(peer)
+    }
+}
+
+impl<T: Acceptor + ?Sized> Acceptor for Arc<T> {
+    fn accept(&self) -> BoxFuture<'_, Result<(PeerStream, SocketAddr)>> {
+        (**self).accept()
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        (**self).local_addr()
+    }
+}
+
+/// Plain tcp, tunneled through the proxy when it is configured to.
+#[derive(Debug, Clone, Default)]
+pub struct TcpTransport {
+    pub proxy: Option<ProxyConfig>,
+}
+
+impl Transport for TcpTransport {
+    fn connect(&self, peer: SocketAddr) -> BoxFuture<'_, Result<PeerStream>> {
+        async move {
+            let direct = || async move {
+                TcpStream::connect(peer)
+                    .await
+                    .with_context(|| format!("tcp connect to {}", peer))
+            };
+            let conn = match &self.proxy {
+                Some(proxy) if proxy.tunnels_peers() => match proxy.connect(peer).await {
+                    Err(e) if proxy.allow_direct_fallback => {
+                        println!(
+                            "connect to {} through proxy failed, retry directly: {:#}",
+                            peer, e
+                        );
+                        direct().await
+                    }
+                    res => res,
+                },
+                _ => direct().await,
+            }?;
+            Ok(Box::new(conn) as PeerStream)
+        }
+        .boxed()
+    }
+}
+
+impl Acceptor for TcpListener {
+    fn accept(&self) -> BoxFuture<'_, Result<(PeerStream, SocketAddr)>> {
+        async move {
+            let (conn, addr) = TcpListener::accept(self).await?;
+            Ok((Box::new(conn) as PeerStream, addr))
+        }
+        .boxed()
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(TcpListener::local_addr(self)?)
+    }
+}
+
+impl Transport for UtpSocket {
+    fn connect(&self, peer: SocketAddr) -> BoxFuture<'_, Result<PeerStream>> {
+        async move { Ok(Box::new(UtpSocket::connect(self, peer).await?) as PeerStream) }.boxed()
+    }
+}
+
+impl Acceptor for UtpSocket {
+    fn accept(&self) -> BoxFuture<'_, Result<(PeerStream, SocketAddr)>> {
+        async move {
+            let (conn, addr) = UtpSocket::accept(self).await?;
+            Ok((Box::new(conn) as PeerStream, addr))
+        }
+        .boxed()
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        UtpSocket::local_addr(self)
+    }
+}
+
+type Backlog = mpsc::Sender<(DuplexStream, SocketAddr)>;
+
+/// Endpoints connected by in-memory pipes instead of sockets, to run peers against each
+/// other without a network. Clones share the network.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryNetwork {
+    endpoints: Arc<Mutex<HashMap<SocketAddr, Backlog>>>,
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// an endpoint reachable at `addr`, replacing an earlier one there
+    pub fn endpoint(&self, addr: SocketAddr) -> MemoryEndpoint {
+        let (backlog, incoming) = mpsc::channel(MEMORY_BACKLOG);
+        self.endpoints.lock().unwrap().insert(addr, backlog);
+        MemoryEndpoint {
+            addr,
+            network: self.clone(),
+            incoming: tokio::sync::Mutex::new(incoming),
+        }
+    }
+}
+
+/// One host of a `MemoryNetwork`: connects to the others as `addr` and accepts their
+/// connections to `addr`.
+#[derive(Debug)]
+pub struct MemoryEndpoint {
+    addr: SocketAddr,
+    network: MemoryNetwork,
+    incoming: tokio::sync::Mutex<mpsc::Receiver<(DuplexStream, SocketAddr)>>,
+}
+
+impl Transport for MemoryEndpoint {
+    fn connect(&self, peer: SocketAddr) -> BoxFuture<'_, Result<PeerStream>> {
+        async move {
+            let backlog = self.network.endpoints.lock().unwrap().get(&peer).cloned();
+            let backlog = backlog.ok_or_else(|| anyhow!("no endpoint at {}", peer))?;
+            let (ours, theirs) = tokio::io::duplex(PIPE_BUFFER);
+            backlog
+                .try_send((theirs, self.addr))
+                .map_err(|_| anyhow!("connection to {} refused", peer))?;
+            Ok(Box::new(ours) as PeerStream)
+        }
+        .boxed()
+    }
+}
+
+impl Acceptor for MemoryEndpoint {
+    fn accept(&self) -> BoxFuture<'_, Result<(PeerStream, SocketAddr)>> {
+        async move {
+            let (conn, addr) = self
+                .incoming
+                .lock()
+                .await
+                .recv()
+                .await
+                .context("memory endpoint closed")?;
+            Ok((Box::new(conn) as PeerStream, addr))
+        }
+        .boxed()
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+impl Drop for MemoryEndpoint {
+    fn drop(&mut self) {
+        self.incoming.get_mut().close();
+        let mut endpoints = self.network.endpoints.lock().unwrap();
+        // unless another endpoint took the address over
+        if endpoints.get(&self.addr).is_some_and(|b| b.is_closed()) {
+            endpoints.remove(&self.addr);
+        }
+    }
+}
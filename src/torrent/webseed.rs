@@ -0,0 +1,306 @@
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context, Result};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::StatusCode;
+use url::Url;
+
+use crate::torrent::proxy::{HttpClient, ProxyConfig};
+use crate::torrent::storage::FileLayout;
+use crate::torrent::torrent::{Keys, Torrent};
+
+// a range request taking longer fails
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+// characters of file names left as they are in urls
+const PATH_SAFE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// A web seed (BEP 19): an HTTP server with the torrent's files below `url`, which we
+/// download byte ranges of the torrent from.
+#[derive(Debug, Clone)]
+pub struct WebSeed {
+    url: String,
+    client: HttpClient,
+    layout: FileLayout,
+    // url of every file, in layout order
+    file_urls: Vec<Url>,
+}
+
+impl WebSeed {
+    /// requests go through `proxy` when given
+    pub fn new(url: &str, torrent: &Torrent, proxy: Option<&ProxyConfig>) -> Result<Self> {
+        let file_urls = file_urls(url, torrent)
+            .iter()
+            .map(|u| Url::parse(u).with_context(|| format!("invalid web seed url {}", u)))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            url: url.to_string(),
+            client: HttpClient::new(proxy, Some(REQUEST_TIMEOUT))?,
+            // only offsets and lengths are used, not the paths
+            layout: FileLayout::new(torrent, Path::new(&torrent.info.name))?,
+            file_urls,
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// bytes `[start, start + length)` of the torrent's data, with a range request for
+    /// every file the range overlaps
+    pub async fn fetch(&self, start: u64, length: u64) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(length as usize);
+        for (file, offset, len) in self.layout.segments(start, length) {
+            let part = self.fetch_file(file, offset, len).await?;
+            data.extend_from_slice(&part);
+        }
+        ensure!(
+            data.len() as u64 == length,
+            "range {}+{} is beyond the torrent",
+            start,
+            length
+        );
+        Ok(data)
+    }
+
+    async fn fetch_file(&self, file: usize, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let url = &self.file_urls[file];
+        let (status, body) = self
+            .client
+            .get(url, Some((offset, offset + length - 1)))
+            .await
+            .with_context(|| format!("web seed {}", url))?;
+        let (from, to) = (offset as usize, (offset + length) as usize);
+        match status {
+            StatusCode::PARTIAL_CONTENT => {
+                ensure!(
+                    body.len() as u64 == length,
+                    "web seed {} sent {} bytes instead of {}",
+                    url,
+                    body.len(),
+                    length
+                );
+                Ok(body.to_vec())
+            }
+            // servers without range support send the whole file
+            StatusCode::OK if body.len() >= to => Ok(body[from..to].to_vec()),
+            StatusCode::OK => bail!("web seed {} sent a short file", url),
+            status => bail!("web seed {} answered {}", url, status),
+        }
+    }
+}
+
+// a single file is at `url` itself unless it ends with a slash, the files of a
+// multi-file torrent are below `url/name/`
+fn file_urls(url: &str, torrent: &Torrent) -> Vec<String> {
+    let encode = |part: &str| utf8_percent_encode(part, PATH_SAFE).to_string();
+    let base = if url.ends_with('/') {
+        format!("{}{}", url, encode(&torrent.info.name))
+    } else if matches!(torrent.info.keys, Keys::Single { .. }) {
+        url.to_string()
+    } else {
+        format!("{}/{}", url, encode(&torrent.info.name))
+    };
+    match &torrent.info.keys {
+        Keys::Single { .. } => vec![base],
+        Keys::Multiple { files } => files
+            .iter()
+            .map(|f| {
+                let path: Vec<String> = f.path.iter().map(|p| encode(p)).collect();
+                format!("{}/{}", base, path.join("/"))
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::torrent::serde::hashes::Hashes;
+    use crate::torrent::torrent::{FileInfo, Info};
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Server {
+        // answers range requests with 206
+        Ranges,
+        // ignores ranges and sends whole files with 200
+        WholeFiles,
+        // a byte short of the range
+        ShortRanges,
+        // a byte beyond the range
+        LongRanges,
+        // half of the file
+        ShortFiles,
+    }
+
+    type Files = Arc<HashMap<String, Vec<u8>>>;
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 13 + i / 509) as u8).collect()
+    }
+
+    fn torrent(name: &str, keys: Keys) -> Torrent {
+        Torrent {
+            announce: String::new(),
+            created_by: String::new(),
+            url_list: Vec::new(),
+            info: Info {
+                name: name.to_string(),
+                piece_length: 16384,
+                // web seeds don't check hashes
+                pieces: Hashes(Vec::new()),
+                private: None,
+                keys,
+            },
+        }
+    }
+
+    fn multi_file_torrent(files: &[(&str, Vec<u8>)]) -> Torrent {
+        let files = files
+            .iter()
+            .map(|(name, data)| FileInfo {
+                length: data.len(),
+                path: vec![name.to_string()],
+            })
+            .collect();
+        torrent("my album", Keys::Multiple { files })
+    }
+
+    // where the server keeps the files of `multi_file_torrent`
+    fn album_paths(files: &[(&str, Vec<u8>)]) -> Vec<(String, Vec<u8>)> {
+        files
+            .iter()
+            .map(|(name, data)| (format!("/my%20album/{}", name), data.clone()))
+            .collect()
+    }
+
+    // an http server with `files` by path, returns its url
+    async fn serve(files: Vec<(String, Vec<u8>)>, server: Server) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let files: Files = Arc::new(files.into_iter().collect());
+        tokio::spawn(async move {
+            loop {
+                let (conn, _) = listener.accept().await.unwrap();
+                tokio::spawn(respond(conn, files.clone(), server));
+            }
+        });
+        url
+    }
+
+    async fn respond(conn: TcpStream, files: Files, server: Server) {
+        let mut conn = BufReader::new(conn);
+        let mut path = String::new();
+        let mut range = None;
+        let mut line = String::new();
+        // up to the empty line ending the headers
+        while conn.read_line(&mut line).await.unwrap() > 2 {
+            if let Some(rest) = line.strip_prefix("GET ") {
+                path = rest.split(' ').next().unwrap().to_string();
+            }
+            if let Some(value) = line.to_ascii_lowercase().strip_prefix("range: bytes=") {
+                let (first, last) = value.trim().split_once('-').unwrap();
+                range = Some((
+                    first.parse::<usize>().unwrap(),
+                    last.parse::<usize>().unwrap(),
+                ));
+            }
+            line.clear();
+        }
+        let ranges = !matches!(server, Server::WholeFiles | Server::ShortFiles);
+        let (status, body) = match (files.get(&path), range) {
+            (None, _) => ("404 Not Found", Vec::new()),
+            (Some(data), Some((first, last))) if ranges => {
+                let mut body = data[first..=last.min(data.len() - 1)].to_vec();
+                match server {
+                    Server::ShortRanges => drop(body.pop()),
+                    Server::LongRanges => body.push(0),
+                    _ => {}
+                }
+                ("206 Partial Content", body)
+            }
+            (Some(data), _) => {
+                let mut body = data.clone();
+                if server == Server::ShortFiles {
+                    body.truncate(data.len() / 2);
+                }
+                ("200 OK", body)
+            }
+        };
+        let head = format!(
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            body.len()
+        );
+        let mut conn = conn.into_inner();
+        conn.write_all(head.as_bytes()).await.unwrap();
+        conn.write_all(&body).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn fetches_ranges_of_a_single_file() {
+        let data = content(100_000);
+        let torrent = torrent("data.bin", Keys::Single { length: data.len() });
+        for server in [Server::Ranges, Server::WholeFiles] {
+            let url = serve(vec![("/data.bin".to_string(), data.clone())], server).await;
+            // the file at the url itself, or named after the torrent below it
+            for url in [format!("{}/data.bin", url), format!("{}/", url)] {
+                let seed = WebSeed::new(&url, &torrent, None).unwrap();
+                assert_eq!(seed.fetch(0, 16384).await.unwrap(), data[..16384]);
+                assert_eq!(seed.fetch(99_000, 1000).await.unwrap(), data[99_000..]);
+                assert!(seed.fetch(99_000, 1001).await.is_err());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn fetches_ranges_spanning_files() {
+        let files = vec![
+            ("a", content(10)),
+            ("b", content(20_000)),
+            ("c", content(5000)),
+        ];
+        let all: Vec<u8> = files.iter().flat_map(|(_, d)| d.clone()).collect();
+        let torrent = multi_file_torrent(&files);
+        for server in [Server::Ranges, Server::WholeFiles] {
+            let url = serve(album_paths(&files), server).await;
+            let seed = WebSeed::new(&url, &torrent, None).unwrap();
+            assert_eq!(seed.fetch(5, 20_010).await.unwrap(), all[5..20_015]);
+            assert_eq!(seed.fetch(10, 20_000).await.unwrap(), all[10..20_010]);
+            assert_eq!(seed.fetch(20_009, 2).await.unwrap(), all[20_009..20_011]);
+            assert_eq!(seed.fetch(0, all.len() as u64).await.unwrap(), all);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_lengths_and_missing_files() {
+        let files = vec![("a", content(3000)), ("b", content(3000))];
+        let torrent = multi_file_torrent(&files);
+        for server in [Server::ShortRanges, Server::LongRanges, Server::ShortFiles] {
+            let url = serve(album_paths(&files), server).await;
+            let seed = WebSeed::new(&url, &torrent, None).unwrap();
+            let err = seed.fetch(2000, 2000).await.unwrap_err();
+            assert!(
+                format!("{:#}", err).contains("sent"),
+                "{:?} {:#}",
+                server,
+                err
+            );
+        }
+        // files the server doesn't have
+        let url = serve(Vec::new(), Server::Ranges).await;
+        let seed = WebSeed::new(&url, &torrent, None).unwrap();
+        let err = seed.fetch(0, 100).await.unwrap_err();
+        assert!(format!("{:#}", err).contains("404"), "{:#}", err);
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{ensure, Context, Result};
+
+use crate::tracker::swarm::SwarmTable;
+
+pub mod http;
+pub mod swarm;
+pub mod udp;
+
+/// Settings of the built-in tracker, both protocols share one swarm table.
+#[derive(Debug, Clone)]
+pub struct TrackerConfig {
+    pub http_addr: Option<SocketAddr>,
+    pub udp_addr: Option<SocketAddr>,
+    /// announce interval sent to clients
+    pub interval: Duration,
+    /// peers that haven't announced for this long are dropped
+    pub peer_ttl: Duration,
+    /// upper bound of peers returned by one announce
+    pub max_numwant: usize,
+    /// when set, only these info hashes are tracked
+    pub whitelist: Option<HashSet<[u8; 20]>>,
+    /// networks whose clients, like loopback ones, may announce another address than
+    /// their own, e.g. a reverse proxy in front of the tracker
+    pub trusted_networks: Vec<(IpAddr, u8)>,
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        Self {
+            http_addr: Some(SocketAddr::from(([0, 0, 0, 0], 6969))),
+            udp_addr: Some(SocketAddr::from(([0, 0, 0, 0], 6969))),
+            interval: Duration::from_secs(1800),
+            peer_ttl: Duration::from_secs(3600),
+            max_numwant: 200,
+            whitelist: None,
+            trusted_networks: Vec::new(),
+        }
+    }
+}
+
+impl TrackerConfig {
+    /// read a whitelist file, one hex encoded info hash per line
+    pub fn load_whitelist(path: &str) -> Result<HashSet<[u8; 20]>> {
+        let content =
+            std::fs::read_to_string(path).with_context(|| format!("read whitelist {}", path))?;
+        content
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| {
+                let bytes = hex::decode(l).with_context(|| format!("invalid info hash {}", l))?;
+                <[u8; 20]>::try_from(bytes.as_slice())
+                    .with_context(|| format!("info hash {} is not 20 bytes", l))
+            })
+            .collect()
+    }
+
+    /// parse a network like `10.0.0.0/8`, a bare address is a network of its own
+    pub fn parse_network(s: &str) -> Result<(IpAddr, u8)> {
+        let (ip, prefix) = s.split_once('/').unwrap_or((s, ""));
+        let ip: IpAddr = ip.parse().with_context(|| format!("invalid network {}", s))?;
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix = if prefix.is_empty() {
+            max
+        } else {
+            prefix.parse().with_context(|| format!("invalid network {}", s))?
+        };
+        ensure!(prefix <= max, "invalid prefix length in {}", s);
+        Ok((ip, prefix))
+    }
+
+    /// whether a client at `remote` may announce another address than its own
+    pub fn trusts(&self, remote: IpAddr) -> bool {
+        remote.is_loopback()
+            || self
+                .trusted_networks
+                .iter()
+                .any(|(net, prefix)| in_network(remote, *net, *prefix))
+    }
+}
+
+fn in_network(ip: IpAddr, net: IpAddr, prefix: u8) -> bool {
+    let (ip, net, bits) = match (ip, net) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => (u32::from(ip) as u128, u32::from(net) as u128, 32),
+        (IpAddr::V6(ip), IpAddr::V6(net)) => (u128::from(ip), u128::from(net), 128),
+        _ => return false,
+    };
+    let shift = bits - prefix as u32;
+    shift >= bits || ip >> shift == net >> shift
+}
+
+// expired peers are dropped at most this often
+const MIN_EXPIRE_PERIOD: Duration = Duration::from_secs(1);
+
+pub type SharedSwarms = Arc<Mutex<SwarmTable>>;
+
+/// Run the http and udp trackers until one of them fails.
+pub async fn run(config: TrackerConfig) -> Result<()> {
+    ensure!(!config.peer_ttl.is_zero(), "peer ttl must be positive");
+    let swarms: SharedSwarms = Arc::new(Mutex::new(SwarmTable::new(
+        config.peer_ttl,
+        config.whitelist.clone(),
+    )));
+
+    let mut tasks = tokio::task::JoinSet::new();
+    if let Some(addr) = config.http_addr {
+        let server = http::HttpTracker::bind(addr, config.clone(), swarms.clone()).await?;
+        println!("http tracker listening on {}", server.local_addr()?);
+        tasks.spawn(server.serve());
+    }
+    if let Some(addr) = config.udp_addr {
+        let server = udp::UdpTracker::bind(addr, config.clone(), swarms.clone()).await?;
+        println!("udp tracker listening on {}", server.local_addr()?);
+        tasks.spawn(server.serve());
+    }
+    {
+        let swarms = swarms.clone();
+        let period = (config.peer_ttl / 4).max(MIN_EXPIRE_PERIOD);
+        tasks.spawn(async move {
+            let mut ticker = tokio::time::interval(period);
+            loop {
+                ticker.tick().await;
+                swarms.lock().unwrap().expire();
+            }
+        });
+    }
+
+    match tasks.join_next().await {
+        Some(res) => res.context("tracker task panicked")?,
+        None => Ok(()),
+    }
+}
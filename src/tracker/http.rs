@@ -0,0 +1,500 @@
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::torrent::serde::peers;
+use crate::tracker::swarm::{AnnounceRequest, AnnounceResult, Event};
+use crate::tracker::{SharedSwarms, TrackerConfig};
+
+const MAX_REQUEST: usize = 8 * 1024;
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// HTTP tracker serving `/announce` and `/scrape`
+pub struct HttpTracker {
+    listener: TcpListener,
+    config: TrackerConfig,
+    swarms: SharedSwarms,
+}
+
+#[derive(Serialize)]
+struct FailureResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason: String,
+}
+
+#[derive(Serialize)]
+struct AnnounceResponse {
+    interval: u64,
+    #[serde(rename = "min interval")]
+    min_interval: u64,
+    complete: usize,
+    incomplete: usize,
+    peers: PeerList,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peers6: Option<ByteBuf>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum PeerList {
+    Compact(ByteBuf),
+    Dicts(Vec<PeerDict>),
+}
+
+#[derive(Serialize)]
+struct PeerDict {
+    #[serde(rename = "peer id")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peer_id: Option<ByteBuf>,
+    ip: String,
+    port: u16,
+}
+
+#[derive(Serialize)]
+struct ScrapeResponse {
+    files: BTreeMap<ByteBuf, ScrapeFile>,
+}
+
+#[derive(Serialize)]
+struct ScrapeFile {
+    complete: usize,
+    downloaded: usize,
+    incomplete: usize,
+}
+
+impl HttpTracker {
+    pub async fn bind(addr: SocketAddr, config: TrackerConfig, swarms: SharedSwarms) -> Result<Self> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("bind http tracker on {}", addr))?;
+        Ok(Self {
+            listener,
+            config,
+            swarms,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    pub async fn serve(self) -> Result<()> {
+        loop {
+            // e.g. out of file descriptors, which passes once connections close
+            let (conn, remote) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    println!("http tracker: accept failed: {}", e);
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+            let config = self.config.clone();
+            let swarms = self.swarms.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_conn(conn, remote, &config, &swarms).await {
+                    println!("http tracker: {} {:#}", remote, e);
+                }
+            });
+        }
+    }
+}
+
+async fn handle_conn(
+    mut conn: TcpStream,
+    remote: SocketAddr,
+    config: &TrackerConfig,
+    swarms: &SharedSwarms,
+) -> Result<()> {
+    let request = tokio::time::timeout(READ_TIMEOUT, read_request(&mut conn))
+        .await
+        .context("read request timeout")??;
+    let target = request
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .ok_or_else(|| anyhow!("malformed request line"))?;
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let params = parse_query(query);
+
+    let (status, body) = match path {
+        "/announce" => (
+            "200 OK",
+            announce(&params, remote, config, swarms).unwrap_or_else(|reason| failure(&reason)),
+        ),
+        "/scrape" => ("200 OK", scrape(&params, swarms)),
+        _ => ("404 Not Found", failure("not found")),
+    };
+    let header = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    conn.write_all(header.as_bytes()).await?;
+    conn.write_all(&body).await?;
+    conn.shutdown().await?;
+    Ok(())
+}
+
+/// read the request head, the body is ignored since trackers only get GET requests
+async fn read_request(conn: &mut TcpStream) -> Result<String> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = conn.read(&mut chunk).await?;
+        anyhow::ensure!(n > 0, "connection closed before end of request");
+        buf.extend_from_slice(&chunk[..n]);
+        anyhow::ensure!(buf.len() <= MAX_REQUEST, "request too large");
+    }
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+/// query values are percent decoded to raw bytes, info_hash and peer_id are binary
+fn parse_query(query: &str) -> Vec<(String, Vec<u8>)> {
+    query
+        .split('&')
+        .filter(|kv| !kv.is_empty())
+        .map(|kv| {
+            let (k, v) = kv.split_once('=').unwrap_or((kv, ""));
+            (
+                percent_decode_str(k).decode_utf8_lossy().into_owned(),
+                percent_decode_str(v).collect(),
+            )
+        })
+        .collect()
+}
+
+fn param<'a>(params: &'a [(String, Vec<u8>)], name: &str) -> Option<&'a [u8]> {
+    params
+        .iter()
+        .find(|(k, _)| k == name)
+        .map(|(_, v)| v.as_slice())
+}
+
+fn param_str(params: &[(String, Vec<u8>)], name: &str) -> Option<String> {
+    param(params, name).map(|v| String::from_utf8_lossy(v).into_owned())
+}
+
+fn param_num<T: std::str::FromStr>(params: &[(String, Vec<u8>)], name: &str) -> Option<T> {
+    param_str(params, name).and_then(|v| v.parse().ok())
+}
+
+fn param_20(params: &[(String, Vec<u8>)], name: &str) -> Result<[u8; 20], String> {
+    param(params, name)
+        .and_then(|v| <[u8; 20]>::try_from(v).ok())
+        .ok_or_else(|| format!("missing or invalid {}", name))
+}
+
+fn announce(
+    params: &[(String, Vec<u8>)],
+    remote: SocketAddr,
+    config: &TrackerConfig,
+    swarms: &SharedSwarms,
+) -> Result<Vec<u8>, String> {
+    let info_hash = param_20(params, "info_hash")?;
+    let peer_id = param_20(params, "peer_id")?;
+    let port: u16 = param_num(params, "port").ok_or("missing or invalid port")?;
+    // others could register third parties as peers
+    let ip = param_str(params, "ip")
+        .filter(|_| config.trusts(remote.ip()))
+        .and_then(|ip| ip.parse::<IpAddr>().ok())
+        .unwrap_or(remote.ip());
+    let req = AnnounceRequest {
+        info_hash,
+        peer_id,
+        addr: SocketAddr::new(ip, port),
+        left: param_num(params, "left").unwrap_or(0),
+        event: Event::parse(&param_str(params, "event").unwrap_or_default()),
+        numwant: param_num(params, "numwant")
+            .unwrap_or(50)
+            .min(config.max_numwant),
+    };
+    let compact = param_str(params, "compact").as_deref() != Some("0");
+    let no_peer_id = param_str(params, "no_peer_id").as_deref() == Some("1");
+
+    let result = swarms.lock().unwrap().announce(&req)?;
+    let response = announce_response(result, config, compact, no_peer_id);
+    serde_bencode::to_bytes(&response).map_err(|e| e.to_string())
+}
+
+fn announce_response(
+    result: AnnounceResult,
+    config: &TrackerConfig,
+    compact: bool,
+    no_peer_id: bool,
+) -> AnnounceResponse {
+    let (peers, peers6) = if compact {
+        let addrs: Vec<_> = result.peers.into_iter().map(|(_, p)| p).collect();
+        let v6 = peers::to_compact6(&addrs);
+        (
+            PeerList::Compact(ByteBuf::from(peers::to_compact(&addrs))),
+            (!v6.is_empty()).then(|| ByteBuf::from(v6)),
+        )
+    } else {
+        let dicts = result
+            .peers
+            .into_iter()
+            .map(|(id, p)| PeerDict {
+                peer_id: (!no_peer_id).then(|| ByteBuf::from(id.to_vec())),
+                ip: p.0,
+                port: p.1,
+            })
+            .collect();
+        (PeerList::Dicts(dicts), None)
+    };
+    let interval = config.interval.as_secs();
+    AnnounceResponse {
+        interval,
+        min_interval: interval / 2,
+        complete: result.complete,
+        incomplete: result.incomplete,
+        peers,
+        peers6,
+    }
+}
+
+fn scrape(params: &[(String, Vec<u8>)], swarms: &SharedSwarms) -> Vec<u8> {
+    let info_hashes: Vec<[u8; 20]> = params
+        .iter()
+        .filter(|(k, _)| k == "info_hash")
+        .filter_map(|(_, v)| <[u8; 20]>::try_from(v.as_slice()).ok())
+        .collect();
+    let files = swarms
+        .lock()
+        .unwrap()
+        .scrape(&info_hashes)
+        .into_iter()
+        .map(|(hash, stats)| {
+            (
+                ByteBuf::from(hash.to_vec()),
+                ScrapeFile {
+                    complete: stats.complete,
+                    downloaded: stats.downloaded,
+                    incomplete: stats.incomplete,
+                },
+            )
+        })
+        .collect();
+    serde_bencode::to_bytes(&ScrapeResponse { files }).unwrap_or_else(|e| failure(&e.to_string()))
+}
+
+fn failure(reason: &str) -> Vec<u8> {
+    serde_bencode::to_bytes(&FailureResponse {
+        failure_reason: reason.to_string(),
+    })
+    .expect("failure response is always encodable")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+
+    use serde_bencode::value::Value;
+
+    use super::*;
+    use crate::torrent::serde::peers::{from_compact, from_compact6};
+    use crate::tracker::swarm::SwarmTable;
+
+    const INFO_HASH: [u8; 20] = [1; 20];
+
+    fn swarms(config: &TrackerConfig) -> SharedSwarms {
+        Arc::new(Mutex::new(SwarmTable::new(
+            config.peer_ttl,
+            config.whitelist.clone(),
+        )))
+    }
+
+    async fn tracker(config: TrackerConfig) -> SocketAddr {
+        let swarms = swarms(&config);
+        let server = HttpTracker::bind(SocketAddr::from(([127, 0, 0, 1], 0)), config, swarms)
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.serve());
+        addr
+    }
+
+    fn encode(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("%{:02X}", b)).collect()
+    }
+
+    // announce of peer `id` on port 6000 + id
+    fn announce_target(id: u8, params: &str) -> String {
+        format!(
+            "/announce?info_hash={}&peer_id={}&port={}&{}",
+            encode(&INFO_HASH),
+            encode(&[id; 20]),
+            6000 + id as u16,
+            params
+        )
+    }
+
+    async fn get(addr: SocketAddr, target: &str) -> Value {
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: tracker\r\n\r\n", target);
+        conn.write_all(request.as_bytes()).await.unwrap();
+        let mut resp = Vec::new();
+        conn.read_to_end(&mut resp).await.unwrap();
+        let body = resp.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        serde_bencode::from_bytes(&resp[body..]).unwrap()
+    }
+
+    fn field<'a>(value: &'a Value, name: &str) -> Option<&'a Value> {
+        match value {
+            Value::Dict(dict) => dict.get(name.as_bytes()),
+            _ => None,
+        }
+    }
+
+    fn bytes<'a>(value: &'a Value, name: &str) -> &'a [u8] {
+        match field(value, name) {
+            Some(Value::Bytes(b)) => b,
+            other => panic!("{} is {:?}", name, other),
+        }
+    }
+
+    fn int(value: &Value, name: &str) -> i64 {
+        match field(value, name) {
+            Some(Value::Int(n)) => *n,
+            other => panic!("{} is {:?}", name, other),
+        }
+    }
+
+    #[tokio::test]
+    async fn returns_compact_and_dict_peers() {
+        let addr = tracker(TrackerConfig::default()).await;
+        // loopback clients may announce any address
+        get(addr, &announce_target(1, "left=100&ip=10.0.0.1")).await;
+        get(addr, &announce_target(2, "left=0&ip=2001:db8::2")).await;
+
+        let resp = get(addr, &announce_target(3, "left=100")).await;
+        assert_eq!(int(&resp, "interval"), 1800);
+        assert_eq!((int(&resp, "complete"), int(&resp, "incomplete")), (1, 2));
+        assert_eq!(
+            from_compact(bytes(&resp, "peers")),
+            ["10.0.0.1:6001".parse().unwrap()]
+        );
+        assert_eq!(
+            from_compact6(bytes(&resp, "peers6")),
+            ["[2001:db8::2]:6002".parse().unwrap()]
+        );
+
+        for (params, with_ids) in [("compact=0", true), ("compact=0&no_peer_id=1", false)] {
+            let resp = get(addr, &announce_target(3, &format!("left=100&{}", params))).await;
+            let Some(Value::List(peers)) = field(&resp, "peers") else {
+                panic!("peers are not a list: {:?}", resp);
+            };
+            assert!(field(&resp, "peers6").is_none());
+            let found: HashSet<(String, i64, Option<u8>)> = peers
+                .iter()
+                .map(|p| {
+                    let ip = String::from_utf8(bytes(p, "ip").to_vec()).unwrap();
+                    let id = field(p, "peer id").map(|_| bytes(p, "peer id")[0]);
+                    (ip, int(p, "port"), id)
+                })
+                .collect();
+            let expected = HashSet::from([
+                ("10.0.0.1".to_string(), 6001, with_ids.then_some(1)),
+                ("2001:db8::2".to_string(), 6002, with_ids.then_some(2)),
+            ]);
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn stopped_peers_leave_the_swarm() {
+        let addr = tracker(TrackerConfig::default()).await;
+        get(addr, &announce_target(1, "left=100&event=started")).await;
+        let resp = get(addr, &announce_target(1, "left=100&event=stopped")).await;
+        assert!(bytes(&resp, "peers").is_empty());
+        let resp = get(addr, &announce_target(2, "left=100&event=started")).await;
+        assert!(bytes(&resp, "peers").is_empty());
+        assert_eq!(int(&resp, "incomplete"), 1);
+    }
+
+    #[tokio::test]
+    async fn scrapes_swarm_counts() {
+        let addr = tracker(TrackerConfig::default()).await;
+        get(addr, &announce_target(1, "left=100&event=started")).await;
+        get(addr, &announce_target(2, "left=100&event=started")).await;
+        get(addr, &announce_target(2, "left=0&event=completed")).await;
+        get(addr, &announce_target(3, "left=0")).await;
+
+        let other = [2u8; 20];
+        let target = format!(
+            "/scrape?info_hash={}&info_hash={}",
+            encode(&INFO_HASH),
+            encode(&other)
+        );
+        let resp = get(addr, &target).await;
+        let Some(Value::Dict(files)) = field(&resp, "files") else {
+            panic!("no files in {:?}", resp);
+        };
+        let stats = &files[INFO_HASH.as_slice()];
+        assert_eq!(int(stats, "complete"), 2);
+        assert_eq!(int(stats, "downloaded"), 1);
+        assert_eq!(int(stats, "incomplete"), 1);
+        assert_eq!(int(&files[other.as_slice()], "complete"), 0);
+    }
+
+    #[tokio::test]
+    async fn refuses_info_hashes_off_the_whitelist() {
+        let config = TrackerConfig {
+            whitelist: Some(HashSet::from([[9; 20]])),
+            ..TrackerConfig::default()
+        };
+        let addr = tracker(config).await;
+        let resp = get(addr, &announce_target(1, "left=100")).await;
+        assert_eq!(
+            bytes(&resp, "failure reason"),
+            b"info hash is not tracked by this tracker"
+        );
+        let resp = get(addr, "/announce?port=1").await;
+        assert!(String::from_utf8_lossy(bytes(&resp, "failure reason")).contains("info_hash"));
+    }
+
+    // the address peer 2 learns for peer 1 when it announced `ip` from `remote`
+    fn announced_addr(config: &TrackerConfig, remote: &str, ip: &str) -> SocketAddr {
+        let swarms = swarms(config);
+        let remote: SocketAddr = remote.parse().unwrap();
+        let first = announce_target(1, &format!("left=100&ip={}", ip));
+        let params = parse_query(first.split_once('?').unwrap().1);
+        announce(&params, remote, config, &swarms).unwrap();
+        let second = announce_target(2, "left=100");
+        let params = parse_query(second.split_once('?').unwrap().1);
+        let body = announce(&params, remote, config, &swarms).unwrap();
+        let resp: Value = serde_bencode::from_bytes(&body).unwrap();
+        from_compact(bytes(&resp, "peers"))[0]
+    }
+
+    #[test]
+    fn honors_the_ip_param_from_trusted_networks_only() {
+        let mut config = TrackerConfig::default();
+        assert_eq!(
+            announced_addr(&config, "192.0.2.7:40000", "10.9.9.9"),
+            "192.0.2.7:6001".parse().unwrap()
+        );
+        assert_eq!(
+            announced_addr(&config, "127.0.0.1:40000", "10.9.9.9"),
+            "10.9.9.9:6001".parse().unwrap()
+        );
+        config.trusted_networks = vec![TrackerConfig::parse_network("192.0.2.0/24").unwrap()];
+        assert_eq!(
+            announced_addr(&config, "192.0.2.7:40000", "10.9.9.9"),
+            "10.9.9.9:6001".parse().unwrap()
+        );
+        assert_eq!(
+            announced_addr(&config, "198.51.100.1:40000", "10.9.9.9"),
+            "198.51.100.1:6001".parse().unwrap()
+        );
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::torrent::serde::peers::Peer;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    None,
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl Event {
+    pub fn parse(s: &str) -> Self {
+        match s {
+            "started" => Event::Started,
+            "completed" => Event::Completed,
+            "stopped" => Event::Stopped,
+            _ => Event::None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AnnounceRequest {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub addr: SocketAddr,
+    pub left: u64,
+    pub event: Event,
+    pub numwant: usize,
+}
+
+#[derive(Debug)]
+pub struct AnnounceResult {
+    pub complete: usize,
+    pub incomplete: usize,
+    /// peer id and address of the other peers in the swarm
+    pub peers: Vec<([u8; 20], Peer)>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ScrapeStats {
+    pub complete: usize,
+    pub downloaded: usize,
+    pub incomplete: usize,
+}
+
+#[derive(Debug)]
+struct PeerEntry {
+    addr: SocketAddr,
+    left: u64,
+    last_seen: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Swarm {
+    peers: HashMap<[u8; 20], PeerEntry>,
+    // number of `completed` events seen
+    downloaded: usize,
+}
+
+impl Swarm {
+    fn stats(&self) -> ScrapeStats {
+        let complete = self.peers.values().filter(|p| p.left == 0).count();
+        ScrapeStats {
+            complete,
+            downloaded: self.downloaded,
+            incomplete: self.peers.len() - complete,
+        }
+    }
+}
+
+/// In-memory swarm state keyed by info hash.
+#[derive(Debug)]
+pub struct SwarmTable {
+    swarms: HashMap<[u8; 20], Swarm>,
+    whitelist: Option<HashSet<[u8; 20]>>,
+    peer_ttl: Duration,
+}
+
+impl SwarmTable {
+    pub fn new(peer_ttl: Duration, whitelist: Option<HashSet<[u8; 20]>>) -> Self {
+        Self {
+            swarms: HashMap::new(),
+            whitelist,
+            peer_ttl,
+        }
+    }
+
+    pub fn is_allowed(&self, info_hash: &[u8; 20]) -> bool {
+        self.whitelist
+            .as_ref()
+            .is_none_or(|allowed| allowed.contains(info_hash))
+    }
+
+    /// record the announcing peer and return up to `numwant` other peers,
+    /// fails with the reason for the client if the info hash isn't allowed
+    pub fn announce(&mut self, req: &AnnounceRequest) -> Result<AnnounceResult, String> {
+        if !self.is_allowed(&req.info_hash) {
+            return Err("info hash is not tracked by this tracker".to_string());
+        }
+        let swarm = self.swarms.entry(req.info_hash).or_default();
+        if req.event == Event::Stopped {
+            swarm.peers.remove(&req.peer_id);
+        } else {
+            if req.event == Event::Completed {
+                swarm.downloaded += 1;
+            }
+            swarm.peers.insert(
+                req.peer_id,
+                PeerEntry {
+                    addr: req.addr,
+                    left: req.left,
+                    last_seen: Instant::now(),
+                },
+            );
+        }
+
+        let stats = swarm.stats();
+        // a peer leaving the swarm needs no others
+        let numwant = if req.event == Event::Stopped {
+            0
+        } else {
+            req.numwant
+        };
+        let peers = swarm
+            .peers
+            .iter()
+            .filter(|(id, _)| **id != req.peer_id)
+            // seeders don't need other seeders
+            .filter(|(_, p)| req.left > 0 || p.left > 0)
+            .take(numwant)
+            .map(|(id, p)| (*id, Peer::from(p.addr)))
+            .collect();
+        Ok(AnnounceResult {
+            complete: stats.complete,
+            incomplete: stats.incomplete,
+            peers,
+        })
+    }
+
+    /// stats of the requested info hashes in request order, all tracked torrents if none are given
+    pub fn scrape(&self, info_hashes: &[[u8; 20]]) -> Vec<([u8; 20], ScrapeStats)> {
+        if info_hashes.is_empty() {
+            return self.swarms.iter().map(|(h, s)| (*h, s.stats())).collect();
+        }
+        info_hashes
+            .iter()
+            .map(|h| (*h, self.swarms.get(h).map(Swarm::stats).unwrap_or_default()))
+            .collect()
+    }
+
+    /// drop peers that haven't announced within the ttl and empty swarms
+    pub fn expire(&mut self) {
+        let ttl = self.peer_ttl;
+        self.swarms.retain(|_, swarm| {
+            swarm.peers.retain(|_, p| p.last_seen.elapsed() < ttl);
+            !swarm.peers.is_empty() || swarm.downloaded > 0
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INFO_HASH: [u8; 20] = [1; 20];
+
+    fn request(id: u8, left: u64, event: Event) -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: INFO_HASH,
+            peer_id: [id; 20],
+            addr: SocketAddr::from(([10, 0, 0, id], 6881)),
+            left,
+            event,
+            numwant: 50,
+        }
+    }
+
+    fn peer_ids(result: &AnnounceResult) -> HashSet<u8> {
+        result.peers.iter().map(|(id, _)| id[0]).collect()
+    }
+
+    #[test]
+    fn hands_out_the_other_peers() {
+        let mut table = SwarmTable::new(Duration::from_secs(60), None);
+        table.announce(&request(1, 0, Event::Started)).unwrap();
+        table.announce(&request(2, 100, Event::Started)).unwrap();
+        let result = table.announce(&request(3, 100, Event::Started)).unwrap();
+        assert_eq!(peer_ids(&result), HashSet::from([1, 2]));
+        assert_eq!((result.complete, result.incomplete), (1, 2));
+        assert_eq!(result.peers[0].1.socket_addr().unwrap().port(), 6881);
+        // a seeder only gets leechers
+        let result = table.announce(&request(4, 0, Event::Started)).unwrap();
+        assert_eq!(peer_ids(&result), HashSet::from([2, 3]));
+        let mut few = request(5, 100, Event::None);
+        few.numwant = 2;
+        assert_eq!(table.announce(&few).unwrap().peers.len(), 2);
+    }
+
+    #[test]
+    fn stopped_removes_the_peer() {
+        let mut table = SwarmTable::new(Duration::from_secs(60), None);
+        table.announce(&request(1, 100, Event::Started)).unwrap();
+        table.announce(&request(2, 100, Event::Started)).unwrap();
+        let result = table.announce(&request(1, 100, Event::Stopped)).unwrap();
+        assert!(result.peers.is_empty());
+        assert_eq!((result.complete, result.incomplete), (0, 1));
+        let result = table.announce(&request(3, 100, Event::Started)).unwrap();
+        assert_eq!(peer_ids(&result), HashSet::from([2]));
+    }
+
+    #[test]
+    fn expires_silent_peers() {
+        let mut table = SwarmTable::new(Duration::from_millis(50), None);
+        table.announce(&request(1, 100, Event::Started)).unwrap();
+        std::thread::sleep(Duration::from_millis(30));
+        table.announce(&request(2, 100, Event::Started)).unwrap();
+        std::thread::sleep(Duration::from_millis(30));
+        table.expire();
+        assert_eq!(table.scrape(&[INFO_HASH])[0].1.incomplete, 1);
+        std::thread::sleep(Duration::from_millis(30));
+        table.expire();
+        // the swarm is gone with its last peer
+        assert!(table.scrape(&[]).is_empty());
+    }
+
+    #[test]
+    fn counts_for_scrapes() {
+        let mut table = SwarmTable::new(Duration::from_secs(60), None);
+        table.announce(&request(1, 100, Event::Started)).unwrap();
+        table.announce(&request(2, 100, Event::Started)).unwrap();
+        table.announce(&request(3, 0, Event::Started)).unwrap();
+        table.announce(&request(2, 0, Event::Completed)).unwrap();
+        let unknown = [9; 20];
+        let stats = table.scrape(&[unknown, INFO_HASH]);
+        assert_eq!(stats[0].0, unknown);
+        assert_eq!(stats[0].1.complete + stats[0].1.incomplete, 0);
+        let s = stats[1].1;
+        assert_eq!((s.complete, s.downloaded, s.incomplete), (2, 1, 1));
+        // a completed swarm is remembered after its peers left
+        table.announce(&request(1, 100, Event::Stopped)).unwrap();
+        table.announce(&request(2, 0, Event::Stopped)).unwrap();
+        table.announce(&request(3, 0, Event::Stopped)).unwrap();
+        table.expire();
+        assert_eq!(table.scrape(&[]).len(), 1);
+    }
+
+    #[test]
+    fn refuses_info_hashes_off_the_whitelist() {
+        let mut table = SwarmTable::new(Duration::from_secs(60), Some(HashSet::from([[2; 20]])));
+        assert!(table.announce(&request(1, 100, Event::Started)).is_err());
+        let mut allowed = request(1, 100, Event::Started);
+        allowed.info_hash = [2; 20];
+        assert!(table.announce(&allowed).is_ok());
+    }
+}
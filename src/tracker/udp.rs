@@ -0,0 +1,362 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::net::UdpSocket;
+
+use crate::torrent::random;
+use crate::torrent::serde::peers;
+use crate::tracker::swarm::{AnnounceRequest, Event};
+use crate::tracker::{SharedSwarms, TrackerConfig};
+
+/// magic constant identifying a connect request (BEP 15)
+pub const PROTOCOL_ID: u64 = 0x41727101980;
+pub const ACTION_CONNECT: u32 = 0;
+pub const ACTION_ANNOUNCE: u32 = 1;
+pub const ACTION_SCRAPE: u32 = 2;
+pub const ACTION_ERROR: u32 = 3;
+
+// clients may reuse a connection id for up to one minute, keep it a bit longer
+const CONNECTION_TTL: Duration = Duration::from_secs(120);
+const MAX_SCRAPE: usize = 74;
+
+/// UDP tracker protocol (BEP 15)
+pub struct UdpTracker {
+    socket: UdpSocket,
+    config: TrackerConfig,
+    swarms: SharedSwarms,
+    connections: HashMap<u64, Instant>,
+}
+
+impl UdpTracker {
+    pub async fn bind(addr: SocketAddr, config: TrackerConfig, swarms: SharedSwarms) -> Result<Self> {
+        let socket = UdpSocket::bind(addr)
+            .await
+            .with_context(|| format!("bind udp tracker on {}", addr))?;
+        Ok(Self {
+            socket,
+            config,
+            swarms,
+            connections: HashMap::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    pub async fn serve(mut self) -> Result<()> {
+        let mut buf = vec![0u8; 2048];
+        loop {
+            // e.g. icmp port unreachable of an earlier response
+            let Ok((n, remote)) = self.socket.recv_from(&mut buf).await else {
+                continue;
+            };
+            if let Some(resp) = self.handle(&buf[..n], remote) {
+                if let Err(e) = self.socket.send_to(&resp, remote).await {
+                    println!("udp tracker: send to {} {}", remote, e);
+                }
+            }
+        }
+    }
+
+    /// build the response of one datagram, malformed packets are dropped silently
+    fn handle(&mut self, mut packet: &[u8], remote: SocketAddr) -> Option<BytesMut> {
+        if packet.len() < 16 {
+            return None;
+        }
+        let connection_id = packet.get_u64();
+        let action = packet.get_u32();
+        let transaction_id = packet.get_u32();
+
+        if action == ACTION_CONNECT {
+            if connection_id != PROTOCOL_ID {
+                return None;
+            }
+            let id = self.new_connection();
+            let mut resp = BytesMut::with_capacity(16);
+            resp.put_u32(ACTION_CONNECT);
+            resp.put_u32(transaction_id);
+            resp.put_u64(id);
+            return Some(resp);
+        }
+
+        if !self.is_connected(connection_id) {
+            return Some(error(transaction_id, "invalid connection id"));
+        }
+        let resp = match action {
+            ACTION_ANNOUNCE => self.announce(packet, remote, transaction_id),
+            ACTION_SCRAPE => self.scrape(packet, transaction_id),
+            _ => error(transaction_id, "unknown action"),
+        };
+        Some(resp)
+    }
+
+    fn new_connection(&mut self) -> u64 {
+        self.connections
+            .retain(|_, issued| issued.elapsed() < CONNECTION_TTL);
+        let id = random::next_u64();
+        self.connections.insert(id, Instant::now());
+        id
+    }
+
+    fn is_connected(&self, id: u64) -> bool {
+        self.connections
+            .get(&id)
+            .is_some_and(|issued| issued.elapsed() < CONNECTION_TTL)
+    }
+
+    fn announce(&self, mut packet: &[u8], remote: SocketAddr, transaction_id: u32) -> BytesMut {
+        // info_hash + peer_id + downloaded + left + uploaded + event + ip + key + num_want + port
+        if packet.len() < 82 {
+            return error(transaction_id, "announce packet too short");
+        }
+        let mut info_hash = [0u8; 20];
+        packet.copy_to_slice(&mut info_hash);
+        let mut peer_id = [0u8; 20];
+        packet.copy_to_slice(&mut peer_id);
+        let _downloaded = packet.get_u64();
+        let left = packet.get_u64();
+        let _uploaded = packet.get_u64();
+        let event = match packet.get_u32() {
+            1 => Event::Completed,
+            2 => Event::Started,
+            3 => Event::Stopped,
+            _ => Event::None,
+        };
+        let ip = packet.get_u32();
+        let _key = packet.get_u32();
+        let numwant = packet.get_i32();
+        let port = packet.get_u16();
+
+        let ip = if ip != 0 && remote.is_ipv4() && self.config.trusts(remote.ip()) {
+            IpAddr::V4(Ipv4Addr::from(ip))
+        } else {
+            remote.ip()
+        };
+        let numwant = if numwant < 0 { 50 } else { numwant as usize };
+        let req = AnnounceRequest {
+            info_hash,
+            peer_id,
+            addr: SocketAddr::new(ip, port),
+            left,
+            event,
+            numwant: numwant.min(self.config.max_numwant),
+        };
+        let result = match self.swarms.lock().unwrap().announce(&req) {
+            Ok(result) => result,
+            Err(reason) => return error(transaction_id, &reason),
+        };
+
+        let addrs: Vec<_> = result.peers.into_iter().map(|(_, p)| p).collect();
+        // a v6 client gets v6 peers, the address family is implied by the socket
+        let compact = if remote.is_ipv4() {
+            peers::to_compact(&addrs)
+        } else {
+            peers::to_compact6(&addrs)
+        };
+        let mut resp = BytesMut::with_capacity(20 + compact.len());
+        resp.put_u32(ACTION_ANNOUNCE);
+        resp.put_u32(transaction_id);
+        resp.put_u32(self.config.interval.as_secs() as u32);
+        resp.put_u32(result.incomplete as u32);
+        resp.put_u32(result.complete as u32);
+        resp.extend_from_slice(&compact);
+        resp
+    }
+
+    fn scrape(&self, packet: &[u8], transaction_id: u32) -> BytesMut {
+        let info_hashes: Vec<[u8; 20]> = packet
+            .chunks_exact(20)
+            .take(MAX_SCRAPE)
+            .map(|c| c.try_into().expect("guaranteed to be length of 20"))
+            .collect();
+        if info_hashes.is_empty() {
+            return error(transaction_id, "no info hash to scrape");
+        }
+        let stats = self.swarms.lock().unwrap().scrape(&info_hashes);
+        let mut resp = BytesMut::with_capacity(8 + 12 * stats.len());
+        resp.put_u32(ACTION_SCRAPE);
+        resp.put_u32(transaction_id);
+        for (_, s) in stats {
+            resp.put_u32(s.complete as u32);
+            resp.put_u32(s.downloaded as u32);
+            resp.put_u32(s.incomplete as u32);
+        }
+        resp
+    }
+}
+
+fn error(transaction_id: u32, message: &str) -> BytesMut {
+    let mut resp = BytesMut::with_capacity(8 + message.len());
+    resp.put_u32(ACTION_ERROR);
+    resp.put_u32(transaction_id);
+    resp.extend_from_slice(message.as_bytes());
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::torrent::serde::peers::from_compact;
+    use crate::tracker::swarm::SwarmTable;
+
+    const INFO_HASH: [u8; 20] = [1; 20];
+
+    async fn tracker() -> (UdpTracker, UdpSocket) {
+        let config = TrackerConfig::default();
+        let swarms = Arc::new(Mutex::new(SwarmTable::new(config.peer_ttl, None)));
+        let tracker = UdpTracker::bind(SocketAddr::from(([127, 0, 0, 1], 0)), config, swarms)
+            .await
+            .unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(tracker.local_addr().unwrap()).await.unwrap();
+        (tracker, client)
+    }
+
+    async fn exchange(client: &UdpSocket, packet: &[u8]) -> Vec<u8> {
+        client.send(packet).await.unwrap();
+        let mut buf = vec![0u8; 2048];
+        let n = tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buf))
+            .await
+            .expect("no response")
+            .unwrap();
+        buf.truncate(n);
+        buf
+    }
+
+    fn connect_packet(transaction_id: u32) -> BytesMut {
+        let mut packet = BytesMut::new();
+        packet.put_u64(PROTOCOL_ID);
+        packet.put_u32(ACTION_CONNECT);
+        packet.put_u32(transaction_id);
+        packet
+    }
+
+    // announce of peer `id` on port 6000 + id
+    fn announce_packet(connection_id: u64, id: u8, left: u64, event: u32, ip: [u8; 4]) -> BytesMut {
+        let mut packet = BytesMut::new();
+        packet.put_u64(connection_id);
+        packet.put_u32(ACTION_ANNOUNCE);
+        packet.put_u32(id as u32);
+        packet.put_slice(&INFO_HASH);
+        packet.put_slice(&[id; 20]);
+        packet.put_u64(0);
+        packet.put_u64(left);
+        packet.put_u64(0);
+        packet.put_u32(event);
+        packet.put_slice(&ip);
+        packet.put_u32(0);
+        packet.put_i32(-1);
+        packet.put_u16(6000 + id as u16);
+        packet
+    }
+
+    fn scrape_packet(connection_id: u64, info_hashes: &[[u8; 20]]) -> BytesMut {
+        let mut packet = BytesMut::new();
+        packet.put_u64(connection_id);
+        packet.put_u32(ACTION_SCRAPE);
+        packet.put_u32(7);
+        for info_hash in info_hashes {
+            packet.put_slice(info_hash);
+        }
+        packet
+    }
+
+    fn assert_error(mut resp: &[u8], message: &str) {
+        assert_eq!(resp.get_u32(), ACTION_ERROR);
+        resp.get_u32();
+        assert_eq!(resp, message.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn connects_announces_and_scrapes() {
+        let (tracker, client) = tracker().await;
+        tokio::spawn(tracker.serve());
+
+        let mut resp = &exchange(&client, &connect_packet(42)).await[..];
+        assert_eq!(resp.len(), 16);
+        assert_eq!(resp.get_u32(), ACTION_CONNECT);
+        assert_eq!(resp.get_u32(), 42);
+        let id = resp.get_u64();
+
+        exchange(&client, &announce_packet(id, 1, 0, 2, [10, 0, 0, 1])).await;
+        exchange(&client, &announce_packet(id, 2, 100, 2, [0; 4])).await;
+        exchange(&client, &announce_packet(id, 2, 0, 1, [0; 4])).await;
+        let resp = exchange(&client, &announce_packet(id, 3, 100, 2, [0; 4])).await;
+        let mut head = &resp[..20];
+        assert_eq!(head.get_u32(), ACTION_ANNOUNCE);
+        assert_eq!(head.get_u32(), 3);
+        assert_eq!(head.get_u32(), 1800);
+        assert_eq!((head.get_u32(), head.get_u32()), (1, 2));
+        let mut peers = from_compact(&resp[20..]);
+        peers.sort();
+        // loopback clients may announce another address
+        let expected: Vec<SocketAddr> = vec![
+            "10.0.0.1:6001".parse().unwrap(),
+            "127.0.0.1:6002".parse().unwrap(),
+        ];
+        assert_eq!(peers, expected);
+
+        let other = [2u8; 20];
+        let mut resp = &exchange(&client, &scrape_packet(id, &[INFO_HASH, other])).await[..];
+        assert_eq!(resp.len(), 8 + 2 * 12);
+        assert_eq!((resp.get_u32(), resp.get_u32()), (ACTION_SCRAPE, 7));
+        assert_eq!((resp.get_u32(), resp.get_u32(), resp.get_u32()), (2, 1, 1));
+        assert_eq!((resp.get_u32(), resp.get_u32(), resp.get_u32()), (0, 0, 0));
+
+        let resp = exchange(&client, &announce_packet(id ^ 1, 1, 0, 0, [0; 4])).await;
+        assert_error(&resp, "invalid connection id");
+        let resp = exchange(&client, &scrape_packet(id, &[])).await;
+        assert_error(&resp, "no info hash to scrape");
+    }
+
+    #[tokio::test]
+    async fn refuses_stale_connection_ids() {
+        let (mut tracker, _) = tracker().await;
+        let remote = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let mut resp = &tracker.handle(&connect_packet(1), remote).unwrap()[8..];
+        let id = resp.get_u64();
+        let issued = Instant::now().checked_sub(CONNECTION_TTL).unwrap();
+        tracker.connections.insert(id, issued);
+        let resp = tracker
+            .handle(&scrape_packet(id, &[INFO_HASH]), remote)
+            .unwrap();
+        assert_error(&resp, "invalid connection id");
+        // connect requests need the protocol id
+        let mut bad = connect_packet(1);
+        bad[7] ^= 1;
+        assert!(tracker.handle(&bad, remote).is_none());
+    }
+
+    #[tokio::test]
+    async fn honors_the_ip_field_from_trusted_networks_only() {
+        let (mut tracker, _) = tracker().await;
+        let remote = SocketAddr::from(([192, 0, 2, 7], 4000));
+        let mut resp = &tracker.handle(&connect_packet(1), remote).unwrap()[8..];
+        let id = resp.get_u64();
+        tracker.handle(&announce_packet(id, 1, 100, 2, [10, 9, 9, 9]), remote);
+        let resp = tracker
+            .handle(&announce_packet(id, 2, 100, 2, [0; 4]), remote)
+            .unwrap();
+        assert_eq!(
+            from_compact(&resp[20..]),
+            ["192.0.2.7:6001".parse().unwrap()]
+        );
+
+        tracker.config.trusted_networks =
+            vec![TrackerConfig::parse_network("192.0.2.0/24").unwrap()];
+        tracker.handle(&announce_packet(id, 1, 100, 0, [10, 9, 9, 9]), remote);
+        let resp = tracker
+            .handle(&announce_packet(id, 2, 100, 0, [0; 4]), remote)
+            .unwrap();
+        assert_eq!(
+            from_compact(&resp[20..]),
+            ["10.9.9.9:6001".parse().unwrap()]
+        );
+    }
+}
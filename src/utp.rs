@@ -0,0 +1,27 @@
+use std::time::Duration;
+
+pub mod conn;
+pub mod packet;
+pub mod socket;
+pub mod stream;
+
+pub use socket::UtpSocket;
+pub use stream::UtpStream;
+
+/// Settings of uTP connections (BEP 29).
+#[derive(Debug, Clone, Copy)]
+pub struct UtpConfig {
+    /// queueing delay LEDBAT aims for, the window shrinks above it
+    pub target_delay: Duration,
+    /// share of outgoing packets dropped on purpose, to try loss recovery
+    pub simulated_loss: f64,
+}
+
+impl Default for UtpConfig {
+    fn default() -> Self {
+        Self {
+            target_delay: Duration::from_millis(100),
+            simulated_loss: 0.0,
+        }
+    }
+}
@@ -0,0 +1,788 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use tokio::io::ReadBuf;
+use tokio::net::UdpSocket;
+
+use crate::torrent::random;
+use crate::utp::packet::{now_micros, seq_before, Packet, PacketType};
+use crate::utp::UtpConfig;
+
+/// payload bytes per packet, keeps datagrams below common path MTUs
+pub const MAX_PAYLOAD: usize = 1380;
+const MIN_CWND: usize = MAX_PAYLOAD;
+const INITIAL_CWND: usize = 4 * MAX_PAYLOAD;
+const MAX_CWND: usize = 1 << 20;
+// bytes written but not yet sent before writers have to wait
+const SEND_BUFFER: usize = 256 * 1024;
+// bytes received but not yet read, advertised as our window. Packets held out of order
+// count too, anything beyond is dropped.
+const RECV_BUFFER: usize = 1 << 20;
+// packets ahead of the next expected one that are kept
+const MAX_OUT_OF_ORDER: u16 = 1024;
+// bytes of the selective ack bitmask, the packets after the first 256 aren't reported
+const MAX_SACK: usize = 32;
+const MIN_RTO: Duration = Duration::from_millis(500);
+const MAX_RTO: Duration = Duration::from_secs(30);
+// a packet sent this often without an ack means the peer is gone
+const MAX_TRANSMISSIONS: u32 = 6;
+// duplicate acks that mean the packet after them was lost
+const DUP_ACKS: u32 = 3;
+const KEEPALIVE: Duration = Duration::from_secs(30);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+// the base delay is the lowest delay seen over this many one minute periods
+const DELAY_HISTORY: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    SynSent,
+    Connected,
+    Closed,
+}
+
+struct Sent {
+    seq: u16,
+    ptype: PacketType,
+    payload: Vec<u8>,
+    sent_at: Instant,
+    transmissions: u32,
+    // the peer got it out of order
+    sacked: bool,
+}
+
+struct Received {
+    payload: Vec<u8>,
+    fin: bool,
+}
+
+/// One uTP connection: reliable, ordered delivery with LEDBAT congestion control, which
+/// backs off when our packets start queueing and so yields to other traffic.
+pub struct Conn {
+    socket: Arc<UdpSocket>,
+    config: UtpConfig,
+    addr: SocketAddr,
+    recv_id: u16,
+    send_id: u16,
+    state: State,
+    error: Option<io::ErrorKind>,
+    // next sequence number we send
+    seq_nr: u16,
+    // our first sequence number, acks of a retried syn must carry it
+    initial_seq: u16,
+    // last sequence number received in order
+    ack_nr: u16,
+    last_ack: u16,
+    dup_acks: u32,
+    // last sequence number sent when a loss was detected, acks short of it mean the
+    // packet after them was lost too
+    recovery: Option<u16>,
+    inflight: VecDeque<Sent>,
+    inflight_bytes: usize,
+    send_buf: VecDeque<u8>,
+    recv_buf: VecDeque<u8>,
+    out_of_order: HashMap<u16, Received>,
+    // payload bytes in `out_of_order`
+    out_of_order_bytes: usize,
+    eof: bool,
+    closing: bool,
+    fin_sent: bool,
+    // the stream is gone, the connection only finishes its fin
+    dropped: bool,
+    peer_wnd: usize,
+    cwnd: usize,
+    rtt: Option<Duration>,
+    rtt_var: Duration,
+    rto: Duration,
+    // lowest delay per minute, newest last
+    base_delays: VecDeque<(Instant, u32)>,
+    // timestamp difference we send back, the delay of the peer's last packet
+    reply_micro: u32,
+    last_recv: Instant,
+    last_sent: Instant,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl Conn {
+    fn new(
+        socket: Arc<UdpSocket>,
+        config: UtpConfig,
+        addr: SocketAddr,
+        recv_id: u16,
+        send_id: u16,
+    ) -> Self {
+        let now = Instant::now();
+        Self {
+            socket,
+            config,
+            addr,
+            recv_id,
+            send_id,
+            state: State::SynSent,
+            error: None,
+            seq_nr: 1,
+            initial_seq: 1,
+            ack_nr: 0,
+            last_ack: 0,
+            dup_acks: 0,
+            recovery: None,
+            inflight: VecDeque::new(),
+            inflight_bytes: 0,
+            send_buf: VecDeque::new(),
+            recv_buf: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            out_of_order_bytes: 0,
+            eof: false,
+            closing: false,
+            fin_sent: false,
+            dropped: false,
+            peer_wnd: RECV_BUFFER,
+            cwnd: INITIAL_CWND,
+            rtt: None,
+            rtt_var: Duration::ZERO,
+            rto: Duration::from_secs(1),
+            base_delays: VecDeque::new(),
+            reply_micro: 0,
+            last_recv: now,
+            last_sent: now,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+
+    /// a connection to `addr`, its syn is sent right away
+    pub fn connect(
+        socket: Arc<UdpSocket>,
+        config: UtpConfig,
+        addr: SocketAddr,
+        recv_id: u16,
+    ) -> Self {
+        let mut conn = Self::new(socket, config, addr, recv_id, recv_id.wrapping_add(1));
+        conn.send_new(PacketType::Syn, Vec::new());
+        conn
+    }
+
+    /// the connection a peer asked for with `syn`, it is acked right away
+    pub fn accept(
+        socket: Arc<UdpSocket>,
+        config: UtpConfig,
+        addr: SocketAddr,
+        syn: &Packet,
+    ) -> Self {
+        let mut conn = Self::new(
+            socket,
+            config,
+            addr,
+            syn.conn_id.wrapping_add(1),
+            syn.conn_id,
+        );
+        conn.state = State::Connected;
+        conn.seq_nr = random::next_u32() as u16;
+        conn.initial_seq = conn.seq_nr;
+        conn.last_ack = conn.seq_nr.wrapping_sub(1);
+        conn.ack_nr = syn.seq_nr;
+        conn.reply_micro = now_micros().wrapping_sub(syn.timestamp);
+        conn.send_state();
+        conn
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn recv_id(&self) -> u16 {
+        self.recv_id
+    }
+
+    /// nothing left to do, the socket forgets the connection
+    pub fn is_finished(&self) -> bool {
+        self.state == State::Closed
+            || (self.dropped && self.fin_sent && self.inflight.is_empty())
+            || (self.dropped && self.last_recv.elapsed() > KEEPALIVE)
+    }
+
+    pub fn on_packet(&mut self, p: Packet) {
+        self.last_recv = Instant::now();
+        self.reply_micro = now_micros().wrapping_sub(p.timestamp);
+        self.peer_wnd = p.wnd_size as usize;
+        match p.ptype {
+            PacketType::Reset => return self.fail(io::ErrorKind::ConnectionReset),
+            // our ack got lost, the peer retries its syn. It takes the sequence number of
+            // the ack as our first, whatever we sent since.
+            PacketType::Syn => return self.transmit(PacketType::State, self.initial_seq, &[]),
+            _ => {}
+        }
+        if self.state == State::SynSent {
+            if p.ptype != PacketType::State {
+                return;
+            }
+            self.state = State::Connected;
+            // the peer's first data packet has the sequence number of this ack
+            self.ack_nr = p.seq_nr.wrapping_sub(1);
+            self.wake();
+        }
+        self.on_ack(&p);
+        if matches!(p.ptype, PacketType::Data | PacketType::Fin) {
+            self.on_data(p);
+            self.send_state();
+        }
+        self.flush();
+    }
+
+    /// retransmissions and timeouts, called every few milliseconds
+    pub fn on_tick(&mut self) {
+        if self.state == State::Closed {
+            return;
+        }
+        let now = Instant::now();
+        if now.duration_since(self.last_recv) > IDLE_TIMEOUT {
+            return self.fail(io::ErrorKind::TimedOut);
+        }
+        if let Some(front) = self.inflight.front() {
+            if now.duration_since(front.sent_at) >= self.rto {
+                if front.transmissions >= MAX_TRANSMISSIONS {
+                    return self.fail(io::ErrorKind::TimedOut);
+                }
+                // a timeout means heavy loss, start over with the smallest window
+                self.cwnd = MIN_CWND;
+                self.recovery = Some(self.seq_nr.wrapping_sub(1));
+                self.rto = (self.rto * 2).min(MAX_RTO);
+                self.retransmit(0);
+                // nothing acked since we accepted: the peer may have missed our ack of
+                // its syn and drops our data until it gets one
+                if self.state == State::Connected
+                    && self.last_ack == self.initial_seq.wrapping_sub(1)
+                {
+                    self.transmit(PacketType::State, self.initial_seq, &[]);
+                }
+            }
+        }
+        if self.state == State::Connected && now.duration_since(self.last_sent) > KEEPALIVE {
+            self.send_state();
+        }
+        self.flush();
+    }
+
+    pub fn poll_connected(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match (self.state, self.error) {
+            (State::Connected, _) => Poll::Ready(Ok(())),
+            (State::Closed, err) => {
+                Poll::Ready(Err(err.unwrap_or(io::ErrorKind::NotConnected).into()))
+            }
+            (State::SynSent, _) => {
+                self.write_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    pub fn poll_read(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.recv_buf.is_empty() {
+            let was_full = self.recv_buf.len() >= RECV_BUFFER - MAX_PAYLOAD;
+            let n = self.recv_buf.len().min(buf.remaining());
+            let (a, b) = self.recv_buf.as_slices();
+            let from_a = n.min(a.len());
+            buf.put_slice(&a[..from_a]);
+            buf.put_slice(&b[..n - from_a]);
+            self.recv_buf.drain(..n);
+            if was_full {
+                // tell the peer our window opened again
+                self.send_state();
+            }
+            return Poll::Ready(Ok(()));
+        }
+        if self.eof {
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(err) = self.error {
+            return Poll::Ready(Err(err.into()));
+        }
+        self.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    pub fn poll_write(&mut self, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        if let Some(err) = self.error {
+            return Poll::Ready(Err(err.into()));
+        }
+        if self.closing {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let room = SEND_BUFFER.saturating_sub(self.send_buf.len());
+        if room == 0 {
+            self.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = room.min(data.len());
+        self.send_buf.extend(&data[..n]);
+        self.flush();
+        Poll::Ready(Ok(n))
+    }
+
+    /// ready once everything written went out, acked or not
+    pub fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(err) = self.error {
+            return Poll::Ready(Err(err.into()));
+        }
+        if self.send_buf.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        self.write_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// ready once the peer acked everything and our fin, so the socket may go away
+    pub fn poll_shutdown(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.closing = true;
+        self.flush();
+        if let Some(err) = self.error {
+            return Poll::Ready(Err(err.into()));
+        }
+        if self.fin_sent && self.inflight.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        self.write_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// the stream was dropped, send what is left and a fin
+    pub fn close(&mut self) {
+        self.dropped = true;
+        self.closing = true;
+        self.flush();
+    }
+
+    fn on_ack(&mut self, p: &Packet) {
+        let now = Instant::now();
+        let mut acked = 0;
+        let mut newest = None;
+        let mut retransmitted = false;
+        while let Some(front) = self.inflight.front() {
+            if seq_before(p.ack_nr, front.seq) {
+                break;
+            }
+            let sent = self.inflight.pop_front().unwrap();
+            if !sent.sacked {
+                acked += sent.payload.len();
+                self.inflight_bytes -= sent.payload.len();
+            }
+            retransmitted |= sent.transmissions > 1;
+            newest = Some(sent.sent_at);
+        }
+        let progress = newest.is_some();
+        // only the packet that triggered the ack gives a sample, and only when no
+        // retransmission filled a hole before it: the ack then waited for the retransmission
+        if let (Some(sent_at), false) = (newest, retransmitted) {
+            self.update_rtt(now.duration_since(sent_at));
+        }
+        if progress {
+            self.last_ack = p.ack_nr;
+            self.dup_acks = 0;
+            // the peer is reachable again, undo the backoff of earlier timeouts
+            if let Some(rtt) = self.rtt {
+                self.rto = (rtt + self.rtt_var * 4).clamp(MIN_RTO, MAX_RTO);
+            }
+            if self.recovery.is_some_and(|end| !seq_before(p.ack_nr, end)) {
+                self.recovery = None;
+            }
+        }
+        let front_unsent = self
+            .inflight
+            .front()
+            .is_some_and(|f| f.transmissions == 1 || self.resend_due(0));
+        if progress && self.recovery.is_some() && front_unsent {
+            // the packet after the ack is missing as well, resend it right away
+            self.retransmit(0);
+        }
+        if let Some(sack) = &p.sack {
+            acked += self.on_sack(p.ack_nr, sack);
+        } else if !progress
+            && p.ptype == PacketType::State
+            && p.ack_nr == self.last_ack
+            && !self.inflight.is_empty()
+        {
+            self.dup_acks += 1;
+            // the packet after the acked one is lost, the peer acks what it got behind it.
+            // Small windows can't produce three duplicates, there every packet behind the
+            // lost one counts.
+            let threshold = (self.inflight.len() as u32 - 1).clamp(1, DUP_ACKS);
+            if self.dup_acks == threshold && self.recovery.is_none() {
+                self.on_loss();
+                self.retransmit(0);
+            } else if self.recovery.is_some() && self.resend_due(0) {
+                // packets sent after the retransmission arrive, so it got lost as well
+                self.retransmit(0);
+            }
+        }
+        if acked > 0 && p.timestamp_diff != 0 {
+            self.on_delay(p.timestamp_diff, acked);
+        }
+        // room in the window, or a shutdown waiting for the ack of its fin
+        if progress || acked > 0 {
+            self.wake();
+        }
+    }
+
+    // mark the packets the peer got out of order and resend the ones with `DUP_ACKS` or
+    // more received behind them, returns the bytes newly acked
+    fn on_sack(&mut self, ack_nr: u16, sack: &[u8]) -> usize {
+        let now = Instant::now();
+        let mut acked = 0;
+        let mut newest = None;
+        let first = ack_nr.wrapping_add(2);
+        for (i, sent) in self.inflight.iter_mut().enumerate() {
+            let bit = sent.seq.wrapping_sub(first) as usize;
+            let received = sack.get(bit / 8).is_some_and(|b| b & (1 << (bit % 8)) != 0);
+            if received && !sent.sacked {
+                sent.sacked = true;
+                acked += sent.payload.len();
+                if sent.transmissions == 1 {
+                    newest = Some((i, sent.sent_at));
+                }
+            }
+        }
+        self.inflight_bytes -= acked;
+        if let Some((_, sent_at)) = newest {
+            self.update_rtt(now.duration_since(sent_at));
+        }
+        // with fewer than `DUP_ACKS` packets behind it a packet counts as lost once all of
+        // them arrived
+        let mut behind = 0;
+        let mut lost = Vec::new();
+        for (i, sent) in self.inflight.iter().enumerate().rev() {
+            let later = (self.inflight.len() - 1 - i) as u32;
+            if sent.sacked {
+                behind += 1;
+            } else if (behind >= DUP_ACKS || (behind > 0 && behind == later))
+                && (sent.transmissions == 1 || self.resend_due(i))
+            {
+                lost.push(i);
+            }
+        }
+        if !lost.is_empty() && self.recovery.is_none() {
+            self.on_loss();
+        }
+        for i in lost.into_iter().rev() {
+            self.retransmit(i);
+        }
+        acked
+    }
+
+    // halve the window once per window of data with losses
+    fn on_loss(&mut self) {
+        self.cwnd = (self.cwnd / 2).max(MIN_CWND);
+        self.recovery = Some(self.seq_nr.wrapping_sub(1));
+    }
+
+    // packet `i` was retransmitted more than two round trips ago, so when packets sent
+    // after it arrive it got lost again
+    fn resend_due(&self, i: usize) -> bool {
+        let (Some(sent), Some(rtt)) = (self.inflight.get(i), self.rtt) else {
+            return false;
+        };
+        sent.transmissions > 1 && sent.sent_at.elapsed() > rtt * 2 + self.rtt_var * 4
+    }
+
+    fn on_data(&mut self, p: Packet) {
+        let expected = self.ack_nr.wrapping_add(1);
+        let received = Received {
+            fin: p.ptype == PacketType::Fin,
+            payload: p.payload,
+        };
+        if p.seq_nr == expected {
+            // the next packet is worth more than those held beyond it
+            while self.buffered() + received.payload.len() > RECV_BUFFER {
+                let farthest = self
+                    .out_of_order
+                    .keys()
+                    .copied()
+                    .max_by_key(|seq| seq.wrapping_sub(expected));
+                let Some(farthest) = farthest else {
+                    // the reader is behind and the peer ignored our window
+                    return;
+                };
+                self.take_out_of_order(farthest);
+            }
+            self.deliver(p.seq_nr, received);
+            while let Some(next) = self.take_out_of_order(self.ack_nr.wrapping_add(1)) {
+                self.deliver(self.ack_nr.wrapping_add(1), next);
+            }
+        } else if seq_before(expected, p.seq_nr)
+            && p.seq_nr.wrapping_sub(expected) < MAX_OUT_OF_ORDER
+            && !self.out_of_order.contains_key(&p.seq_nr)
+            && self.buffered() + received.payload.len() <= RECV_BUFFER
+        {
+            self.out_of_order_bytes += received.payload.len();
+            self.out_of_order.insert(p.seq_nr, received);
+        }
+    }
+
+    // bytes received and not read yet, in order or not
+    fn buffered(&self) -> usize {
+        self.recv_buf.len() + self.out_of_order_bytes
+    }
+
+    fn take_out_of_order(&mut self, seq: u16) -> Option<Received> {
+        let received = self.out_of_order.remove(&seq)?;
+        self.out_of_order_bytes -= received.payload.len();
+        Some(received)
+    }
+
+    fn deliver(&mut self, seq: u16, received: Received) {
+        self.ack_nr = seq;
+        if received.fin {
+            self.eof = true;
+            self.out_of_order.clear();
+            self.out_of_order_bytes = 0;
+        } else {
+            self.recv_buf.extend(received.payload);
+        }
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+    }
+
+    // LEDBAT: grow the window while our packets' queueing delay is below the target and
+    // shrink it above, proportional to the distance from the target
+    fn on_delay(&mut self, delay: u32, acked: usize) {
+        let now = Instant::now();
+        match self.base_delays.back_mut() {
+            Some((since, lowest)) if now.duration_since(*since) < Duration::from_secs(60) => {
+                if wrapping_less(delay, *lowest) {
+                    *lowest = delay;
+                }
+            }
+            _ => {
+                self.base_delays.push_back((now, delay));
+                if self.base_delays.len() > DELAY_HISTORY {
+                    self.base_delays.pop_front();
+                }
+            }
+        }
+        let base = self
+            .base_delays
+            .iter()
+            .map(|(_, d)| *d)
+            .reduce(|a, b| if wrapping_less(b, a) { b } else { a })
+            .unwrap_or(delay);
+        let queuing = (delay.wrapping_sub(base) as i32).max(0) as f64;
+        let target = self.config.target_delay.as_micros() as f64;
+        let off_target = (target - queuing) / target;
+        let gain = MAX_PAYLOAD as f64 * off_target * acked as f64 / self.cwnd as f64;
+        self.cwnd = (self.cwnd as f64 + gain).clamp(MIN_CWND as f64, MAX_CWND as f64) as usize;
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        match self.rtt {
+            None => {
+                self.rtt = Some(sample);
+                self.rtt_var = sample / 2;
+            }
+            Some(rtt) => {
+                let diff = rtt.abs_diff(sample);
+                self.rtt_var = (self.rtt_var * 3 + diff) / 4;
+                self.rtt = Some((rtt * 7 + sample) / 8);
+            }
+        }
+        self.rto = (self.rtt.unwrap() + self.rtt_var * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    // send what the windows allow, then the fin once everything is out
+    fn flush(&mut self) {
+        if self.state != State::Connected {
+            return;
+        }
+        // every duplicate ack means a packet left the network, replace it to keep the acks
+        // coming while we recover
+        let window = (self.cwnd + self.dup_acks as usize * MAX_PAYLOAD).min(self.peer_wnd);
+        let mut progress = false;
+        while !self.send_buf.is_empty() {
+            let size = self.send_buf.len().min(MAX_PAYLOAD);
+            // with nothing in flight one packet may go out, probing a closed window
+            if self.inflight_bytes + size > window && !self.inflight.is_empty() {
+                break;
+            }
+            let payload: Vec<u8> = self.send_buf.drain(..size).collect();
+            self.send_new(PacketType::Data, payload);
+            progress = true;
+        }
+        if self.closing && self.send_buf.is_empty() && !self.fin_sent {
+            self.send_new(PacketType::Fin, Vec::new());
+            self.fin_sent = true;
+            progress = true;
+        }
+        if progress {
+            if let Some(waker) = self.write_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    // a packet with the next sequence number, kept until acked
+    fn send_new(&mut self, ptype: PacketType, payload: Vec<u8>) {
+        let seq = self.seq_nr;
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.transmit(ptype, seq, &payload);
+        self.inflight_bytes += payload.len();
+        self.inflight.push_back(Sent {
+            seq,
+            ptype,
+            payload,
+            sent_at: Instant::now(),
+            transmissions: 1,
+            sacked: false,
+        });
+    }
+
+    fn retransmit(&mut self, i: usize) {
+        let Some(sent) = self.inflight.get_mut(i) else {
+            return;
+        };
+        sent.sent_at = Instant::now();
+        sent.transmissions += 1;
+        let (ptype, seq, payload) = (sent.ptype, sent.seq, sent.payload.clone());
+        self.transmit(ptype, seq, &payload);
+    }
+
+    fn send_state(&mut self) {
+        self.transmit(PacketType::State, self.seq_nr, &[]);
+    }
+
+    fn transmit(&mut self, ptype: PacketType, seq_nr: u16, payload: &[u8]) {
+        self.last_sent = Instant::now();
+        let packet = Packet {
+            ptype,
+            // the syn carries the id we receive on, everything else the peer's
+            conn_id: if ptype == PacketType::Syn {
+                self.recv_id
+            } else {
+                self.send_id
+            },
+            timestamp: now_micros(),
+            timestamp_diff: self.reply_micro,
+            wnd_size: RECV_BUFFER.saturating_sub(self.recv_buf.len()) as u32,
+            seq_nr,
+            ack_nr: self.ack_nr,
+            payload: payload.to_vec(),
+            sack: (ptype == PacketType::State).then(|| self.sack()).flatten(),
+        };
+        if self.config.simulated_loss > 0.0
+            && (random::next_u32() as f64 / u32::MAX as f64) < self.config.simulated_loss
+        {
+            return;
+        }
+        // a full socket buffer drops the packet like the network would
+        let _ = self.socket.try_send_to(&packet.to_bytes(), self.addr);
+    }
+
+    // the packets received beyond a hole, so the peer resends only what is missing
+    fn sack(&self) -> Option<Vec<u8>> {
+        let first = self.ack_nr.wrapping_add(2);
+        let bits: Vec<usize> = self
+            .out_of_order
+            .keys()
+            .map(|seq| seq.wrapping_sub(first) as usize)
+            .filter(|bit| *bit < MAX_SACK * 8)
+            .collect();
+        let last = *bits.iter().max()?;
+        // a multiple of 4 bytes
+        let mut sack = vec![0u8; (last / 32 + 1) * 4];
+        for bit in bits {
+            sack[bit / 8] |= 1 << (bit % 8);
+        }
+        Some(sack)
+    }
+
+    fn fail(&mut self, err: io::ErrorKind) {
+        self.state = State::Closed;
+        self.error.get_or_insert(err);
+        self.wake();
+    }
+
+    fn wake(&mut self) {
+        for waker in [self.read_waker.take(), self.write_waker.take()]
+            .into_iter()
+            .flatten()
+        {
+            waker.wake();
+        }
+    }
+}
+
+// delays are compared in wrapping order, the clocks of both ends are unrelated
+fn wrapping_less(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn conn() -> Conn {
+        let socket = UdpSocket::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap();
+        let addr = socket.local_addr().unwrap();
+        Conn::new(Arc::new(socket), UtpConfig::default(), addr, 1, 2)
+    }
+
+    fn data(seq_nr: u16, len: usize) -> Packet {
+        Packet {
+            ptype: PacketType::Data,
+            conn_id: 1,
+            timestamp: 0,
+            timestamp_diff: 0,
+            wnd_size: RECV_BUFFER as u32,
+            seq_nr,
+            ack_nr: 0,
+            payload: vec![seq_nr as u8; len],
+            sack: None,
+        }
+    }
+
+    fn held(conn: &Conn) -> Vec<u16> {
+        let mut seqs: Vec<u16> = conn.out_of_order.keys().copied().collect();
+        seqs.sort_unstable();
+        seqs
+    }
+
+    #[tokio::test]
+    async fn holds_no_more_than_the_receive_window() {
+        let mut conn = conn().await;
+        conn.on_data(data(1, RECV_BUFFER - 3 * MAX_PAYLOAD));
+        assert_eq!(conn.ack_nr, 1);
+
+        // three packets fit the window beyond the hole at 2, the fourth is dropped
+        for seq in 3..7 {
+            conn.on_data(data(seq, MAX_PAYLOAD));
+        }
+        conn.on_data(data(3, MAX_PAYLOAD));
+        assert_eq!(held(&conn), [3, 4, 5]);
+        assert_eq!(conn.buffered(), RECV_BUFFER);
+
+        // the missing packet pushes out the farthest one held
+        conn.on_data(data(2, MAX_PAYLOAD));
+        assert_eq!(conn.ack_nr, 4);
+        assert!(held(&conn).is_empty());
+        assert_eq!(
+            (conn.recv_buf.len(), conn.out_of_order_bytes),
+            (RECV_BUFFER, 0)
+        );
+
+        // a full buffer takes nothing until it is read
+        conn.on_data(data(5, MAX_PAYLOAD));
+        assert_eq!(conn.ack_nr, 4);
+        conn.recv_buf.drain(..MAX_PAYLOAD);
+        conn.on_data(data(5, MAX_PAYLOAD));
+        assert_eq!(conn.ack_nr, 5);
+        assert_eq!(conn.recv_buf.back(), Some(&5));
+    }
+}
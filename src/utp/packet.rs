@@ -0,0 +1,125 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, ensure, Result};
+use bytes::{Buf, BufMut};
+
+pub const HEADER_SIZE: usize = 20;
+pub const VERSION: u8 = 1;
+/// extension type of a selective ack
+pub const EXT_SACK: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+impl TryFrom<u8> for PacketType {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        Ok(match value {
+            0 => Self::Data,
+            1 => Self::Fin,
+            2 => Self::State,
+            3 => Self::Reset,
+            4 => Self::Syn,
+            _ => bail!("unknown utp packet type {}", value),
+        })
+    }
+}
+
+/// A uTP packet (BEP 29). Of the extensions only the selective ack is kept, others are
+/// skipped when parsing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub ptype: PacketType,
+    pub conn_id: u16,
+    /// sender's clock in microseconds
+    pub timestamp: u32,
+    /// one way delay of the last packet the sender received from us
+    pub timestamp_diff: u32,
+    /// bytes the sender can still receive
+    pub wnd_size: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+    pub payload: Vec<u8>,
+    /// selective ack: bit `i` says `ack_nr + 2 + i` arrived, lowest bit of the first byte
+    /// first
+    pub sack: Option<Vec<u8>>,
+}
+
+impl Packet {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_SIZE + self.payload.len());
+        buf.put_u8((self.ptype as u8) << 4 | VERSION);
+        buf.put_u8(if self.sack.is_some() { EXT_SACK } else { 0 });
+        buf.put_u16(self.conn_id);
+        buf.put_u32(self.timestamp);
+        buf.put_u32(self.timestamp_diff);
+        buf.put_u32(self.wnd_size);
+        buf.put_u16(self.seq_nr);
+        buf.put_u16(self.ack_nr);
+        if let Some(sack) = &self.sack {
+            buf.put_u8(0);
+            buf.put_u8(sack.len() as u8);
+            buf.extend_from_slice(sack);
+        }
+        buf.extend_from_slice(&self.payload);
+        buf
+    }
+
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        ensure!(bytes.len() >= HEADER_SIZE, "utp packet too short");
+        let first = bytes.get_u8();
+        ensure!(first & 0x0f == VERSION, "utp version {}", first & 0x0f);
+        let ptype = PacketType::try_from(first >> 4)?;
+        let mut extension = bytes.get_u8();
+        let conn_id = bytes.get_u16();
+        let timestamp = bytes.get_u32();
+        let timestamp_diff = bytes.get_u32();
+        let wnd_size = bytes.get_u32();
+        let seq_nr = bytes.get_u16();
+        let ack_nr = bytes.get_u16();
+        // a chain of extensions: next extension type, length, data
+        let mut sack = None;
+        while extension != 0 {
+            ensure!(bytes.len() >= 2, "truncated utp extension");
+            let next = bytes.get_u8();
+            let len = bytes.get_u8() as usize;
+            ensure!(bytes.len() >= len, "truncated utp extension");
+            if extension == EXT_SACK {
+                sack = Some(bytes[..len].to_vec());
+            }
+            bytes.advance(len);
+            extension = next;
+        }
+        Ok(Self {
+            ptype,
+            conn_id,
+            timestamp,
+            timestamp_diff,
+            wnd_size,
+            seq_nr,
+            ack_nr,
+            payload: bytes.to_vec(),
+            sack,
+        })
+    }
+}
+
+/// our clock for packet timestamps, only differences matter
+pub fn now_micros() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u32)
+        .unwrap_or_default()
+}
+
+/// `a` comes before `b` in wrapping sequence number order
+pub fn seq_before(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) < 0
+}
@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::future::poll_fn;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::torrent::random;
+use crate::utp::conn::Conn;
+use crate::utp::packet::{now_micros, Packet, PacketType};
+use crate::utp::stream::UtpStream;
+use crate::utp::UtpConfig;
+
+// connections waiting for `accept`, more syns are reset
+const ACCEPT_QUEUE: usize = 32;
+const TICK: Duration = Duration::from_millis(20);
+
+type SharedConn = Arc<Mutex<Conn>>;
+
+/// A UDP socket carrying uTP connections, both the ones we make and the ones we accept.
+/// Clones share the socket, it closes once the last clone and stream are dropped.
+#[derive(Clone)]
+pub struct UtpSocket {
+    inner: Arc<SocketInner>,
+}
+
+pub(crate) struct SocketInner {
+    udp: Arc<UdpSocket>,
+    config: UtpConfig,
+    // by peer address and the id we receive on
+    conns: Mutex<HashMap<(SocketAddr, u16), SharedConn>>,
+    // syns are only accepted once someone calls `accept`
+    listening: AtomicBool,
+    incoming: mpsc::Sender<SharedConn>,
+    incoming_rx: tokio::sync::Mutex<mpsc::Receiver<SharedConn>>,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Debug for UtpSocket {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UtpSocket")
+            .field("local_addr", &self.inner.udp.local_addr().ok())
+            .finish()
+    }
+}
+
+impl UtpSocket {
+    pub async fn bind(addr: SocketAddr, config: UtpConfig) -> Result<Self> {
+        let udp = UdpSocket::bind(addr)
+            .await
+            .with_context(|| format!("bind utp on {}", addr))?;
+        let (incoming, incoming_rx) = mpsc::channel(ACCEPT_QUEUE);
+        let inner = Arc::new(SocketInner {
+            udp: Arc::new(udp),
+            config,
+            conns: Mutex::new(HashMap::new()),
+            listening: AtomicBool::new(false),
+            incoming,
+            incoming_rx: tokio::sync::Mutex::new(incoming_rx),
+            tasks: Mutex::new(Vec::new()),
+        });
+        let driver = tokio::spawn(drive(inner.udp.clone(), Arc::downgrade(&inner)));
+        inner.tasks.lock().unwrap().push(driver);
+        Ok(Self { inner })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.inner.udp.local_addr()?)
+    }
+
+    /// open a connection to `addr`, fails when the peer doesn't answer our syn
+    pub async fn connect(&self, addr: SocketAddr) -> Result<UtpStream> {
+        let conn = {
+            let mut conns = self.inner.conns.lock().unwrap();
+            let recv_id = loop {
+                let id = random::next_u32() as u16;
+                if !conns.contains_key(&(addr, id)) {
+                    break id;
+                }
+            };
+            let conn = Conn::connect(self.inner.udp.clone(), self.inner.config, addr, recv_id);
+            let conn = Arc::new(Mutex::new(conn));
+            conns.insert((addr, recv_id), conn.clone());
+            conn
+        };
+        poll_fn(|cx| conn.lock().unwrap().poll_connected(cx))
+            .await
+            .with_context(|| format!("utp connect to {}", addr))?;
+        Ok(UtpStream::new(conn, self.inner.clone()))
+    }
+
+    /// the next connection a peer opened to us
+    pub async fn accept(&self) -> Result<(UtpStream, SocketAddr)> {
+        self.inner.listening.store(true, Ordering::Relaxed);
+        let conn = self
+            .inner
+            .incoming_rx
+            .lock()
+            .await
+            .recv()
+            .await
+            .context("utp socket closed")?;
+        let addr = conn.lock().unwrap().addr();
+        Ok((UtpStream::new(conn, self.inner.clone()), addr))
+    }
+}
+
+impl SocketInner {
+    fn on_datagram(&self, bytes: &[u8], from: SocketAddr) {
+        let Ok(packet) = Packet::from_bytes(bytes) else {
+            return;
+        };
+        // packets carry the id we receive on, except syns which carry the id the peer does
+        let recv_id = match packet.ptype {
+            PacketType::Syn => packet.conn_id.wrapping_add(1),
+            _ => packet.conn_id,
+        };
+        let conn = self.conns.lock().unwrap().get(&(from, recv_id)).cloned();
+        match conn {
+            Some(conn) => conn.lock().unwrap().on_packet(packet),
+            None if packet.ptype == PacketType::Syn => self.on_syn(&packet, from),
+            None if packet.ptype != PacketType::Reset => self.reset(&packet, from),
+            None => {}
+        }
+    }
+
+    fn on_syn(&self, syn: &Packet, from: SocketAddr) {
+        if !self.listening.load(Ordering::Relaxed) {
+            return self.reset(syn, from);
+        }
+        let conn = Conn::accept(self.udp.clone(), self.config, from, syn);
+        let recv_id = conn.recv_id();
+        let conn = Arc::new(Mutex::new(conn));
+        if self.incoming.try_send(conn.clone()).is_err() {
+            return self.reset(syn, from);
+        }
+        self.conns.lock().unwrap().insert((from, recv_id), conn);
+    }
+
+    // tell the peer we don't know the connection
+    fn reset(&self, packet: &Packet, to: SocketAddr) {
+        let reset = Packet {
+            ptype: PacketType::Reset,
+            conn_id: packet.conn_id,
+            timestamp: now_micros(),
+            timestamp_diff: 0,
+            wnd_size: 0,
+            seq_nr: 0,
+            ack_nr: packet.seq_nr,
+            payload: Vec::new(),
+            sack: None,
+        };
+        let _ = self.udp.try_send_to(&reset.to_bytes(), to);
+    }
+
+    fn on_tick(&self) {
+        let conns: Vec<_> = self.conns.lock().unwrap().values().cloned().collect();
+        for conn in &conns {
+            conn.lock().unwrap().on_tick();
+        }
+        self.conns
+            .lock()
+            .unwrap()
+            .retain(|_, conn| !conn.lock().unwrap().is_finished());
+    }
+}
+
+impl Drop for SocketInner {
+    fn drop(&mut self) {
+        for task in self.tasks.get_mut().unwrap().drain(..) {
+            task.abort();
+        }
+    }
+}
+
+// receives packets and runs the timers of all connections, until the socket is dropped
+async fn drive(udp: Arc<UdpSocket>, inner: Weak<SocketInner>) {
+    let mut buf = vec![0u8; 65536];
+    let mut tick = tokio::time::interval(TICK);
+    loop {
+        tokio::select! {
+            res = udp.recv_from(&mut buf) => {
+                // e.g. icmp port unreachable of an earlier packet
+                let Ok((n, from)) = res else {
+                    continue;
+                };
+                let Some(inner) = inner.upgrade() else {
+                    return;
+                };
+                inner.on_datagram(&buf[..n], from);
+            }
+            _ = tick.tick() => {
+                let Some(inner) = inner.upgrade() else {
+                    return;
+                };
+                inner.on_tick();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    async fn pair(config: UtpConfig) -> (UtpSocket, UtpSocket) {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let a = UtpSocket::bind(addr, config).await.unwrap();
+        let b = UtpSocket::bind(addr, config).await.unwrap();
+        (a, b)
+    }
+
+    #[tokio::test]
+    async fn connects_and_closes() {
+        let (client, server) = pair(UtpConfig::default()).await;
+        let server_addr = server.local_addr().unwrap();
+        let accept = tokio::spawn(async move {
+            let (mut conn, addr) = server.accept().await.unwrap();
+            let mut buf = Vec::new();
+            conn.read_to_end(&mut buf).await.unwrap();
+            conn.write_all(b"bye").await.unwrap();
+            conn.shutdown().await.unwrap();
+            (buf, addr)
+        });
+        let mut conn = client.connect(server_addr).await.unwrap();
+        assert_eq!(conn.peer_addr(), server_addr);
+        conn.write_all(b"hello").await.unwrap();
+        conn.shutdown().await.unwrap();
+        let mut reply = Vec::new();
+        conn.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"bye");
+        let (received, addr) = accept.await.unwrap();
+        assert_eq!(received, b"hello");
+        assert_eq!(addr, client.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn refuses_without_accept() {
+        let (client, server) = pair(UtpConfig::default()).await;
+        assert!(client.connect(server.local_addr().unwrap()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn transfers_in_order_despite_loss() {
+        let config = UtpConfig {
+            simulated_loss: 0.1,
+            ..UtpConfig::default()
+        };
+        let (client, server) = pair(config).await;
+        let server_addr = server.local_addr().unwrap();
+        let data: Vec<u8> = (0..4 << 20).map(|i: u32| (i * 7 + i / 4093) as u8).collect();
+        let expected = data.clone();
+        let send = tokio::spawn(async move {
+            let (mut conn, _) = server.accept().await.unwrap();
+            conn.write_all(&data).await.unwrap();
+            conn.shutdown().await.unwrap();
+        });
+        let mut conn = client.connect(server_addr).await.unwrap();
+        let mut received = Vec::new();
+        conn.read_to_end(&mut received).await.unwrap();
+        send.await.unwrap();
+        assert_eq!(received.len(), expected.len());
+        assert!(received == expected, "data arrived corrupted or out of order");
+    }
+}
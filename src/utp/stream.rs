@@ -0,0 +1,68 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::utp::conn::Conn;
+use crate::utp::socket::SocketInner;
+
+/// A uTP connection as a byte stream, like a `TcpStream`. Dropping it sends what is left
+/// and closes the connection.
+pub struct UtpStream {
+    conn: Arc<Mutex<Conn>>,
+    addr: SocketAddr,
+    // keeps the socket and its driver alive
+    _socket: Arc<SocketInner>,
+}
+
+impl UtpStream {
+    pub(crate) fn new(conn: Arc<Mutex<Conn>>, socket: Arc<SocketInner>) -> Self {
+        let addr = conn.lock().unwrap().addr();
+        Self {
+            conn,
+            addr,
+            _socket: socket,
+        }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.conn.lock().unwrap().poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.conn.lock().unwrap().poll_write(cx, data)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.conn.lock().unwrap().poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.conn.lock().unwrap().poll_shutdown(cx)
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        self.conn.lock().unwrap().close();
+    }
+}